@@ -7,7 +7,7 @@ use forest::mqtt::MqttSender;
 
 #[tokio::main]
 async fn main() {
-    let mut mqtt_server = forest::mqtt::start_broker(None).await;
+    let mut mqtt_server = forest::mqtt::start_broker(None, None).await;
 
     // Example: create a message channel and receive messages
     let receiver = mqtt_server.message_receiver();
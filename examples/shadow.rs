@@ -25,6 +25,7 @@ fn main() {
                 delta: json!(null),
             }
         },
+        version: None,
     };
     // Apply update
     shadow.update(&update).unwrap();
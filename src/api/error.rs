@@ -20,6 +20,8 @@ pub enum AppError {
     InternalServerError(String),
     #[error("Conflict: {0}")]
     Conflict(String),
+    #[error("Bad request: {0}")]
+    BadRequest(String),
 }
 
 impl IntoResponse for AppError {
@@ -39,6 +41,10 @@ impl IntoResponse for AppError {
                 // Add msg to conflict message
                 (StatusCode::CONFLICT, format!("Conflict: {}", msg))
             }
+            AppError::BadRequest(msg) => {
+                // Add msg to bad request message
+                (StatusCode::BAD_REQUEST, format!("Bad request: {}", msg))
+            }
             AppError::DatabaseError(e) => {
                 tracing::error!(error=?e, "Database error in API");
                 // Add error to database error message
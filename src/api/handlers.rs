@@ -2,16 +2,28 @@ use std::collections::HashMap;
 
 use crate::api::error::AppError;
 use crate::api::AppState;
-use crate::api::services::create_device;
+use crate::api::services::{
+    create_device, enroll_device, renew_device_certificate, revoke_device_certificate,
+};
+use crate::certs::renewal::{RenewalRequest, RenewedCertificate};
+use crate::certs::{CertificateError, CertificateManager, RevokedCertificate};
 use crate::dataconfig::{DataConfig, DataConfigEntry};
 use crate::db::DatabaseError;
+use crate::mqtt::auth::hash_password;
 use crate::processor::send_delta_to_mqtt;
-use crate::shadow::{NestedStateDocument, Shadow, StateUpdateDocument};
-use crate::models::{DeviceInformation, DeviceMetadata};
-use crate::models::{ShadowName, TenantId};
-use crate::timeseries::{TimeSeriesConversions, TimeSeriesModel};
+use crate::shadow::{
+    NestedStateDocument, Shadow, ShadowError, ShadowHistoryEntry, StateUpdateDocument,
+};
+use crate::models::{CreatedDeviceResponse, DeviceInformation, DeviceResponse};
+use crate::models::{is_valid_tenant_id, split_client_id, to_client_id, ShadowName, TenantId};
+use crate::timeseries::{
+    now_micros, parse_bucket_width, Aggregation, Precision, TimeSeriesConversions,
+    TimeSeriesModel,
+};
 use axum::{
     extract::{Path, Query, State},
+    http::header,
+    response::IntoResponse,
     Json,
 };
 use serde::{Deserialize, Serialize};
@@ -55,18 +67,34 @@ pub async fn health_handler() -> &'static str {
     "OK"
 }
 
-pub async fn get_shadow_handler(
-    Path((_tenant_id, device_id)): Path<(String, String)>,
+// Parse the tenant path segment, invalid tenants are rejected
+// so they can never reach into the key space of another tenant
+fn parse_tenant_id(tenant_id: &str) -> Result<TenantId, AppError> {
+    if !is_valid_tenant_id(tenant_id) {
+        return Err(AppError::BadRequest(format!(
+            "Invalid tenant id: {}",
+            tenant_id
+        )));
+    }
+    Ok(TenantId::from_str(tenant_id))
+}
+
+pub async fn list_shadows_handler(
+    Path((tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ShadowName>>, AppError> {
+    let tenant_id = parse_tenant_id(&tenant_id)?;
+    let shadow_names = state.db.list_shadow_names(&device_id, &tenant_id)?;
+    Ok(Json(shadow_names))
+}
+
+pub async fn get_named_shadow_handler(
+    Path((tenant_id, device_id, shadow_name)): Path<(String, String, String)>,
     State(state): State<AppState>,
-    Query(params): Query<HashMap<String, String>>,
 ) -> Result<Json<Shadow>, AppError> {
-    let db = state.db.clone();
-    let maybe_shadow_name = params.get("name");
-    let shadow_name = match maybe_shadow_name {
-        Some(name) => ShadowName::from_str(name),
-        None => ShadowName::Default,
-    };
-    match db._get_shadow(&device_id, &shadow_name, &TenantId::Default) {
+    let tenant_id = parse_tenant_id(&tenant_id)?;
+    let shadow_name = ShadowName::from_str(&shadow_name);
+    match state.db._get_shadow(&device_id, &shadow_name, &tenant_id) {
         Ok(doc) => Ok(Json(doc)),
         Err(DatabaseError::NotFoundError(_)) => Err(AppError::NotFound(format!(
             "Shadow ({}) not found for device: {}",
@@ -77,54 +105,195 @@ pub async fn get_shadow_handler(
     }
 }
 
-pub async fn update_shadow_handler(
-    Path((_tenant_id, device_id)): Path<(String, String)>,
-    State(state): State<AppState>,
-    Query(params): Query<HashMap<String, String>>,
-    Json(nested_update_doc): Json<NestedStateDocument>,
-) -> Result<Json<Shadow>, AppError> {
-    let tenant_id = TenantId::Default;
-    let maybe_shadow_name = params.get("name");
-    let shadow_name = match maybe_shadow_name {
-        Some(name) => ShadowName::from_str(name),
-        None => ShadowName::Default,
-    };
+fn apply_shadow_update(
+    state: &AppState,
+    tenant_id: &TenantId,
+    device_id: &str,
+    shadow_name: &ShadowName,
+    nested_update_doc: NestedStateDocument,
+    send_delta: bool,
+) -> Result<Shadow, AppError> {
     let update_doc = StateUpdateDocument::from_nested_state(
         nested_update_doc,
-        &device_id,
-        &shadow_name,
-        &tenant_id,
+        device_id,
+        shadow_name,
+        tenant_id,
     );
     // Upsert shadow
     let shadow = match state.db._upsert_shadow(&update_doc) {
         Ok(updated) => updated,
+        Err(DatabaseError::ShadowError(e @ ShadowError::VersionConflict { .. })) => {
+            return Err(AppError::Conflict(e.to_string()))
+        }
         Err(e) => return Err(AppError::DatabaseError(e)),
     };
 
     //  Send delta to device if we have a mqtt sender
-    if params.get("send_delta").is_some() {
+    if send_delta {
         if let Some(mqtt_sender) = &state.mqtt_sender {
             let _delta_sent = send_delta_to_mqtt(&shadow, mqtt_sender, &state.shadow_topic_prefix);
         }
     }
 
+    Ok(shadow)
+}
+
+pub async fn update_shadow_handler(
+    Path((tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    Json(nested_update_doc): Json<NestedStateDocument>,
+) -> Result<Json<Shadow>, AppError> {
+    let tenant_id = parse_tenant_id(&tenant_id)?;
+    // named shadows are updated at `/{tenant_id}/shadow/{device_id}/{shadow_name}`
+    let shadow = apply_shadow_update(
+        &state,
+        &tenant_id,
+        &device_id,
+        &ShadowName::Default,
+        nested_update_doc,
+        params.contains_key("send_delta"),
+    )?;
+    Ok(Json(shadow))
+}
+
+pub async fn update_named_shadow_handler(
+    Path((tenant_id, device_id, shadow_name)): Path<(String, String, String)>,
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    Json(nested_update_doc): Json<NestedStateDocument>,
+) -> Result<Json<Shadow>, AppError> {
+    let tenant_id = parse_tenant_id(&tenant_id)?;
+    let shadow_name = ShadowName::from_str(&shadow_name);
+    let shadow = apply_shadow_update(
+        &state,
+        &tenant_id,
+        &device_id,
+        &shadow_name,
+        nested_update_doc,
+        params.contains_key("send_delta"),
+    )?;
     Ok(Json(shadow))
 }
 
+pub async fn delete_shadow_handler(
+    Path((tenant_id, device_id, shadow_name)): Path<(String, String, String)>,
+    State(state): State<AppState>,
+) -> Result<Json<()>, AppError> {
+    let tenant_id = parse_tenant_id(&tenant_id)?;
+    let shadow_name = ShadowName::from_str(&shadow_name);
+    match state.db._delete_shadow(&device_id, &shadow_name, &tenant_id) {
+        Ok(_) => Ok(Json(())),
+        Err(DatabaseError::NotFoundError(_)) => Err(AppError::NotFound(format!(
+            "Shadow ({}) not found for device: {}",
+            shadow_name.as_str(),
+            device_id
+        ))),
+        Err(e) => Err(AppError::DatabaseError(e)),
+    }
+}
+
+pub async fn list_shadow_history_handler(
+    Path((tenant_id, device_id, shadow_name)): Path<(String, String, String)>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ShadowHistoryEntry>>, AppError> {
+    let tenant_id = parse_tenant_id(&tenant_id)?;
+    let shadow_name = ShadowName::from_str(&shadow_name);
+    let history = state
+        .db
+        .list_shadow_history(&device_id, &shadow_name, &tenant_id)?;
+    Ok(Json(history))
+}
+
+#[derive(Deserialize)]
+pub struct ShadowSnapshotQuery {
+    pub version: Option<u64>,
+    pub timestamp: Option<u64>,
+}
+
+pub async fn get_shadow_snapshot_handler(
+    Path((tenant_id, device_id, shadow_name)): Path<(String, String, String)>,
+    State(state): State<AppState>,
+    Query(query): Query<ShadowSnapshotQuery>,
+) -> Result<Json<Shadow>, AppError> {
+    let tenant_id = parse_tenant_id(&tenant_id)?;
+    let shadow_name = ShadowName::from_str(&shadow_name);
+    let result = match (query.version, query.timestamp) {
+        (Some(version), None) => {
+            state
+                .db
+                .get_shadow_at_version(&device_id, &shadow_name, &tenant_id, version)
+        }
+        (None, Some(timestamp)) => {
+            state
+                .db
+                .get_shadow_at_timestamp(&device_id, &shadow_name, &tenant_id, timestamp)
+        }
+        _ => {
+            return Err(AppError::BadRequest(
+                "Either version or timestamp is required".to_string(),
+            ))
+        }
+    };
+    match result {
+        Ok(shadow) => Ok(Json(shadow)),
+        Err(DatabaseError::NotFoundError(_)) => Err(AppError::NotFound(format!(
+            "Shadow ({}) history not found for device: {}",
+            shadow_name.as_str(),
+            device_id
+        ))),
+        Err(e) => Err(AppError::DatabaseError(e)),
+    }
+}
+
 #[derive(Deserialize)]
 pub struct TimeseriesQuery {
     pub start: u64,
     pub end: u64,
+    /// Bucket width for aggregation, e.g. `1m`, `1h` or `1d`
+    pub bucket: Option<String>,
+    /// Aggregate function per bucket, defaults to `avg`
+    pub aggregate: Option<String>,
+    /// Unit of `start`, `end` and the returned timestamps (`s`, `ms` or `us`), defaults to `s`
+    #[serde(default)]
+    pub precision: Precision,
 }
 
 pub async fn get_timeseries_handler(
-    Path((_tenant_id, device_id, metric)): Path<(String, String, String)>,
+    Path((tenant_id, device_id, metric)): Path<(String, String, String)>,
     State(state): State<AppState>,
     Query(range): Query<TimeseriesQuery>,
 ) -> Result<Json<TimeSeriesModel>, AppError> {
     let db = &state.db;
-    let tenant_id = TenantId::Default;
-    let timeseries = match db.get_metric(&tenant_id, &device_id, &metric, range.start, range.end) {
+    let tenant_id = parse_tenant_id(&tenant_id)?;
+    let (start, end) = range.precision.range_to_micros(range.start, range.end);
+    let result = match (&range.bucket, &range.aggregate) {
+        (Some(bucket), aggregate) => {
+            let aggregation = match aggregate {
+                Some(aggregate) => aggregate.parse::<Aggregation>(),
+                None => Ok(Aggregation::Avg),
+            };
+            let (bucket_width, aggregation) = parse_bucket_width(bucket)
+                .and_then(|width| Ok((width, aggregation?)))
+                .map_err(|e| AppError::BadRequest(e.to_string()))?;
+            db.get_metric_aggregated(
+                &tenant_id,
+                &device_id,
+                &metric,
+                start,
+                end,
+                bucket_width,
+                aggregation,
+            )
+        }
+        (None, Some(_)) => {
+            return Err(AppError::BadRequest(
+                "Aggregation requires a bucket width".to_string(),
+            ))
+        }
+        (None, None) => db.get_metric(&tenant_id, &device_id, &metric, start, end),
+    };
+    let timeseries = match result {
         Ok(ts) => ts,
         Err(DatabaseError::NotFoundError(_)) => {
             return Err(AppError::NotFound(format!(
@@ -132,23 +301,27 @@ pub async fn get_timeseries_handler(
                 device_id, metric
             )));
         }
+        Err(DatabaseError::AggregationError(e)) => return Err(AppError::BadRequest(e.to_string())),
         Err(e) => return Err(AppError::DatabaseError(e)),
     };
-    Ok(Json(timeseries.to_model(&device_id, &metric)))
+    Ok(Json(timeseries.to_model(&device_id, &metric, range.precision)))
 }
 
 #[derive(Deserialize)]
 pub struct LastValuesQuery {
     pub limit: Option<u64>,
+    /// Unit of the returned timestamps, defaults to `s`
+    #[serde(default)]
+    pub precision: Precision,
 }
 
 pub async fn get_last_timeseries_handler(
-    Path((_tenant_id, device_id, metric)): Path<(String, String, String)>,
+    Path((tenant_id, device_id, metric)): Path<(String, String, String)>,
     State(state): State<AppState>,
     Query(query): Query<LastValuesQuery>,
 ) -> Result<Json<TimeSeriesModel>, AppError> {
     let db = &state.db;
-    let tenant_id = TenantId::Default;
+    let tenant_id = parse_tenant_id(&tenant_id)?;
     let limit = query.limit.unwrap_or(1);
 
     let timeseries = match db.get_last_metric(&tenant_id, &device_id, &metric, limit) {
@@ -162,16 +335,28 @@ pub async fn get_last_timeseries_handler(
         Err(e) => return Err(AppError::DatabaseError(e)),
     };
 
-    Ok(Json(timeseries.to_model(&device_id, &metric)))
+    Ok(Json(timeseries.to_model(&device_id, &metric, query.precision)))
+}
+
+// Parse and validate an uploaded config, invalid configs are rejected
+// before they can break the metric extraction of a tenant
+fn parse_data_config(body: &str) -> Result<DataConfig, AppError> {
+    let config =
+        DataConfig::from_json(body).map_err(|e| AppError::BadRequest(e.to_string()))?;
+    config
+        .validate()
+        .map_err(|e| AppError::BadRequest(e.to_string()))?;
+    Ok(config)
 }
 
 pub async fn store_device_config_handler(
     Path((tenant_id, device_prefix)): Path<(String, String)>,
     State(state): State<AppState>,
-    Json(config): Json<DataConfig>,
+    body: String,
 ) -> Result<Json<DataConfig>, AppError> {
     let db = &state.db;
-    let tenant_id = TenantId::from_str(&tenant_id);
+    let tenant_id = parse_tenant_id(&tenant_id)?;
+    let config = parse_data_config(&body)?;
     match db.store_device_data_config(&tenant_id, &device_prefix, &config) {
         Ok(_) => Ok(Json(config)),
         Err(e) => Err(AppError::DatabaseError(e)),
@@ -181,10 +366,11 @@ pub async fn store_device_config_handler(
 pub async fn store_tenant_config_handler(
     Path(tenant_id): Path<String>,
     State(state): State<AppState>,
-    Json(config): Json<DataConfig>,
+    body: String,
 ) -> Result<Json<DataConfig>, AppError> {
     let db = &state.db;
-    let tenant_id = TenantId::from_str(&tenant_id);
+    let tenant_id = parse_tenant_id(&tenant_id)?;
+    let config = parse_data_config(&body)?;
     match db.store_tenant_data_config(&tenant_id, &config) {
         Ok(_) => Ok(Json(config)),
         Err(e) => Err(AppError::DatabaseError(e)),
@@ -196,7 +382,7 @@ pub async fn get_tenant_config_handler(
     State(state): State<AppState>,
 ) -> Result<Json<DataConfig>, AppError> {
     let db = &state.db;
-    let tenant_id = TenantId::from_str(&tenant_id);
+    let tenant_id = parse_tenant_id(&tenant_id)?;
     match db.get_data_config(&tenant_id, None) {
         Ok(Some(config)) => Ok(Json(config)),
         Ok(None) => Err(AppError::NotFound(format!(
@@ -213,7 +399,7 @@ pub async fn get_config_handler(
     State(state): State<AppState>,
 ) -> Result<Json<DataConfig>, AppError> {
     let db = &state.db;
-    let tenant_id = TenantId::from_str(&tenant_id);
+    let tenant_id = parse_tenant_id(&tenant_id)?;
     match db.get_data_config(&tenant_id, Some(&device_id)) {
         Ok(Some(config)) => Ok(Json(config)),
         Ok(None) => Err(AppError::NotFound(format!(
@@ -230,7 +416,7 @@ pub async fn delete_config_handler(
     State(state): State<AppState>,
 ) -> Result<Json<()>, AppError> {
     let db = &state.db;
-    let tenant_id = TenantId::from_str(&tenant_id);
+    let tenant_id = parse_tenant_id(&tenant_id)?;
     match db.delete_data_config(&tenant_id, device_prefix.as_deref()) {
         Ok(_) => Ok(Json(())),
         Err(e) => Err(AppError::DatabaseError(e)),
@@ -242,20 +428,94 @@ pub async fn list_configs_handler(
     State(state): State<AppState>,
 ) -> Result<Json<Vec<DataConfigEntry>>, AppError> {
     let db = &state.db;
-    let tenant_id = TenantId::from_str(&tenant_id);
+    let tenant_id = parse_tenant_id(&tenant_id)?;
     match db.list_data_configs(&tenant_id) {
         Ok(configs) => Ok(Json(configs)),
         Err(e) => Err(AppError::DatabaseError(e)),
     }
 }
 
+#[derive(Deserialize)]
+pub struct TestConfigBody {
+    /// Device whose effective config is tested, only the tenant config is used if not set
+    pub device_id: Option<String>,
+    /// Sample payload as a device would publish it
+    pub payload: serde_json::Value,
+    /// Unsaved config merged over the stored config like a device config
+    pub config: Option<DataConfig>,
+    /// Unit of the returned timestamps, defaults to `s`
+    #[serde(default)]
+    pub precision: Precision,
+}
+
+#[derive(Serialize)]
+pub struct TestConfigResponse {
+    /// Effective config the payload was extracted with
+    pub config: DataConfig,
+    pub metrics: Vec<TimeSeriesModel>,
+}
+
+// Dry run of the metric extraction, nothing is stored
+pub async fn test_config_handler(
+    Path(tenant_id): Path<String>,
+    State(state): State<AppState>,
+    Json(body): Json<TestConfigBody>,
+) -> Result<Json<TestConfigResponse>, AppError> {
+    let db = &state.db;
+    let tenant_id = parse_tenant_id(&tenant_id)?;
+    if let Some(config) = &body.config {
+        config
+            .validate()
+            .map_err(|e| AppError::BadRequest(e.to_string()))?;
+    }
+    let stored = match db.get_data_config(&tenant_id, body.device_id.as_deref()) {
+        Ok(config) => config,
+        Err(DatabaseError::DataConfigError(key, e)) => {
+            return Err(AppError::BadRequest(format!(
+                "Stored config {} is invalid: {}",
+                key, e
+            )))
+        }
+        Err(e) => return Err(AppError::DatabaseError(e)),
+    };
+    let config = match (stored, body.config) {
+        (Some(stored), Some(config)) => stored.merge_with(&config),
+        (Some(config), None) | (None, Some(config)) => config,
+        (None, None) => {
+            return Err(AppError::NotFound(format!(
+                "No config found for tenant: {} and device: {:?}",
+                tenant_id, body.device_id
+            )))
+        }
+    };
+
+    let device_id = body.device_id.unwrap_or_default();
+    let metrics = config
+        .extract_metrics_from_json(body.payload, now_micros())
+        .iter()
+        .map(|(name, series)| series.to_model(&device_id, name, body.precision))
+        .collect();
+    Ok(Json(TestConfigResponse { config, metrics }))
+}
+
 pub async fn list_connections_handler(
-    Path(_tenant_id): Path<String>,
+    Path(tenant_id): Path<String>,
     State(state): State<AppState>,
 ) -> Result<Json<Vec<String>>, AppError> {
+    let tenant_id = parse_tenant_id(&tenant_id)?;
     let devices = state.connected_clients;
-    let device_iter = devices.iter();
-    let connections = device_iter.map(|x| (*x).to_owned()).collect();
+    // only list the devices of the requested tenant
+    let connections = devices
+        .iter()
+        .filter_map(|client_id| {
+            let (client_tenant, device_id) = split_client_id(&client_id);
+            if client_tenant == tenant_id {
+                Some(device_id)
+            } else {
+                None
+            }
+        })
+        .collect();
     Ok(Json(connections))
 }
 
@@ -280,6 +540,29 @@ pub async fn backup_database_handler(
 #[derive(Deserialize)]
 pub struct PutDeviceBody {
     key: Option<String>,
+    /// Optional mqtt password, as an alternative to the client certificate
+    password: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct EnrollDeviceBody {
+    /// PEM encoded CSR, its common name is the client id of the device
+    csr: String,
+    password: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateDeviceBody {
+    enabled: Option<bool>,
+    password: Option<String>,
+}
+
+fn parse_password_hash(password: &str) -> Result<String, AppError> {
+    if password.is_empty() {
+        return Err(AppError::BadRequest("Password must not be empty".to_string()));
+    }
+    hash_password(password)
+        .map_err(|e| AppError::InternalServerError(format!("Failed to hash password: {}", e)))
 }
 
 // Handler to create or update device metadata
@@ -287,9 +570,9 @@ pub async fn post_device_metadata_handler(
     Path((tenant_id, device_id)): Path<(String, String)>,
     State(state): State<AppState>,
     Json(device_info): Json<PutDeviceBody>,
-) -> Result<Json<DeviceMetadata>, AppError> {
+) -> Result<Json<CreatedDeviceResponse>, AppError> {
     // Ensure the path parameters match the body
-    let tenant_id = TenantId::from_str(&tenant_id);
+    let tenant_id = parse_tenant_id(&tenant_id)?;
     let db = state.db.clone();
     let cert_manager = state.cert_manager.clone();
 
@@ -300,21 +583,79 @@ pub async fn post_device_metadata_handler(
         // You might want to store this key in the device metadata
         // or use it for certificate generation
     }
-    let metadata = create_device(&device_id, &tenant_id, db, cert_manager)?;
+    let password_hash = match &device_info.password {
+        Some(password) => Some(parse_password_hash(password)?),
+        None => None,
+    };
+    let (mut metadata, key) = create_device(&device_id, &tenant_id, db, cert_manager)?;
+    metadata.password_hash = password_hash;
 
     match state.db.put_device_metadata(&metadata) {
-        Ok(_) => Ok(Json(metadata)),
+        Ok(_) => Ok(Json(CreatedDeviceResponse {
+            device: metadata.into(),
+            key,
+        })),
         Err(e) => Err(AppError::DatabaseError(e)),
     }
 }
 
+// Handler to register a device with a certificate signed from its CSR,
+// only the certificate is stored
+pub async fn enroll_device_handler(
+    Path((tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Json(body): Json<EnrollDeviceBody>,
+) -> Result<Json<DeviceResponse>, AppError> {
+    let tenant_id = parse_tenant_id(&tenant_id)?;
+    let password_hash = match &body.password {
+        Some(password) => Some(parse_password_hash(password)?),
+        None => None,
+    };
+    let mut metadata = enroll_device(
+        &device_id,
+        &tenant_id,
+        &body.csr,
+        state.db.clone(),
+        state.cert_manager.clone(),
+    )?;
+    if password_hash.is_some() {
+        metadata.password_hash = password_hash;
+        state.db.put_device_metadata(&metadata)?;
+    }
+    Ok(Json(metadata.into()))
+}
+
+// Handler to enable or disable a device and to set its mqtt password
+pub async fn update_device_handler(
+    Path((tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Json(update): Json<UpdateDeviceBody>,
+) -> Result<Json<DeviceResponse>, AppError> {
+    let tenant_id = parse_tenant_id(&tenant_id)?;
+    let mut metadata = match state.db.get_device_metadata(&tenant_id, &device_id)? {
+        Some(metadata) => metadata,
+        None => return Err(AppError::NotFound(format!(
+            "Device metadata not found for tenant: {} and device: {}",
+            tenant_id, device_id
+        ))),
+    };
+    if let Some(enabled) = update.enabled {
+        metadata.enabled = enabled;
+    }
+    if let Some(password) = &update.password {
+        metadata.password_hash = Some(parse_password_hash(password)?);
+    }
+    state.db.put_device_metadata(&metadata)?;
+    Ok(Json(metadata.into()))
+}
+
 // Handler to get detailed device information
 pub async fn get_device_info_handler(
     Path((tenant_id, device_id)): Path<(String, String)>,
     State(state): State<AppState>,
     Query(params): Query<HashMap<String, String>>,
 ) -> Result<Json<DeviceInformation>, AppError> {
-    let tenant_id = TenantId::from_str(&tenant_id);
+    let tenant_id = parse_tenant_id(&tenant_id)?;
     
     // Get device metadata
     let metadata = match state.db.get_device_metadata(&tenant_id, &device_id) {
@@ -327,7 +668,9 @@ pub async fn get_device_info_handler(
     };
     
     // Check connection status
-    let connected = state.connected_clients.contains(&device_id);
+    let connected = state
+        .connected_clients
+        .contains(&to_client_id(&tenant_id, &device_id));
     
     // Get shadow last update time if requested
     let mut last_shadow_update = None;
@@ -366,11 +709,11 @@ pub async fn get_device_info_handler(
 pub async fn get_device_metadata_handler(
     Path((tenant_id, device_id)): Path<(String, String)>,
     State(state): State<AppState>,
-) -> Result<Json<DeviceMetadata>, AppError> {
-    let tenant_id = TenantId::from_str(&tenant_id);
+) -> Result<Json<DeviceResponse>, AppError> {
+    let tenant_id = parse_tenant_id(&tenant_id)?;
     
     match state.db.get_device_metadata(&tenant_id, &device_id) {
-        Ok(Some(metadata)) => Ok(Json(metadata)),
+        Ok(Some(metadata)) => Ok(Json(metadata.into())),
         Ok(None) => Err(AppError::NotFound(format!(
             "Device metadata not found for tenant: {} and device: {}",
             tenant_id, device_id
@@ -385,7 +728,7 @@ pub async fn list_devices_handler(
     Path(tenant_id): Path<String>,
     State(state): State<AppState>,
 ) -> Result<Json<Vec<String>>, AppError> {
-    let tenant_id = TenantId::from_str(&tenant_id);
+    let tenant_id = parse_tenant_id(&tenant_id)?;
     
     match state.db.list_devices(&tenant_id) {
         Ok(devices) =>{
@@ -400,14 +743,92 @@ pub async fn list_devices_handler(
     }
 }
 
-// Handler to delete device metadata
+// Handler to delete device metadata, the certificate of the device is revoked
 pub async fn delete_device_metadata_handler(
     Path((tenant_id, device_id)): Path<(String, String)>,
     State(state): State<AppState>,
 ) -> Result<Json<()>, AppError> {
-    let tenant_id = TenantId::from_str(&tenant_id);
+    let tenant_id = parse_tenant_id(&tenant_id)?;
+    if let Some(certificate) = state
+        .db
+        .get_device_metadata(&tenant_id, &device_id)?
+        .and_then(|metadata| metadata.certificate)
+    {
+        state.cert_manager.revoke_certificate(&certificate, Some("device deleted"))?;
+    }
     match state.db.delete_device_metadata(&tenant_id, &device_id) {
         Ok(_) => Ok(Json(())),
         Err(e) => Err(AppError::DatabaseError(e)),
     }
-}
\ No newline at end of file
+}
+
+#[derive(Deserialize)]
+pub struct RevokeDeviceBody {
+    reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeSerialBody {
+    serial: String,
+    reason: Option<String>,
+}
+
+// Handler to revoke the certificate of a device
+pub async fn revoke_device_handler(
+    Path((tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Json(body): Json<RevokeDeviceBody>,
+) -> Result<Json<RevokedCertificate>, AppError> {
+    let tenant_id = parse_tenant_id(&tenant_id)?;
+    let revoked = revoke_device_certificate(
+        &device_id,
+        &tenant_id,
+        state.db.clone(),
+        state.cert_manager.clone(),
+        body.reason.as_deref(),
+    )?;
+    Ok(Json(revoked))
+}
+
+// Handler to renew the certificate of a device, with a CSR the private key stays on the device
+pub async fn renew_device_handler(
+    Path((tenant_id, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Json(request): Json<RenewalRequest>,
+) -> Result<Json<RenewedCertificate>, AppError> {
+    let tenant_id = parse_tenant_id(&tenant_id)?;
+    let renewed = renew_device_certificate(
+        &device_id,
+        &tenant_id,
+        state.db.clone(),
+        state.cert_manager.clone(),
+        &request,
+    )?;
+    Ok(Json(renewed))
+}
+
+// Handler to revoke a certificate by its serial number
+pub async fn revoke_serial_handler(
+    State(state): State<AppState>,
+    Json(body): Json<RevokeSerialBody>,
+) -> Result<Json<RevokedCertificate>, AppError> {
+    let serial = CertificateManager::normalize_serial(&body.serial).map_err(|e| match e {
+        CertificateError::ValidationError(msg) => AppError::BadRequest(msg),
+        e => AppError::CertificateError(e),
+    })?;
+    let revoked = state.cert_manager.revoke_serial(&serial, body.reason.as_deref())?;
+    Ok(Json(revoked))
+}
+
+// Handler to list the revoked certificates
+pub async fn list_revoked_handler(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<RevokedCertificate>>, AppError> {
+    Ok(Json(state.cert_manager.list_revoked()?))
+}
+
+// Handler to download the current CRL
+pub async fn get_crl_handler(State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    let crl = state.cert_manager.get_crl()?;
+    Ok(([(header::CONTENT_TYPE, "application/x-pem-file")], crl))
+}
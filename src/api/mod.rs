@@ -30,9 +30,9 @@ pub async fn start_api_server(
     mqtt_sender: Option<MqttSender>,
     mqtt_metrics: Arc<MqttServerMetrics>,
     connected_clients: Arc<ConnectionSet>,
+    cert_manager: Arc<CertificateManager>,
     config: &ForestConfig,
 ) -> CancellationToken {
-    let cert_manager = Arc::new(CertificateManager::new(&config.cert_dir, config.tenant_id.clone()).unwrap());
     let state = AppState {
         db: db.clone(),
         mqtt_sender,
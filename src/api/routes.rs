@@ -1,15 +1,29 @@
 use crate::api::handlers::*;
 use crate::api::AppState;
-use axum::{routing::get, routing::put, Router};
+use axum::{routing::get, routing::post, routing::put, Router};
 
 pub fn get_routes(state: AppState) -> Router {
     Router::new()
         .route("/", get(home_handler))
         .route("/health", get(health_handler))
-        .route("/{tenant_id}/shadow/{device_id}", get(get_shadow_handler))
-        .route("/{tenant_id}/shadow/{device_id}", put(update_shadow_handler))
-        // .route("/{tenant_id}/shadow/{device_id}/{shadow_name}", get(get_named_shadow_handler))
-        // .route("/{tenant_id}/shadow/{device_id}/{shadow_name}", put(update_named_shadow_handler))
+        .route(
+            "/{tenant_id}/shadow/{device_id}",
+            get(list_shadows_handler).put(update_shadow_handler),
+        )
+        .route(
+            "/{tenant_id}/shadow/{device_id}/{shadow_name}",
+            get(get_named_shadow_handler)
+                .put(update_named_shadow_handler)
+                .delete(delete_shadow_handler),
+        )
+        .route(
+            "/{tenant_id}/shadow/{device_id}/{shadow_name}/history",
+            get(list_shadow_history_handler),
+        )
+        .route(
+            "/{tenant_id}/shadow/{device_id}/{shadow_name}/history/snapshot",
+            get(get_shadow_snapshot_handler),
+        )
         .route("/{tenant_id}/data/{device_id}/{metric}", get(get_timeseries_handler))
         .route(
             "/{tenant_id}/data/{device_id}/{metric}/last",
@@ -28,6 +42,7 @@ pub fn get_routes(state: AppState) -> Router {
                 .delete(delete_config_handler),
         )
         .route("/{tenant_id}/dataconfig/all", get(list_configs_handler))
+        .route("/{tenant_id}/dataconfig/test", post(test_config_handler))
         .route("/{tenant_id}/connected", get(list_connections_handler))
         .route(
             "/{tenant_id}/devices",
@@ -37,12 +52,28 @@ pub fn get_routes(state: AppState) -> Router {
             "/{tenant_id}/devices/{device_id}",
             get(get_device_info_handler)
                 .post(post_device_metadata_handler)
+                .put(update_device_handler)
                 .delete(delete_device_metadata_handler)
         )
         .route(
             "/{tenant_id}/devices/{device_id}/metadata",
             get(get_device_metadata_handler)
         )
+        .route(
+            "/{tenant_id}/devices/{device_id}/enroll",
+            post(enroll_device_handler)
+        )
+        .route(
+            "/{tenant_id}/devices/{device_id}/revoke",
+            post(revoke_device_handler)
+        )
+        .route(
+            "/{tenant_id}/devices/{device_id}/renew",
+            post(renew_device_handler)
+        )
+        .route("/certificates/revoke", post(revoke_serial_handler))
+        .route("/certificates/revoked", get(list_revoked_handler))
+        .route("/certificates/crl", get(get_crl_handler))
         .route("/database/backup", get(backup_database_handler))
         .with_state(state)
 }
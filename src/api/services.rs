@@ -1,21 +1,77 @@
 use std::sync::Arc;
 
-use crate::certs::CertificateManager;
+use crate::certs::renewal::{renew_device_certificate as renew_certificate, RenewalError, RenewalRequest, RenewedCertificate};
+use crate::certs::{CertificateError, CertificateManager, RevokedCertificate};
 use crate::db::DB;
 use crate::models::{DeviceMetadata, TenantId};
 use crate::api::error::AppError;
 
 
-pub fn create_device(device_id: &str, tenant_id: &TenantId, db: Arc<DB>, cert_manager: Arc<CertificateManager>) -> Result<DeviceMetadata, AppError> {
+/// Register a device with a new certificate, the generated private key is returned
+/// alongside the metadata and not stored
+pub fn create_device(device_id: &str, tenant_id: &TenantId, db: Arc<DB>, cert_manager: Arc<CertificateManager>) -> Result<(DeviceMetadata, String), AppError> {
     // Check if device already exists
     let existing_device = db.get_device_metadata(&tenant_id, &device_id)?;
     if existing_device.is_some() {
         return Err(AppError::Conflict(format!("Device {} already exists", device_id)));
     }
     // Generate Device Cert and Key
-    let cert_data = cert_manager.create_client_cert(device_id)?;
-    let device_metadata = DeviceMetadata::new(&device_id, &tenant_id).with_credentials(cert_data.cert, cert_data.key);
+    let cert_data = cert_manager.create_tenant_client_cert(tenant_id, device_id)?;
+    let device_metadata = DeviceMetadata::new(&device_id, &tenant_id).with_certificate(cert_data.cert);
     // Save device metadata to DB
     db.put_device_metadata(&device_metadata)?;
+    Ok((device_metadata, cert_data.key))
+}
+
+/// Revoke the registered certificate of a device. The metadata keeps the certificate,
+/// the broker refuses it until a new one is issued.
+pub fn revoke_device_certificate(device_id: &str, tenant_id: &TenantId, db: Arc<DB>, cert_manager: Arc<CertificateManager>, reason: Option<&str>) -> Result<RevokedCertificate, AppError> {
+    let device = match db.get_device_metadata(tenant_id, device_id)? {
+        Some(device) => device,
+        None => return Err(AppError::NotFound(format!("Device {} does not exist", device_id))),
+    };
+    let certificate = match &device.certificate {
+        Some(certificate) => certificate,
+        None => return Err(AppError::NotFound(format!("Device {} has no certificate", device_id))),
+    };
+    let revoked = cert_manager.revoke_certificate(certificate, reason)?;
+    tracing::info!(device_id, tenant_id = %tenant_id, serial = %revoked.serial, "Revoked device certificate");
+    Ok(revoked)
+}
+
+// A rejected CSR is a bad request, other certificate errors are internal
+fn csr_error(e: CertificateError) -> AppError {
+    match e {
+        CertificateError::ValidationError(_) | CertificateError::CommonNameMismatch { .. } => {
+            AppError::BadRequest(e.to_string())
+        }
+        e => AppError::CertificateError(e),
+    }
+}
+
+/// Issue a new certificate for a device, from its CSR if one is given
+pub fn renew_device_certificate(device_id: &str, tenant_id: &TenantId, db: Arc<DB>, cert_manager: Arc<CertificateManager>, request: &RenewalRequest) -> Result<RenewedCertificate, AppError> {
+    match renew_certificate(&db, &cert_manager, tenant_id, device_id, request) {
+        Ok(renewed) => Ok(renewed),
+        Err(RenewalError::UnknownDevice) => Err(AppError::NotFound(format!("Device {} does not exist", device_id))),
+        Err(RenewalError::DeviceDisabled) => Err(AppError::Conflict(format!("Device {} is disabled", device_id))),
+        Err(RenewalError::CertificateError(e)) => Err(csr_error(e)),
+        Err(RenewalError::DatabaseError(e)) => Err(AppError::DatabaseError(e)),
+    }
+}
+
+/// Register a device with a certificate signed from its CSR, the private key never leaves the device
+pub fn enroll_device(device_id: &str, tenant_id: &TenantId, csr: &str, db: Arc<DB>, cert_manager: Arc<CertificateManager>) -> Result<DeviceMetadata, AppError> {
+    // Check if device already exists
+    let existing_device = db.get_device_metadata(tenant_id, device_id)?;
+    if existing_device.is_some() {
+        return Err(AppError::Conflict(format!("Device {} already exists", device_id)));
+    }
+    let certificate = cert_manager
+        .sign_tenant_client_csr(tenant_id, device_id, csr)
+        .map_err(csr_error)?;
+    let mut device_metadata = DeviceMetadata::new(device_id, tenant_id);
+    device_metadata.certificate = Some(certificate);
+    db.put_device_metadata(&device_metadata)?;
     Ok(device_metadata)
-}
\ No newline at end of file
+}
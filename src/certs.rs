@@ -1,11 +1,14 @@
+mod crl;
+pub mod renewal;
+
 use openssl::asn1::{Asn1Integer, Asn1Time};
 use openssl::bn::{BigNum, MsbOption};
 use openssl::error::ErrorStack;
 use openssl::hash::MessageDigest;
 use openssl::nid::Nid;
-use openssl::pkey::{PKey, Private};
+use openssl::pkey::{HasPublic, Id, PKey, PKeyRef, Private};
 use openssl::rsa::Rsa;
-use openssl::x509::{X509, X509Builder, X509NameBuilder, X509ReqBuilder};
+use openssl::x509::{X509, X509Builder, X509NameBuilder, X509NameRef, X509Req, X509ReqBuilder};
 use openssl::x509::extension::{AuthorityKeyIdentifier, BasicConstraints, KeyUsage, SubjectAlternativeName, SubjectKeyIdentifier};
 use std::fs::{self, File};
 use std::io::{Read, Write};
@@ -13,12 +16,26 @@ use std::ops::Add;
 use std::path::{Path, PathBuf};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::collections::HashSet;
+use std::sync::Mutex;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::models::{to_client_id, TenantId};
+
 pub const CA_CERT_FILENAME: &str = "ca.pem";
 pub const CA_KEY_FILENAME: &str = "ca-key.pem";
 pub const SERVER_CERT_FILENAME: &str = "server.pem";
 pub const SERVER_KEY_FILENAME: &str = "server-key.pem";
+pub const DEFAULT_ORGANIZATION: &str = "Forest";
+pub const REVOKED_FILENAME: &str = "revoked.json";
+pub const CRL_FILENAME: &str = "crl.pem";
+
+/// Validity of client certificates unless configured otherwise
+const DEFAULT_CLIENT_VALIDITY_DAYS: u32 = 10 * 365;
+const DEFAULT_RENEWAL_GRACE_PERIOD_DAYS: u32 = 7;
+
+/// Clients have to fetch a new CRL before it expires, it is regenerated after half of it
+const CRL_VALIDITY: Duration = Duration::from_secs(30 * 24 * 60 * 60);
 
 #[derive(Error, Debug)]
 pub enum CertificateError {
@@ -48,6 +65,27 @@ pub enum CertificateError {
     
     #[error("Certificate exists but is invalid: {0}")]
     InvalidCertificate(String),
+
+    #[error("Invalid revocation list: {0}")]
+    InvalidRevocationList(String),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CertificateConfig {
+    /// Validity of issued client certificates in days
+    pub client_validity_days: u32,
+    /// Days the previous certificate of a device stays valid after a renewal
+    pub renewal_grace_period_days: u32,
+}
+
+impl Default for CertificateConfig {
+    fn default() -> Self {
+        Self {
+            client_validity_days: DEFAULT_CLIENT_VALIDITY_DAYS,
+            renewal_grace_period_days: DEFAULT_RENEWAL_GRACE_PERIOD_DAYS,
+        }
+    }
 }
 
 // A type alias for our result type
@@ -58,10 +96,34 @@ pub struct CertificateData {
     pub key: String,
 }
 
+/// A certificate on the revocation list
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RevokedCertificate {
+    /// Serial number as uppercase hex of whole bytes, as printed by OpenSSL
+    pub serial: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub common_name: Option<String>,
+    /// Unix timestamp in seconds
+    pub revoked_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// The revocation list persisted next to the CA, the CRL is generated from it
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RevocationList {
+    crl_number: u64,
+    revoked: Vec<RevokedCertificate>,
+}
+
 /// Certificate Manager for handling CA, server and client certificates
 pub struct CertificateManager {
     cert_dir: PathBuf,
     tenant_id: Option<String>,
+    client_validity_days: u32,
+    renewal_grace_period_days: u32,
+    // serializes updates of the revocation list
+    revocation_lock: Mutex<()>,
 }
 
 impl CertificateManager {
@@ -78,11 +140,29 @@ impl CertificateManager {
             }
         }
 
-        let n = Self { cert_dir: dir_path, tenant_id };
+        let n = Self {
+            cert_dir: dir_path,
+            tenant_id,
+            client_validity_days: DEFAULT_CLIENT_VALIDITY_DAYS,
+            renewal_grace_period_days: DEFAULT_RENEWAL_GRACE_PERIOD_DAYS,
+            revocation_lock: Mutex::new(()),
+        };
         n.ensure_dirs_exist()?;
         Ok(n)
     }
 
+    /// Apply the configured validity of client certificates and the renewal grace period
+    pub fn with_config(mut self, config: &CertificateConfig) -> Self {
+        self.client_validity_days = config.client_validity_days;
+        self.renewal_grace_period_days = config.renewal_grace_period_days;
+        self
+    }
+
+    /// How long the previous certificate of a device stays valid after a renewal
+    pub fn renewal_grace_period(&self) -> Duration {
+        Duration::from_secs(self.renewal_grace_period_days as u64 * 24 * 60 * 60)
+    }
+
     pub fn ensure_dirs_exist(&self) -> CertResult<()> {
         // Create the base directory
         if !self.cert_dir.exists() {
@@ -117,10 +197,36 @@ impl CertificateManager {
             // Create server certificate with the key and hostnames
             self.create_server_cert_with_key(server_name, host_names, &server_key)?;
         }
+
+        // Publish a fresh CRL on every start
+        self.generate_crl()?;
+
+        // Client keys are only returned, remove the ones earlier versions stored
+        self.remove_client_keys()?;
         
         Ok(())
     }
 
+    /// Remove the stored private keys of client certificates, the CA and server keys
+    /// are kept. Returns the number of removed keys.
+    pub fn remove_client_keys(&self) -> CertResult<usize> {
+        let mut removed = 0;
+        for entry in fs::read_dir(self.get_file_path(""))? {
+            let path = entry?.path();
+            let is_client_key = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| {
+                    name.ends_with("-key.pem") && name != CA_KEY_FILENAME && name != SERVER_KEY_FILENAME
+                });
+            if is_client_key && path.is_file() {
+                fs::remove_file(path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
     /// Generate an RSA private key with 2048 bits
     fn generate_private_key() -> Result<PKey<Private>, ErrorStack> {
         let rsa = Rsa::generate(2048)?;
@@ -139,7 +245,7 @@ impl CertificateManager {
     fn get_org_name(&self) -> String {
         match &self.tenant_id {
             Some(tenant) => tenant.clone(),
-            None => "Forest".to_string(),
+            None => DEFAULT_ORGANIZATION.to_string(),
         }
     }
 
@@ -316,13 +422,21 @@ impl CertificateManager {
 
     /// Create a client certificate signed by the CA
     pub fn create_client_cert(&self, client_name: &str) -> CertResult<CertificateData> {
-        // Ensure CA exists
-        self.ensure_ca_exists()?;
-        
-        // Load CA key and certificate
-        let ca_key = self.load_private_key(CA_KEY_FILENAME)?;
-        let ca_cert = self.load_certificate(CA_CERT_FILENAME)?;
-        
+        self.create_client_cert_with_org(client_name, &self.get_org_name())
+    }
+
+    /// Create a client certificate for a device of a tenant.
+    /// The common name is the mqtt client id of the device and the organization is the tenant.
+    pub fn create_tenant_client_cert(&self, tenant_id: &TenantId, device_id: &str) -> CertResult<CertificateData> {
+        let client_id = to_client_id(tenant_id, device_id);
+        let organization = match tenant_id {
+            TenantId::Default => self.get_org_name(),
+            TenantId::Custom(tenant) => tenant.clone(),
+        };
+        self.create_client_cert_with_org(&client_id, &organization)
+    }
+
+    fn create_client_cert_with_org(&self, client_name: &str, organization: &str) -> CertResult<CertificateData> {
         // Generate client private key
         let client_key = Self::generate_private_key()?;
         
@@ -330,7 +444,7 @@ impl CertificateManager {
         let mut req_builder = X509ReqBuilder::new()?;
         let mut x509_name = X509NameBuilder::new()?;
         x509_name.append_entry_by_nid(Nid::COMMONNAME, client_name)?;
-        x509_name.append_entry_by_nid(Nid::ORGANIZATIONNAME, &self.get_org_name())?;
+        x509_name.append_entry_by_nid(Nid::ORGANIZATIONNAME, organization)?;
         let x509_name = x509_name.build();
         
         req_builder.set_subject_name(&x509_name)?;
@@ -338,7 +452,82 @@ impl CertificateManager {
         req_builder.sign(&client_key, MessageDigest::sha256())?;
         let req = req_builder.build();
         
-        // Create client certificate
+        let client_cert = self.issue_client_cert(req.subject_name(), &client_key)?;
+        
+        // Save the client certificate, the private key is only returned
+        let client_cert_filename = format!("{}-cert.pem", client_name);
+        let cert = self.save_certificate(&client_cert, &client_cert_filename)?;
+        let key = String::from_utf8(client_key.private_key_to_pem_pkcs8()?)
+            .map_err(|_| CertificateError::ValidationError("Invalid UTF-8 in private key".to_string()))?;
+        
+        Ok(CertificateData { cert, key })
+    }
+
+    /// Sign the CSR of a device of a tenant, the private key stays on the device.
+    /// The CSR subject has to name the device, the certificate gets the subject and
+    /// extensions set by the CA and only the public key of the CSR.
+    pub fn sign_tenant_client_csr(&self, tenant_id: &TenantId, device_id: &str, csr_pem: &str) -> CertResult<String> {
+        let client_id = to_client_id(tenant_id, device_id);
+        let organization = match tenant_id {
+            TenantId::Default => self.get_org_name(),
+            TenantId::Custom(tenant) => tenant.clone(),
+        };
+
+        let req = X509Req::from_pem(csr_pem.as_bytes())
+            .map_err(|_| CertificateError::ValidationError("CSR is not PEM encoded".to_string()))?;
+        let public_key = req.public_key()?;
+        if !req.verify(&public_key)? {
+            return Err(CertificateError::ValidationError("CSR signature is invalid".to_string()));
+        }
+        let key_is_strong = match public_key.id() {
+            Id::RSA => public_key.bits() >= 2048,
+            Id::EC => public_key.bits() >= 256,
+            _ => false,
+        };
+        if !key_is_strong {
+            return Err(CertificateError::ValidationError("CSR key must be RSA (2048+) or EC (256+)".to_string()));
+        }
+        // The common name has to be the client id and an organization the tenant
+        let subject = req.subject_name();
+        let found = match subject.entries_by_nid(Nid::COMMONNAME).next() {
+            Some(entry) => entry.data().as_utf8()?.to_string(),
+            None => return Err(CertificateError::ValidationError("CSR has no common name".to_string())),
+        };
+        if found != client_id {
+            return Err(CertificateError::CommonNameMismatch { expected: client_id, found });
+        }
+        if let Some(entry) = subject.entries_by_nid(Nid::ORGANIZATIONNAME).next() {
+            let found = entry.data().as_utf8()?.to_string();
+            if found != organization {
+                return Err(CertificateError::ValidationError(format!(
+                    "CSR organization '{}' does not match tenant '{}'", found, organization
+                )));
+            }
+        }
+
+        let mut x509_name = X509NameBuilder::new()?;
+        x509_name.append_entry_by_nid(Nid::COMMONNAME, &client_id)?;
+        x509_name.append_entry_by_nid(Nid::ORGANIZATIONNAME, &organization)?;
+        let x509_name = x509_name.build();
+        let client_cert = self.issue_client_cert(&x509_name, &public_key)?;
+
+        // The key file of an earlier certificate does not belong to this one
+        let client_key_path = self.get_file_path(&format!("{}-key.pem", client_id));
+        if client_key_path.exists() {
+            fs::remove_file(client_key_path)?;
+        }
+        self.save_certificate(&client_cert, &format!("{}-cert.pem", client_id))
+    }
+
+    /// Issue a client certificate for a public key, valid for the configured number of days
+    fn issue_client_cert<T: HasPublic>(&self, subject: &X509NameRef, public_key: &PKeyRef<T>) -> CertResult<X509> {
+        // Ensure CA exists
+        self.ensure_ca_exists()?;
+
+        // Load CA key and certificate
+        let ca_key = self.load_private_key(CA_KEY_FILENAME)?;
+        let ca_cert = self.load_certificate(CA_CERT_FILENAME)?;
+
         let mut cert_builder = X509Builder::new()?;
         cert_builder.set_version(2)?;
         
@@ -348,10 +537,9 @@ impl CertificateManager {
         let serial = Asn1Integer::from_bn(&serial)?;
         cert_builder.set_serial_number(&serial)?;
         
-        cert_builder.set_subject_name(req.subject_name())?;
+        cert_builder.set_subject_name(subject)?;
         cert_builder.set_issuer_name(ca_cert.subject_name())?;
         
-        // Certificate valid for 10 years
         let not_before = Asn1Time::from_unix(SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
@@ -360,13 +548,13 @@ impl CertificateManager {
         let not_after = Asn1Time::from_unix(SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
-            .add(Duration::from_secs(10 * 365 * 24 * 60 * 60))
+            .add(Duration::from_secs(self.client_validity_days as u64 * 24 * 60 * 60))
             .as_secs() as i64)?;
         
         cert_builder.set_not_before(&not_before)?;
         cert_builder.set_not_after(&not_after)?;
         
-        cert_builder.set_pubkey(&client_key)?;
+        cert_builder.set_pubkey(public_key)?;
         
         // Set client certificate extensions
         let basic_constraints = BasicConstraints::new().build()?;
@@ -388,16 +576,14 @@ impl CertificateManager {
         
         // Sign the client certificate with the CA key
         cert_builder.sign(&ca_key, MessageDigest::sha256())?;
-        let client_cert = cert_builder.build();
-        
-        // Save the client certificate and private key
-        let client_cert_filename = format!("{}-cert.pem", client_name);
-        let client_key_filename = format!("{}-key.pem", client_name);
-        
-        let key = self.save_private_key(&client_key, &client_key_filename)?;
-        let cert = self.save_certificate(&client_cert, &client_cert_filename)?;
-        
-        Ok(CertificateData { cert, key })
+        Ok(cert_builder.build())
+    }
+
+    /// Get the expiry of a PEM encoded certificate as unix timestamp in seconds
+    pub fn certificate_expiry(cert_pem: &str) -> CertResult<u64> {
+        let cert = X509::from_pem(cert_pem.as_bytes())?;
+        let diff = Asn1Time::from_unix(0)?.diff(cert.not_after())?;
+        Ok((diff.days as i64 * 24 * 60 * 60 + diff.secs as i64).max(0) as u64)
     }
 
     /// Check if server certificate exists and contains all required hostnames
@@ -586,6 +772,168 @@ impl CertificateManager {
         // Create with multiple hostnames
         self.create_server_cert_with_key(server_name, host_names, &server_key)
     }
+
+    /// Load the revocation list, it is empty until the first revocation
+    fn load_revocation_list(&self) -> CertResult<RevocationList> {
+        let path = self.get_file_path(REVOKED_FILENAME);
+        if !path.exists() {
+            return Ok(RevocationList::default());
+        }
+        let contents = fs::read(&path)?;
+        serde_json::from_slice(&contents)
+            .map_err(|e| CertificateError::InvalidRevocationList(e.to_string()))
+    }
+
+    /// Save the revocation list, written to a temporary file first so it is never truncated
+    fn save_revocation_list(&self, list: &RevocationList) -> CertResult<()> {
+        let contents = serde_json::to_vec_pretty(list)
+            .map_err(|e| CertificateError::InvalidRevocationList(e.to_string()))?;
+        let path = self.get_file_path(REVOKED_FILENAME);
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, contents)?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Normalize a hex serial number (optionally `:` separated) to the form of the revocation list
+    pub fn normalize_serial(serial: &str) -> CertResult<String> {
+        let hex = serial.trim().replace(':', "");
+        if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(CertificateError::ValidationError(format!("Invalid serial number: {}", serial)));
+        }
+        Ok(BigNum::from_hex_str(&hex)?.to_hex_str()?.to_string())
+    }
+
+    /// Get the serial number of a PEM encoded certificate as uppercase hex
+    pub fn certificate_serial(cert_pem: &str) -> CertResult<String> {
+        let cert = X509::from_pem(cert_pem.as_bytes())?;
+        Ok(cert.serial_number().to_bn()?.to_hex_str()?.to_string())
+    }
+
+    /// Revoke a certificate issued by our CA
+    pub fn revoke_certificate(&self, cert_pem: &str, reason: Option<&str>) -> CertResult<RevokedCertificate> {
+        let cert = X509::from_pem(cert_pem.as_bytes())?;
+        let ca_cert = self.load_certificate(CA_CERT_FILENAME)?;
+        let ca_public_key = ca_cert.public_key()?;
+        if !cert.verify(&ca_public_key)? {
+            return Err(CertificateError::ValidationError("Certificate was not issued by this CA".to_string()));
+        }
+        let serial = cert.serial_number().to_bn()?.to_hex_str()?.to_string();
+        let common_name = cert.subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .and_then(|entry| entry.data().as_utf8().ok())
+            .map(|cn| cn.to_string());
+        self.add_revocation(serial, common_name, reason)
+    }
+
+    /// Revoke a certificate by its serial number
+    pub fn revoke_serial(&self, serial: &str, reason: Option<&str>) -> CertResult<RevokedCertificate> {
+        let serial = Self::normalize_serial(serial)?;
+        self.add_revocation(serial, None, reason)
+    }
+
+    fn add_revocation(&self, serial: String, common_name: Option<String>, reason: Option<&str>) -> CertResult<RevokedCertificate> {
+        let _guard = self.revocation_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut list = self.load_revocation_list()?;
+        // Revoking a certificate twice keeps the original entry
+        if let Some(existing) = list.revoked.iter().find(|r| r.serial == serial) {
+            return Ok(existing.clone());
+        }
+        let revoked = RevokedCertificate {
+            serial,
+            common_name,
+            revoked_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            reason: reason.map(|r| r.to_string()),
+        };
+        list.revoked.push(revoked.clone());
+        self.write_crl(&mut list)?;
+        Ok(revoked)
+    }
+
+    /// List all revoked certificates
+    pub fn list_revoked(&self) -> CertResult<Vec<RevokedCertificate>> {
+        Ok(self.load_revocation_list()?.revoked)
+    }
+
+    /// Check if the certificate with the given serial number was revoked
+    pub fn is_serial_revoked(&self, serial: &str) -> CertResult<bool> {
+        let serial = Self::normalize_serial(serial)?;
+        Ok(self.load_revocation_list()?.revoked.iter().any(|r| r.serial == serial))
+    }
+
+    /// Check if any certificate issued for the common name was revoked
+    pub fn is_common_name_revoked(&self, common_name: &str) -> CertResult<bool> {
+        Ok(self
+            .load_revocation_list()?
+            .revoked
+            .iter()
+            .any(|r| r.common_name.as_deref() == Some(common_name)))
+    }
+
+    /// Check if a PEM encoded certificate was revoked
+    pub fn is_certificate_revoked(&self, cert_pem: &str) -> CertResult<bool> {
+        self.is_serial_revoked(&Self::certificate_serial(cert_pem)?)
+    }
+
+    /// Generate the CRL from the revocation list and save it next to the CA
+    pub fn generate_crl(&self) -> CertResult<String> {
+        let _guard = self.revocation_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut list = self.load_revocation_list()?;
+        self.write_crl(&mut list)
+    }
+
+    /// Get the current CRL, it is regenerated if it is missing or half of its validity has passed
+    pub fn get_crl(&self) -> CertResult<String> {
+        let path = self.get_file_path(CRL_FILENAME);
+        let is_fresh = fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age < CRL_VALIDITY / 2);
+        if is_fresh {
+            return Ok(fs::read_to_string(path)?);
+        }
+        self.generate_crl()
+    }
+
+    // Bumps the CRL number, saves the revocation list and writes the signed CRL
+    fn write_crl(&self, list: &mut RevocationList) -> CertResult<String> {
+        let ca_cert = self.load_certificate(CA_CERT_FILENAME)?;
+        let ca_key = self.load_private_key(CA_KEY_FILENAME)?;
+
+        let mut entries = Vec::with_capacity(list.revoked.len());
+        for revoked in &list.revoked {
+            entries.push(crl::CrlEntry {
+                serial: BigNum::from_hex_str(&revoked.serial)?,
+                revoked_at: revoked.revoked_at as i64,
+            });
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        list.crl_number += 1;
+        let crl_pem = crl::build_crl(
+            ca_cert.subject_name(),
+            &ca_key,
+            &entries,
+            list.crl_number,
+            now.as_secs() as i64,
+            now.add(CRL_VALIDITY).as_secs() as i64,
+        )?;
+        self.save_revocation_list(list)?;
+
+        let mut file = File::create(self.get_file_path(CRL_FILENAME))?;
+        file.write_all(crl_pem.as_bytes())?;
+        Ok(crl_pem)
+    }
+}
+
+/// Map the organization of a client certificate to the tenant it was issued for
+pub fn tenant_from_organization(organization: &str) -> TenantId {
+    if organization == DEFAULT_ORGANIZATION {
+        TenantId::Default
+    } else {
+        TenantId::from_str(organization)
+    }
 }
 
 #[cfg(test)]
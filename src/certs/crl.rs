@@ -0,0 +1,134 @@
+//! DER encoding of X.509 v2 certificate revocation lists (RFC 5280).
+//!
+//! Only what a CRL of our CA needs: an RSA/SHA-256 signature, the serials and
+//! revocation times of the revoked certificates and the CRL number extension.
+
+use chrono::Datelike;
+use openssl::base64;
+use openssl::bn::BigNum;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKeyRef, Private};
+use openssl::sign::Signer;
+use openssl::x509::X509NameRef;
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_NULL: u8 = 0x05;
+const TAG_OID: u8 = 0x06;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_EXTENSIONS: u8 = 0xa0;
+
+/// sha256WithRSAEncryption, 1.2.840.113549.1.1.11
+const OID_SHA256_WITH_RSA: [u8; 9] = [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+/// cRLNumber, 2.5.29.20
+const OID_CRL_NUMBER: [u8; 3] = [0x55, 0x1d, 0x14];
+
+/// A revoked certificate as listed in the CRL
+pub struct CrlEntry {
+    pub serial: BigNum,
+    /// Unix timestamp in seconds
+    pub revoked_at: i64,
+}
+
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|b| *b == 0)
+            .collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend_from_slice(&bytes);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+fn der_integer(value: &BigNum) -> Vec<u8> {
+    let mut bytes = value.to_vec();
+    // positive integers must not start with a set high bit
+    if bytes.first().is_none_or(|b| b & 0x80 != 0) {
+        bytes.insert(0, 0);
+    }
+    der(TAG_INTEGER, &bytes)
+}
+
+// UTCTime until 2049, GeneralizedTime afterwards
+fn der_time(timestamp: i64) -> Vec<u8> {
+    let time = chrono::DateTime::from_timestamp(timestamp, 0).unwrap_or_default();
+    if time.year() < 2050 {
+        der(TAG_UTC_TIME, time.format("%y%m%d%H%M%SZ").to_string().as_bytes())
+    } else {
+        der(
+            TAG_GENERALIZED_TIME,
+            time.format("%Y%m%d%H%M%SZ").to_string().as_bytes(),
+        )
+    }
+}
+
+fn signature_algorithm() -> Vec<u8> {
+    der(
+        TAG_SEQUENCE,
+        &[der(TAG_OID, &OID_SHA256_WITH_RSA), der(TAG_NULL, &[])].concat(),
+    )
+}
+
+/// Build a CRL signed by the CA and return it PEM encoded
+pub fn build_crl(
+    issuer: &X509NameRef,
+    ca_key: &PKeyRef<Private>,
+    entries: &[CrlEntry],
+    crl_number: u64,
+    this_update: i64,
+    next_update: i64,
+) -> Result<String, ErrorStack> {
+    let mut tbs = Vec::new();
+    // v2
+    tbs.extend(der(TAG_INTEGER, &[1]));
+    tbs.extend(signature_algorithm());
+    tbs.extend(issuer.to_der()?);
+    tbs.extend(der_time(this_update));
+    tbs.extend(der_time(next_update));
+    if !entries.is_empty() {
+        let mut revoked = Vec::new();
+        for entry in entries {
+            let content = [der_integer(&entry.serial), der_time(entry.revoked_at)].concat();
+            revoked.extend(der(TAG_SEQUENCE, &content));
+        }
+        tbs.extend(der(TAG_SEQUENCE, &revoked));
+    }
+    let crl_number = der_integer(&BigNum::from_dec_str(&crl_number.to_string())?);
+    let extension = der(
+        TAG_SEQUENCE,
+        &[der(TAG_OID, &OID_CRL_NUMBER), der(TAG_OCTET_STRING, &crl_number)].concat(),
+    );
+    tbs.extend(der(TAG_EXTENSIONS, &der(TAG_SEQUENCE, &extension)));
+    let tbs = der(TAG_SEQUENCE, &tbs);
+
+    let mut signer = Signer::new(MessageDigest::sha256(), ca_key)?;
+    signer.update(&tbs)?;
+    let signature = signer.sign_to_vec()?;
+    let mut bit_string = vec![0];
+    bit_string.extend(signature);
+
+    let crl = der(
+        TAG_SEQUENCE,
+        &[tbs, signature_algorithm(), der(TAG_BIT_STRING, &bit_string)].concat(),
+    );
+    let encoded = base64::encode_block(&crl);
+    let mut pem = String::from("-----BEGIN X509 CRL-----\n");
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(&String::from_utf8_lossy(line));
+        pem.push('\n');
+    }
+    pem.push_str("-----END X509 CRL-----\n");
+    Ok(pem)
+}
@@ -0,0 +1,160 @@
+//! Renewal of device certificates.
+//!
+//! A device requests a new certificate before its current one expires, either
+//! with a CSR so its private key never leaves the device, or without one and
+//! gets a new key pair from the CA. A generated key is only returned, never
+//! stored. The replaced certificate stays valid for a grace period so the
+//! device can switch over, afterwards it is revoked.
+
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tracing::{info, warn};
+
+use super::{CertificateError, CertificateManager};
+use crate::db::{DatabaseError, DB};
+use crate::models::{PreviousCertificate, TenantId};
+
+/// How often the grace periods of previous certificates are checked
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Error, Debug)]
+pub enum RenewalError {
+    #[error("Device is not registered")]
+    UnknownDevice,
+    #[error("Device is disabled")]
+    DeviceDisabled,
+    #[error("Certificate error: {0}")]
+    CertificateError(#[from] CertificateError),
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] DatabaseError),
+}
+
+/// A renewal request, without a CSR the CA generates the key pair
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RenewalRequest {
+    #[serde(default)]
+    pub csr: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RenewedCertificate {
+    pub certificate: String,
+    /// Only set if the key pair was generated by the CA, it is not stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    pub serial: String,
+    /// Unix timestamp in seconds
+    pub expires_at: u64,
+    /// End of the grace period of the replaced certificate
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_valid_until: Option<u64>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Issue a new certificate for a device. The active certificate becomes the
+/// previous one, a certificate still in its grace period is revoked right away.
+pub fn renew_device_certificate(
+    db: &DB,
+    certs: &CertificateManager,
+    tenant_id: &TenantId,
+    device_id: &str,
+    request: &RenewalRequest,
+) -> Result<RenewedCertificate, RenewalError> {
+    let mut device = db
+        .get_device_metadata(tenant_id, device_id)?
+        .ok_or(RenewalError::UnknownDevice)?;
+    if !device.enabled {
+        return Err(RenewalError::DeviceDisabled);
+    }
+
+    let (certificate, key) = match &request.csr {
+        Some(csr) => (
+            certs.sign_tenant_client_csr(tenant_id, device_id, csr)?,
+            None,
+        ),
+        None => {
+            let data = certs.create_tenant_client_cert(tenant_id, device_id)?;
+            (data.cert, Some(data.key))
+        }
+    };
+
+    if let Some(previous) = device.previous_certificate.take() {
+        certs.revoke_certificate(&previous.certificate, Some("superseded"))?;
+    }
+    let previous_valid_until = unix_now() + certs.renewal_grace_period().as_secs();
+    device.previous_certificate =
+        device
+            .certificate
+            .take()
+            .map(|certificate| PreviousCertificate {
+                certificate,
+                valid_until: previous_valid_until,
+            });
+    device.certificate = Some(certificate.clone());
+    db.put_device_metadata(&device)?;
+
+    let serial = CertificateManager::certificate_serial(&certificate)?;
+    info!(%tenant_id, device_id, serial, "Renewed device certificate");
+    Ok(RenewedCertificate {
+        expires_at: CertificateManager::certificate_expiry(&certificate)?,
+        certificate,
+        key,
+        serial,
+        previous_valid_until: device.previous_certificate.map(|p| p.valid_until),
+    })
+}
+
+/// Revoke the previous certificates whose grace period has ended, returns their number
+pub fn expire_previous_certificates(
+    db: &DB,
+    certs: &CertificateManager,
+    now: u64,
+) -> Result<usize, RenewalError> {
+    let mut expired = 0;
+    for mut device in db.list_all_devices()? {
+        let previous = match &device.previous_certificate {
+            Some(previous) if previous.valid_until <= now => previous,
+            _ => continue,
+        };
+        certs.revoke_certificate(&previous.certificate, Some("superseded"))?;
+        device.previous_certificate = None;
+        db.put_device_metadata(&device)?;
+        expired += 1;
+    }
+    Ok(expired)
+}
+
+/// Periodically revoke previous certificates after their grace period
+pub fn start_expiry_worker(
+    db: Arc<DB>,
+    certs: Arc<CertificateManager>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(EXPIRY_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let db = db.clone();
+            let certs = certs.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                expire_previous_certificates(&db, &certs, unix_now())
+            })
+            .await;
+            match result {
+                Ok(Err(e)) => warn!(error = ?e, "Certificate expiry failed"),
+                Err(e) => warn!(error = ?e, "Certificate expiry task failed"),
+                Ok(Ok(expired)) if expired > 0 => {
+                    info!(expired, "Revoked previous certificates")
+                }
+                Ok(Ok(_)) => {}
+            }
+        }
+    })
+}
@@ -62,7 +62,25 @@ fn test_create_client_cert() {
     assert!(temp_dir.path().join(CA_CERT_FILENAME).exists());
     assert!(temp_dir.path().join(CA_KEY_FILENAME).exists());
     assert!(temp_dir.path().join("client1-cert.pem").exists());
-    assert!(temp_dir.path().join("client1-key.pem").exists());
+    // the private key is only returned
+    assert!(!temp_dir.path().join("client1-key.pem").exists());
+}
+
+#[test]
+fn test_remove_client_keys() {
+    let temp_dir = tempdir().unwrap();
+    let cert_manager = CertificateManager::new(&temp_dir, None).unwrap();
+    cert_manager.ensure_ca_exists().unwrap();
+    cert_manager.create_server_cert("example.com").unwrap();
+    fs::write(temp_dir.path().join("acme.device1-key.pem"), "key").unwrap();
+    fs::write(temp_dir.path().join("device2-key.pem"), "key").unwrap();
+
+    assert_eq!(cert_manager.remove_client_keys().unwrap(), 2);
+    assert!(!temp_dir.path().join("acme.device1-key.pem").exists());
+    assert!(!temp_dir.path().join("device2-key.pem").exists());
+    assert!(temp_dir.path().join(CA_KEY_FILENAME).exists());
+    assert!(temp_dir.path().join(SERVER_KEY_FILENAME).exists());
+    assert_eq!(cert_manager.remove_client_keys().unwrap(), 0);
 }
 
 #[test]
@@ -138,4 +156,228 @@ fn test_is_server_cert_valid() {
     // Test invalid cases
     assert!(!cert_manager.is_server_cert_valid("example.com", &["example.com", "another.example.com"]).unwrap());
     assert!(!cert_manager.is_server_cert_valid("wrong.com", &["example.com"]).unwrap());
-}
\ No newline at end of file
+}
+#[test]
+fn test_create_tenant_client_cert() {
+    let temp_dir = tempdir().unwrap();
+    let cert_manager = CertificateManager::new(&temp_dir, None).unwrap();
+
+    let cert_data = cert_manager
+        .create_tenant_client_cert(&TenantId::new("acme"), "device1")
+        .unwrap();
+    let cert = X509::from_pem(cert_data.cert.as_bytes()).unwrap();
+
+    // Common name is the client id, organization is the tenant
+    let cn = cert.subject_name().entries_by_nid(Nid::COMMONNAME).next().unwrap();
+    assert_eq!(cn.data().as_utf8().unwrap().to_string(), "acme.device1");
+    let org = cert.subject_name().entries_by_nid(Nid::ORGANIZATIONNAME).next().unwrap();
+    assert_eq!(org.data().as_utf8().unwrap().to_string(), "acme");
+    assert!(temp_dir.path().join("acme.device1-cert.pem").exists());
+
+    // Default tenant keeps the default organization
+    let cert_data = cert_manager
+        .create_tenant_client_cert(&TenantId::Default, "device1")
+        .unwrap();
+    let cert = X509::from_pem(cert_data.cert.as_bytes()).unwrap();
+    let org = cert.subject_name().entries_by_nid(Nid::ORGANIZATIONNAME).next().unwrap();
+    assert_eq!(org.data().as_utf8().unwrap().to_string(), DEFAULT_ORGANIZATION);
+
+    assert_eq!(tenant_from_organization(DEFAULT_ORGANIZATION), TenantId::Default);
+    assert_eq!(tenant_from_organization("acme"), TenantId::new("acme"));
+}
+
+#[test]
+fn test_revoke_certificate() {
+    use openssl::x509::{CrlStatus, X509Crl};
+
+    let temp_dir = tempdir().unwrap();
+    let cert_manager = CertificateManager::new(&temp_dir, None).unwrap();
+    cert_manager.ensure_ca_exists().unwrap();
+    let revoked_data = cert_manager
+        .create_tenant_client_cert(&TenantId::new("acme"), "device1")
+        .unwrap();
+    let valid_data = cert_manager
+        .create_tenant_client_cert(&TenantId::new("acme"), "device2")
+        .unwrap();
+
+    let revoked = cert_manager.revoke_certificate(&revoked_data.cert, Some("lost")).unwrap();
+    assert_eq!(revoked.common_name.as_deref(), Some("acme.device1"));
+    assert_eq!(revoked.reason.as_deref(), Some("lost"));
+    assert!(cert_manager.is_certificate_revoked(&revoked_data.cert).unwrap());
+    assert!(!cert_manager.is_certificate_revoked(&valid_data.cert).unwrap());
+
+    // Revoking again keeps the original entry
+    let again = cert_manager.revoke_certificate(&revoked_data.cert, Some("other")).unwrap();
+    assert_eq!(again, revoked);
+    assert_eq!(cert_manager.list_revoked().unwrap().len(), 1);
+
+    // The CRL is signed by the CA and lists the revoked certificate only
+    let crl_pem = fs::read(temp_dir.path().join(CRL_FILENAME)).unwrap();
+    let crl = X509Crl::from_pem(&crl_pem).unwrap();
+    let ca_cert = X509::from_pem(&fs::read(temp_dir.path().join(CA_CERT_FILENAME)).unwrap()).unwrap();
+    assert!(crl.verify(&ca_cert.public_key().unwrap()).unwrap());
+    assert!(crl.next_update().is_some());
+    let revoked_cert = X509::from_pem(revoked_data.cert.as_bytes()).unwrap();
+    let valid_cert = X509::from_pem(valid_data.cert.as_bytes()).unwrap();
+    assert!(matches!(crl.get_by_serial(revoked_cert.serial_number()), CrlStatus::Revoked(_)));
+    assert!(matches!(crl.get_by_serial(valid_cert.serial_number()), CrlStatus::NotRevoked));
+
+    // The revocation list is persisted next to the CA
+    assert!(temp_dir.path().join(REVOKED_FILENAME).exists());
+    let reopened = CertificateManager::new(&temp_dir, None).unwrap();
+    assert!(reopened.is_certificate_revoked(&revoked_data.cert).unwrap());
+    assert_eq!(reopened.get_crl().unwrap().as_bytes(), crl_pem.as_slice());
+
+    // Certificates of another CA are rejected
+    let other_dir = tempdir().unwrap();
+    let other_manager = CertificateManager::new(&other_dir, None).unwrap();
+    let other_data = other_manager.create_client_cert("device1").unwrap();
+    assert!(matches!(
+        cert_manager.revoke_certificate(&other_data.cert, None),
+        Err(CertificateError::ValidationError(_))
+    ));
+}
+
+#[test]
+fn test_revoke_serial() {
+    let temp_dir = tempdir().unwrap();
+    let cert_manager = CertificateManager::new(&temp_dir, None).unwrap();
+    cert_manager.ensure_ca_exists().unwrap();
+
+    assert_eq!(CertificateManager::normalize_serial("00:0a:bC").unwrap(), "0ABC");
+    assert!(CertificateManager::normalize_serial("").is_err());
+    assert!(CertificateManager::normalize_serial("xyz").is_err());
+
+    let revoked = cert_manager.revoke_serial("0a:bc", None).unwrap();
+    assert_eq!(revoked.serial, "0ABC");
+    assert!(cert_manager.is_serial_revoked("ABC").unwrap());
+    assert!(cert_manager.is_serial_revoked("00abc").unwrap());
+    assert!(!cert_manager.is_serial_revoked("ABD").unwrap());
+
+    let cert_data = cert_manager.create_client_cert("device1").unwrap();
+    let serial = CertificateManager::certificate_serial(&cert_data.cert).unwrap();
+    cert_manager.revoke_serial(&serial, None).unwrap();
+    assert!(cert_manager.is_certificate_revoked(&cert_data.cert).unwrap());
+    assert_eq!(cert_manager.list_revoked().unwrap().len(), 2);
+}
+
+fn create_csr_with_subject(subject: &[(Nid, &str)], bits: u32) -> String {
+    let key = PKey::from_rsa(Rsa::generate(bits).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    for (nid, value) in subject {
+        name.append_entry_by_nid(*nid, value).unwrap();
+    }
+    let name = name.build();
+    let mut req = X509ReqBuilder::new().unwrap();
+    req.set_subject_name(&name).unwrap();
+    req.set_pubkey(&key).unwrap();
+    req.sign(&key, MessageDigest::sha256()).unwrap();
+    String::from_utf8(req.build().to_pem().unwrap()).unwrap()
+}
+
+fn create_csr(common_name: &str, bits: u32) -> String {
+    create_csr_with_subject(&[(Nid::COMMONNAME, common_name)], bits)
+}
+
+#[test]
+fn test_sign_client_csr() {
+    let temp_dir = tempdir().unwrap();
+    let config = CertificateConfig {
+        client_validity_days: 30,
+        ..Default::default()
+    };
+    let cert_manager = CertificateManager::new(&temp_dir, None).unwrap().with_config(&config);
+    let tenant_id = TenantId::new("acme");
+
+    let cert_pem = cert_manager
+        .sign_tenant_client_csr(&tenant_id, "device1", &create_csr("acme.device1", 2048))
+        .unwrap();
+    let cert = X509::from_pem(cert_pem.as_bytes()).unwrap();
+    let org = cert.subject_name().entries_by_nid(Nid::ORGANIZATIONNAME).next().unwrap();
+    assert_eq!(org.data().as_utf8().unwrap().to_string(), "acme");
+    assert!(temp_dir.path().join("acme.device1-cert.pem").exists());
+    assert!(!temp_dir.path().join("acme.device1-key.pem").exists());
+
+    // Valid for the configured number of days
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let expires_at = CertificateManager::certificate_expiry(&cert_pem).unwrap();
+    assert!(expires_at.abs_diff(now + 30 * 24 * 60 * 60) < 60);
+
+    assert!(matches!(
+        cert_manager.sign_tenant_client_csr(&tenant_id, "device1", &create_csr("acme.device2", 2048)),
+        Err(CertificateError::CommonNameMismatch { .. })
+    ));
+    assert!(matches!(
+        cert_manager.sign_tenant_client_csr(&tenant_id, "device1", &create_csr("acme.device1", 1024)),
+        Err(CertificateError::ValidationError(_))
+    ));
+    assert!(matches!(
+        cert_manager.sign_tenant_client_csr(&tenant_id, "device1", "not a csr"),
+        Err(CertificateError::ValidationError(_))
+    ));
+
+    // The subject has to name the device and its tenant
+    let csr = create_csr_with_subject(&[(Nid::COMMONNAME, "acme.device1"), (Nid::ORGANIZATIONNAME, "acme")], 2048);
+    assert!(cert_manager.sign_tenant_client_csr(&tenant_id, "device1", &csr).is_ok());
+    let csr = create_csr_with_subject(&[(Nid::COMMONNAME, "acme.device1"), (Nid::ORGANIZATIONNAME, "other")], 2048);
+    assert!(matches!(
+        cert_manager.sign_tenant_client_csr(&tenant_id, "device1", &csr),
+        Err(CertificateError::ValidationError(_))
+    ));
+    let csr = create_csr_with_subject(&[(Nid::ORGANIZATIONNAME, "acme")], 2048);
+    assert!(matches!(
+        cert_manager.sign_tenant_client_csr(&tenant_id, "device1", &csr),
+        Err(CertificateError::ValidationError(_))
+    ));
+    // Devices of the default tenant use the default organization
+    let csr = create_csr_with_subject(&[(Nid::COMMONNAME, "device1"), (Nid::ORGANIZATIONNAME, DEFAULT_ORGANIZATION)], 2048);
+    assert!(cert_manager.sign_tenant_client_csr(&TenantId::Default, "device1", &csr).is_ok());
+}
+
+#[test]
+fn test_renew_device_certificate() {
+    use crate::db::DB;
+    use crate::models::DeviceMetadata;
+    use renewal::{expire_previous_certificates, renew_device_certificate, RenewalError, RenewalRequest};
+
+    let temp_dir = tempdir().unwrap();
+    let db = DB::open_default(temp_dir.path().join("db").to_str().unwrap()).unwrap();
+    let cert_manager = CertificateManager::new(temp_dir.path().join("certs"), None).unwrap();
+    let tenant_id = TenantId::new("acme");
+    let initial = cert_manager.create_tenant_client_cert(&tenant_id, "device1").unwrap();
+    let device = DeviceMetadata::new("device1", &tenant_id).with_certificate(initial.cert.clone());
+    db.put_device_metadata(&device).unwrap();
+
+    // With a CSR the private key is not known to the server
+    let request = RenewalRequest { csr: Some(create_csr("acme.device1", 2048)) };
+    let renewed = renew_device_certificate(&db, &cert_manager, &tenant_id, "device1", &request).unwrap();
+    assert!(renewed.key.is_none());
+    let device = db.get_device_metadata(&tenant_id, "device1").unwrap().unwrap();
+    assert_eq!(device.certificate.as_deref(), Some(renewed.certificate.as_str()));
+    let previous = device.previous_certificate.unwrap();
+    assert_eq!(previous.certificate, initial.cert);
+    assert_eq!(Some(previous.valid_until), renewed.previous_valid_until);
+    // The previous certificate stays valid during the grace period
+    assert!(!cert_manager.is_certificate_revoked(&initial.cert).unwrap());
+    assert_eq!(expire_previous_certificates(&db, &cert_manager, previous.valid_until - 1).unwrap(), 0);
+
+    // Renewing again revokes the certificate still in its grace period
+    let second = renew_device_certificate(&db, &cert_manager, &tenant_id, "device1", &RenewalRequest::default()).unwrap();
+    assert!(second.key.is_some());
+    assert!(cert_manager.is_certificate_revoked(&initial.cert).unwrap());
+    assert!(!cert_manager.is_certificate_revoked(&renewed.certificate).unwrap());
+
+    // After the grace period the previous certificate is revoked
+    let device = db.get_device_metadata(&tenant_id, "device1").unwrap().unwrap();
+    let valid_until = device.previous_certificate.unwrap().valid_until;
+    assert_eq!(expire_previous_certificates(&db, &cert_manager, valid_until).unwrap(), 1);
+    assert!(cert_manager.is_certificate_revoked(&renewed.certificate).unwrap());
+    assert!(!cert_manager.is_certificate_revoked(&second.certificate).unwrap());
+    let device = db.get_device_metadata(&tenant_id, "device1").unwrap().unwrap();
+    assert!(device.previous_certificate.is_none());
+
+    assert!(matches!(
+        renew_device_certificate(&db, &cert_manager, &tenant_id, "device2", &RenewalRequest::default()),
+        Err(RenewalError::UnknownDevice)
+    ));
+}
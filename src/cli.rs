@@ -44,4 +44,27 @@ pub enum Commands {
         #[arg(long)]
         device_id: String,
     },
+    /// Register a device with a certificate signed from its CSR
+    #[command(name="enroll-device")]
+    EnrollDevice {
+        /// Device ID
+        #[arg(long)]
+        device_id: String,
+        /// PEM encoded certificate signing request
+        #[arg(long, value_name = "FILE")]
+        csr: PathBuf,
+    },
+    /// Revoke the certificate of a device or a certificate by its serial number
+    #[command(name="revoke-certificate")]
+    RevokeCertificate {
+        /// Device ID
+        #[arg(long, required_unless_present = "serial", conflicts_with = "serial")]
+        device_id: Option<String>,
+        /// Certificate serial number (hex)
+        #[arg(long)]
+        serial: Option<String>,
+        /// Reason stored in the revocation list
+        #[arg(long)]
+        reason: Option<String>,
+    },
 }
\ No newline at end of file
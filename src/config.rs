@@ -2,6 +2,7 @@ use std::path::Path;
 use serde::{Serialize,Deserialize};
 use config::{Config, ConfigError, Environment, File};
 
+use crate::certs::CertificateConfig;
 use crate::mqtt::MqttConfig;
 use crate::processor::ProcessorConfig;
 use crate::db::DatabaseConfig;
@@ -14,6 +15,8 @@ pub struct ForestConfig {
     pub bind_api: String,
     pub tenant_id: Option<String>,
     pub cert_dir: String,
+    #[serde(default)]
+    pub certificates: CertificateConfig,
     pub server_name: String,
     pub host_names: Vec<String>,
 }
@@ -27,6 +30,7 @@ impl Default for ForestConfig {
             bind_api: String::from("127.0.0.1:8807"),
             tenant_id: None,
             cert_dir: "/etc/forest/certs".to_string(),
+            certificates: CertificateConfig::default(),
             server_name: String::from("localhost"),
             host_names: vec![String::from("localhost"), String::from("127.0.0.1")],
         }
@@ -46,6 +50,7 @@ impl ForestConfig {
             .set_default("mqtt.enable_ssl", default_config.mqtt.enable_ssl)?
             .set_default("mqtt.max_connections", default_config.mqtt.max_connections as u64)?
             .set_default("processor.shadow_topic_prefix", default_config.processor.shadow_topic_prefix)?
+            .set_default("processor.ingest_topics", default_config.processor.ingest_topics)?
             .set_default("database.create_if_missing", default_config.database.create_if_missing)?
             .set_default("database.path", default_config.database.path)?
             .set_default("database.backup_path", default_config.database.backup_path)?
@@ -1,7 +1,35 @@
-use crate::timeseries::{LatLong, MetricValue};
+pub mod pattern;
+pub mod transform;
+
+use crate::timeseries::{LatLong, MetricTimeSeries, MetricValue, MICROS_PER_SECOND};
 use crate::models::TenantId;
 use serde::{Deserialize, Serialize};
+use pattern::{expand_pointer, has_wildcard, is_valid_pointer, name_matches, placeholders, render_name};
 use serde_json::Value;
+use std::collections::HashSet;
+use thiserror::Error;
+use transform::{apply_transforms, Transform};
+
+/// Numeric timestamps above this are interpreted as milliseconds (year 5138 in seconds)
+const MAX_SECONDS_TIMESTAMP: f64 = 1e11;
+/// Numeric timestamps above this are interpreted as microseconds
+const MAX_MILLIS_TIMESTAMP: f64 = 1e14;
+/// Longer string values are truncated to this many bytes
+pub const MAX_STRING_LENGTH: usize = 256;
+
+#[derive(Error, Debug)]
+pub enum DataConfigError {
+    #[error("Invalid DataConfig JSON: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("Invalid metric name {name:?}: {reason}")]
+    InvalidName { name: String, reason: String },
+    #[error("Duplicate metric name {0:?}")]
+    DuplicateName(String),
+    #[error("Invalid JSON pointer {pointer:?} in metric {metric:?}: pointers must be empty or start with '/' and escape '~' as '~0'")]
+    InvalidPointer { metric: String, pointer: String },
+    #[error("Invalid metric {metric:?}: {reason}")]
+    InvalidMetric { metric: String, reason: String },
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum DataType {
@@ -9,13 +37,33 @@ pub enum DataType {
     Int,
     LocationObject,
     LocationTuple,
+    Bool,
+    String,
+    /// One of a fixed set of values, e.g. the states of a state machine
+    Categorical(Vec<String>),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MetricConfig {
+    /// JSON pointer to the value, `*` segments match all array elements or object members
     pub json_pointer: String,
+    /// Metric name, may contain placeholders filled from wildcard matches like `temp_{id}`
     pub name: String,
     pub data_type: DataType,
+    /// Seconds the metric is kept, overrides the retention of the tenant
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<u64>,
+    /// JSON pointer to the device timestamp (seconds, milliseconds or RFC 3339),
+    /// values are stamped with the receive time if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp_pointer: Option<String>,
+    /// JSON pointer to an array of samples, `json_pointer` and `timestamp_pointer`
+    /// are then resolved relative to each sample
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub samples_pointer: Option<String>,
+    /// Transforms applied in order to numeric values before they are stored
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transforms: Vec<Transform>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -28,6 +76,78 @@ pub struct DataConfigEntry {
     pub tenant_id: TenantId,
     pub device_prefix: Option<String>,
     pub metrics: Vec<MetricConfig>,
+    /// Set instead of `metrics` if the stored config could not be parsed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl MetricConfig {
+    /// True if the metric `name` is extracted by this config
+    pub fn matches_name(&self, name: &str) -> bool {
+        name_matches(&self.name, name)
+    }
+
+    fn validate(&self) -> Result<(), DataConfigError> {
+        let invalid_name = |reason: &str| DataConfigError::InvalidName {
+            name: self.name.clone(),
+            reason: reason.to_string(),
+        };
+        let invalid_metric = |reason: String| DataConfigError::InvalidMetric {
+            metric: self.name.clone(),
+            reason,
+        };
+
+        if self.name.is_empty() {
+            return Err(invalid_name("name must not be empty"));
+        }
+        // names are part of database keys (`#` separated) and api paths
+        if self.name.contains(['#', '/']) {
+            return Err(invalid_name("name must not contain '#' or '/'"));
+        }
+        let placeholders =
+            placeholders(&self.name).ok_or_else(|| invalid_name("unbalanced braces"))?;
+        if placeholders.iter().any(|p| p.is_empty()) {
+            return Err(invalid_name("empty placeholder"));
+        }
+        if !placeholders.is_empty() && !has_wildcard(&self.json_pointer) {
+            return Err(invalid_name(
+                "placeholders require a wildcard ('*') in the json_pointer",
+            ));
+        }
+
+        let pointers = [
+            Some(&self.json_pointer),
+            self.timestamp_pointer.as_ref(),
+            self.samples_pointer.as_ref(),
+        ];
+        for pointer in pointers.into_iter().flatten() {
+            if !is_valid_pointer(pointer) {
+                return Err(DataConfigError::InvalidPointer {
+                    metric: self.name.clone(),
+                    pointer: pointer.clone(),
+                });
+            }
+        }
+
+        if let DataType::Categorical(categories) = &self.data_type {
+            if categories.is_empty() {
+                return Err(invalid_metric(
+                    "categorical metrics need at least one category".to_string(),
+                ));
+            }
+        }
+        if !self.transforms.is_empty() && !matches!(self.data_type, DataType::Float | DataType::Int)
+        {
+            return Err(invalid_metric(format!(
+                "transforms are only supported for Float and Int metrics, not {:?}",
+                self.data_type
+            )));
+        }
+        for transform in &self.transforms {
+            transform.validate().map_err(invalid_metric)?;
+        }
+        Ok(())
+    }
 }
 
 impl DataConfig {
@@ -48,46 +168,188 @@ impl DataConfig {
         serde_json::to_string(self).unwrap()
     }
 
-    pub fn from_json(json: &str) -> DataConfig {
-        serde_json::from_str(json).unwrap()
+    pub fn from_json(json: &str) -> Result<DataConfig, DataConfigError> {
+        Ok(serde_json::from_str(json)?)
     }
 
-    pub fn extract_metrics_from_json(&self, json_value: Value) -> Vec<(String, MetricValue)> {
+    /// Check a config before it is stored, unknown data types and malformed
+    /// documents are already rejected by `from_json`
+    pub fn validate(&self) -> Result<(), DataConfigError> {
+        let mut names = HashSet::new();
+        for metric in &self.metrics {
+            metric.validate()?;
+            if !names.insert(metric.name.as_str()) {
+                return Err(DataConfigError::DuplicateName(metric.name.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Extract the configured metrics of a payload, values without a device
+    /// timestamp are stamped with `received_at`
+    pub fn extract_metrics_from_json(
+        &self,
+        json_value: Value,
+        received_at: u64,
+    ) -> Vec<(String, MetricTimeSeries)> {
         let mut metrics = Vec::new();
         for metric in &self.metrics {
-            if let Some(value) = json_value.pointer(&metric.json_pointer) {
-                // handle data types
-                let value: Option<MetricValue> = match metric.data_type {
-                    DataType::Float => value.as_f64().map(MetricValue::Float),
-                    DataType::Int => {
-                        // handle both i64 and f64 as int
-                        let int = value.as_i64().or(value.as_f64().map(|f| f as i64));
-                        int.map(MetricValue::Int)
-                    }
-                    DataType::LocationObject => {
-                        let lat = value["lat"].as_f64();
-                        let long = value["long"].as_f64();
-                        if let (Some(lat), Some(long)) = (lat, long) {
-                            Some(MetricValue::Location(LatLong::new(lat, long)))
-                        } else {
-                            None
-                        }
-                    }
-                    DataType::LocationTuple => {
-                        let lat = value[0].as_f64();
-                        let long = value[1].as_f64();
-                        if let (Some(lat), Some(long)) = (lat, long) {
-                            Some(MetricValue::Location(LatLong::new(lat, long)))
-                        } else {
-                            None
+            let samples: Vec<&Value> = match &metric.samples_pointer {
+                Some(pointer) => match json_value.pointer(pointer) {
+                    Some(Value::Array(samples)) => samples.iter().collect(),
+                    _ => continue,
+                },
+                None => vec![&json_value],
+            };
+
+            // wildcard pointers can yield several series per metric config
+            let mut series: Vec<(String, MetricTimeSeries)> = Vec::new();
+            for sample in samples {
+                let timestamp = match &metric.timestamp_pointer {
+                    // samples without a valid timestamp are dropped instead of
+                    // being stored at the wrong time
+                    Some(pointer) => match sample.pointer(pointer).and_then(parse_timestamp) {
+                        Some(timestamp) => timestamp,
+                        None => continue,
+                    },
+                    None => received_at,
+                };
+                for (bindings, value) in expand_pointer(sample, &metric.json_pointer) {
+                    // matches without the fields referenced by the name are skipped
+                    let name = match render_name(&metric.name, &bindings) {
+                        Some(name) => name,
+                        None => continue,
+                    };
+                    let value = extract_value(&metric.data_type, value)
+                        .and_then(|value| transform_value(&metric.transforms, value, sample));
+                    let value = match value {
+                        Some(value) => value,
+                        None => continue,
+                    };
+                    match series.iter_mut().find(|(n, _)| *n == name) {
+                        Some((_, s)) => s.add_point(timestamp, value),
+                        None => {
+                            let mut s = MetricTimeSeries::new();
+                            s.add_point(timestamp, value);
+                            series.push((name, s));
                         }
                     }
-                };
-                if let Some(value) = value {
-                    metrics.push((metric.name.clone(), value));
                 }
             }
+            metrics.extend(series);
         }
         metrics
     }
 }
+
+fn extract_value(data_type: &DataType, value: &Value) -> Option<MetricValue> {
+    match data_type {
+        DataType::Float => value.as_f64().map(MetricValue::Float),
+        DataType::Int => {
+            // handle both i64 and f64 as int
+            let int = value.as_i64().or(value.as_f64().map(|f| f as i64));
+            int.map(MetricValue::Int)
+        }
+        DataType::LocationObject => {
+            let lat = value["lat"].as_f64();
+            let long = value["long"].as_f64();
+            if let (Some(lat), Some(long)) = (lat, long) {
+                Some(MetricValue::Location(LatLong::new(lat, long)))
+            } else {
+                None
+            }
+        }
+        DataType::LocationTuple => {
+            let lat = value[0].as_f64();
+            let long = value[1].as_f64();
+            if let (Some(lat), Some(long)) = (lat, long) {
+                Some(MetricValue::Location(LatLong::new(lat, long)))
+            } else {
+                None
+            }
+        }
+        DataType::Bool => {
+            // handle 0 and 1 as bool
+            let b = value.as_bool().or(match value.as_i64() {
+                Some(0) => Some(false),
+                Some(1) => Some(true),
+                _ => None,
+            });
+            b.map(MetricValue::Bool)
+        }
+        DataType::String => value
+            .as_str()
+            .map(|s| MetricValue::String(truncate(s, MAX_STRING_LENGTH).to_string())),
+        DataType::Categorical(categories) => {
+            // numeric states are matched by their string representation
+            let category = match value {
+                Value::String(s) => s.clone(),
+                Value::Number(n) => n.to_string(),
+                Value::Bool(b) => b.to_string(),
+                _ => return None,
+            };
+            categories
+                .contains(&category)
+                .then_some(MetricValue::String(category))
+        }
+    }
+}
+
+// Numeric values keep their type, transforms of other values drop them
+fn transform_value(
+    transforms: &[Transform],
+    value: MetricValue,
+    sample: &Value,
+) -> Option<MetricValue> {
+    if transforms.is_empty() {
+        return Some(value);
+    }
+    match value {
+        MetricValue::Float(f) => apply_transforms(transforms, f, sample).map(MetricValue::Float),
+        MetricValue::Int(i) => apply_transforms(transforms, i as f64, sample)
+            .map(|f| MetricValue::Int(f.round() as i64)),
+        _ => None,
+    }
+}
+
+// Cut a string to at most `max_len` bytes without splitting a character
+fn truncate(s: &str, max_len: usize) -> &str {
+    if s.len() <= max_len {
+        return s;
+    }
+    let mut end = max_len;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Parse a device timestamp into unix microseconds. Numbers are seconds or, if
+/// too large for a plausible date in seconds, milliseconds or microseconds.
+/// Strings are RFC 3339.
+pub fn parse_timestamp(value: &Value) -> Option<u64> {
+    match value {
+        Value::Number(number) => {
+            let number = number.as_f64()?;
+            if number < 0.0 {
+                return None;
+            }
+            let scale = if number > MAX_MILLIS_TIMESTAMP {
+                1.0
+            } else if number > MAX_SECONDS_TIMESTAMP {
+                1_000.0
+            } else {
+                MICROS_PER_SECOND as f64
+            };
+            Some((number * scale).round() as u64)
+        }
+        Value::String(s) => {
+            let datetime = chrono::DateTime::parse_from_rfc3339(s).ok()?;
+            u64::try_from(datetime.timestamp_micros()).ok()
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests;
\ No newline at end of file
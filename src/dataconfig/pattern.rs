@@ -0,0 +1,190 @@
+//! Wildcard pointers and metric name templates.
+//!
+//! A `*` segment in a JSON pointer matches every element of an array or every
+//! member of an object. Metric names can refer to the matches with placeholders:
+//! `{key}` is the array index or object key matched by the innermost wildcard,
+//! any other `{field}` is the value of `field` in the matched element, searched
+//! from the innermost to the outermost wildcard.
+//!
+//! `{"sensors": [{"id": "t1", "v": 21.3}]}` with the pointer `/sensors/*/v`
+//! and the name `temp_{id}` yields the metric `temp_t1`.
+
+use serde_json::Value;
+
+pub const WILDCARD: &str = "*";
+
+/// Key and element matched by a wildcard
+#[derive(Debug, Clone)]
+pub struct Binding<'a> {
+    pub key: String,
+    pub element: &'a Value,
+}
+
+pub fn has_wildcard(pointer: &str) -> bool {
+    pointer.split('/').any(|segment| segment == WILDCARD)
+}
+
+/// All values matched by the pointer together with the wildcard bindings of each match
+pub fn expand_pointer<'a>(value: &'a Value, pointer: &str) -> Vec<(Vec<Binding<'a>>, &'a Value)> {
+    if !has_wildcard(pointer) {
+        return value
+            .pointer(pointer)
+            .map(|v| vec![(Vec::new(), v)])
+            .unwrap_or_default();
+    }
+    // a pointer is either empty or starts with a separator
+    let segments: Vec<&str> = match pointer.strip_prefix('/') {
+        Some(rest) => rest.split('/').collect(),
+        None => return Vec::new(),
+    };
+    let mut matches = Vec::new();
+    expand_segments(value, &segments, &mut Vec::new(), &mut matches);
+    matches
+}
+
+fn expand_segments<'a>(
+    value: &'a Value,
+    segments: &[&str],
+    bindings: &mut Vec<Binding<'a>>,
+    matches: &mut Vec<(Vec<Binding<'a>>, &'a Value)>,
+) {
+    let (segment, rest) = match segments.split_first() {
+        Some(split) => split,
+        None => {
+            matches.push((bindings.clone(), value));
+            return;
+        }
+    };
+
+    if *segment == WILDCARD {
+        let children: Vec<(String, &Value)> = match value {
+            Value::Array(items) => items
+                .iter()
+                .enumerate()
+                .map(|(idx, item)| (idx.to_string(), item))
+                .collect(),
+            Value::Object(members) => members.iter().map(|(k, v)| (k.clone(), v)).collect(),
+            _ => return,
+        };
+        for (key, child) in children {
+            bindings.push(Binding {
+                key,
+                element: child,
+            });
+            expand_segments(child, rest, bindings, matches);
+            bindings.pop();
+        }
+        return;
+    }
+
+    let segment = segment.replace("~1", "/").replace("~0", "~");
+    let child = match value {
+        Value::Array(items) => segment.parse::<usize>().ok().and_then(|idx| items.get(idx)),
+        Value::Object(members) => members.get(&segment),
+        _ => None,
+    };
+    if let Some(child) = child {
+        expand_segments(child, rest, bindings, matches);
+    }
+}
+
+/// Fill the placeholders of a name template, `None` if a placeholder cannot be resolved
+pub fn render_name(template: &str, bindings: &[Binding]) -> Option<String> {
+    let mut name = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = start + rest[start..].find('}')?;
+        name.push_str(&rest[..start]);
+        name.push_str(&resolve_placeholder(&rest[start + 1..end], bindings)?);
+        rest = &rest[end + 1..];
+    }
+    name.push_str(rest);
+    Some(name)
+}
+
+fn resolve_placeholder(placeholder: &str, bindings: &[Binding]) -> Option<String> {
+    if placeholder == "key" {
+        return bindings.last().map(|b| sanitize(&b.key));
+    }
+    bindings
+        .iter()
+        .rev()
+        .find_map(|binding| match binding.element.get(placeholder)? {
+            Value::String(s) => Some(sanitize(s)),
+            Value::Number(n) => Some(n.to_string()),
+            Value::Bool(b) => Some(b.to_string()),
+            _ => None,
+        })
+}
+
+// Device supplied parts of names must not contain key or topic separators
+fn sanitize(part: &str) -> String {
+    part.replace(['#', '/'], "_")
+}
+
+/// True if `name` may have been generated from the template
+pub fn name_matches(template: &str, name: &str) -> bool {
+    // literal parts between the placeholders
+    let mut literals = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        literals.push(&rest[..start]);
+        rest = &rest[end + 1..];
+    }
+    if literals.is_empty() {
+        return template == name;
+    }
+    literals.push(rest);
+
+    let first = literals[0];
+    let last = literals[literals.len() - 1];
+    if !name.starts_with(first) || name.len() < first.len() + last.len() || !name.ends_with(last) {
+        return false;
+    }
+    let mut remaining = &name[first.len()..name.len() - last.len()];
+    for literal in &literals[1..literals.len() - 1] {
+        match remaining.find(literal) {
+            Some(idx) => remaining = &remaining[idx + literal.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+/// True if the pointer is valid RFC 6901 syntax: empty or starting with `/`,
+/// with `~` only used in the escapes `~0` and `~1`
+pub fn is_valid_pointer(pointer: &str) -> bool {
+    if !pointer.is_empty() && !pointer.starts_with('/') {
+        return false;
+    }
+    let mut chars = pointer.chars();
+    while let Some(c) = chars.next() {
+        if c == '~' && !matches!(chars.next(), Some('0') | Some('1')) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Placeholders of a name template, `None` if its braces are unbalanced
+pub fn placeholders(template: &str) -> Option<Vec<&str>> {
+    let mut placeholders = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        if rest[start..].starts_with('}') {
+            return None;
+        }
+        let end = start + rest[start..].find('}')?;
+        let placeholder = &rest[start + 1..end];
+        if placeholder.contains('{') {
+            return None;
+        }
+        placeholders.push(placeholder);
+        rest = &rest[end + 1..];
+    }
+    Some(placeholders)
+}
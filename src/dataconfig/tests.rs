@@ -0,0 +1,474 @@
+use super::*;
+use crate::timeseries::MICROS_PER_SECOND;
+use serde_json::json;
+
+const TS: u64 = 1700000000 * MICROS_PER_SECOND;
+
+fn metric(name: &str, json_pointer: &str, data_type: DataType) -> MetricConfig {
+    MetricConfig {
+        json_pointer: json_pointer.to_string(),
+        name: name.to_string(),
+        data_type,
+        retention: None,
+        timestamp_pointer: None,
+        samples_pointer: None,
+        transforms: Vec::new(),
+    }
+}
+
+#[test]
+fn test_parse_timestamp() {
+    assert_eq!(parse_timestamp(&json!(1700000000)), Some(TS));
+    assert_eq!(parse_timestamp(&json!(1700000000.5)), Some(TS + 500_000));
+    assert_eq!(
+        parse_timestamp(&json!(1700000000123u64)),
+        Some(TS + 123_000)
+    );
+    assert_eq!(
+        parse_timestamp(&json!(1700000000123456u64)),
+        Some(TS + 123_456)
+    );
+    assert_eq!(parse_timestamp(&json!("2023-11-14T22:13:20Z")), Some(TS));
+    assert_eq!(
+        parse_timestamp(&json!("2023-11-14T23:13:20.25+01:00")),
+        Some(TS + 250_000)
+    );
+    assert_eq!(parse_timestamp(&json!(-1)), None);
+    assert_eq!(parse_timestamp(&json!("yesterday")), None);
+    assert_eq!(parse_timestamp(&json!(null)), None);
+}
+
+#[test]
+fn test_extract_metrics_receive_time() {
+    let config = DataConfig {
+        metrics: vec![
+            metric("temperature", "/temp", DataType::Float),
+            metric("location", "/pos", DataType::LocationTuple),
+        ],
+    };
+    let metrics = config.extract_metrics_from_json(json!({"temp": 21.5}), 1000);
+    assert_eq!(metrics.len(), 1);
+    assert_eq!(metrics[0].0, "temperature");
+    assert_eq!(
+        metrics[0].1.latest(),
+        Some((1000, &MetricValue::Float(21.5)))
+    );
+}
+
+#[test]
+fn test_extract_metrics_device_timestamp() {
+    let mut temperature = metric("temperature", "/temp", DataType::Float);
+    temperature.timestamp_pointer = Some("/ts".to_string());
+    let config = DataConfig {
+        metrics: vec![temperature],
+    };
+
+    let metrics =
+        config.extract_metrics_from_json(json!({"temp": 21.5, "ts": 1700000000123u64}), 1000);
+    assert_eq!(
+        metrics[0].1.latest(),
+        Some((TS + 123_000, &MetricValue::Float(21.5)))
+    );
+
+    // values without a valid device timestamp are dropped
+    let metrics = config.extract_metrics_from_json(json!({"temp": 21.5, "ts": "now"}), 1000);
+    assert!(metrics.is_empty());
+}
+
+#[test]
+fn test_extract_metrics_samples() {
+    let mut temperature = metric("temperature", "/temp", DataType::Float);
+    temperature.samples_pointer = Some("/samples".to_string());
+    temperature.timestamp_pointer = Some("/ts".to_string());
+    let config = DataConfig {
+        metrics: vec![temperature],
+    };
+
+    let payload = json!({
+        "samples": [
+            {"ts": 1700000000, "temp": 20.0},
+            {"ts": "2023-11-14T23:13:20Z", "temp": 21.0},
+            {"ts": 1700007200, "temp": "invalid"},
+            {"temp": 22.0}
+        ]
+    });
+    let metrics = config.extract_metrics_from_json(payload, 1000);
+    assert_eq!(metrics.len(), 1);
+    let points: Vec<(u64, &MetricValue)> = metrics[0].1.iter().collect();
+    assert_eq!(
+        points,
+        vec![
+            (TS, &MetricValue::Float(20.0)),
+            (TS + 3600 * MICROS_PER_SECOND, &MetricValue::Float(21.0))
+        ]
+    );
+
+    // payloads without samples yield nothing
+    assert!(config
+        .extract_metrics_from_json(json!({"temp": 20.0}), 1000)
+        .is_empty());
+}
+
+#[test]
+fn test_extract_bool_string_categorical() {
+    let config = DataConfig {
+        metrics: vec![
+            metric("door_open", "/door", DataType::Bool),
+            metric("relay", "/relay", DataType::Bool),
+            metric("firmware", "/fw", DataType::String),
+            metric(
+                "state",
+                "/state",
+                DataType::Categorical(vec!["idle".to_string(), "running".to_string()]),
+            ),
+            metric(
+                "mode",
+                "/mode",
+                DataType::Categorical(vec!["1".to_string(), "2".to_string()]),
+            ),
+        ],
+    };
+    let payload = json!({
+        "door": true,
+        "relay": 0,
+        "fw": "1.4.2",
+        "state": "running",
+        "mode": 2
+    });
+    let metrics = config.extract_metrics_from_json(payload, 1000);
+    let values: Vec<(&str, &MetricValue)> = metrics
+        .iter()
+        .map(|(name, ts)| (name.as_str(), ts.latest().unwrap().1))
+        .collect();
+    assert_eq!(
+        values,
+        vec![
+            ("door_open", &MetricValue::Bool(true)),
+            ("relay", &MetricValue::Bool(false)),
+            ("firmware", &MetricValue::String("1.4.2".to_string())),
+            ("state", &MetricValue::String("running".to_string())),
+            ("mode", &MetricValue::String("2".to_string())),
+        ]
+    );
+
+    // unknown categories and invalid booleans are dropped
+    let payload = json!({"door": 2, "state": "exploded"});
+    assert!(config.extract_metrics_from_json(payload, 1000).is_empty());
+
+    // long strings are capped without splitting characters
+    let payload = json!({"fw": "ü".repeat(MAX_STRING_LENGTH)});
+    let metrics = config.extract_metrics_from_json(payload, 1000);
+    match metrics[0].1.latest().unwrap().1 {
+        MetricValue::String(s) => assert_eq!(s.len(), MAX_STRING_LENGTH),
+        other => panic!("unexpected value {:?}", other),
+    }
+
+    // categorical configs serialize with their categories
+    let json = serde_json::to_value(&config.metrics[3].data_type).unwrap();
+    assert_eq!(json, json!({"Categorical": ["idle", "running"]}));
+}
+
+#[test]
+fn test_transforms() {
+    use transform::{Operator, Transform, Unit};
+
+    let sample = json!({});
+    let apply = |transform: Transform, value: f64| transform.apply(value, &sample);
+
+    assert_eq!(
+        apply(
+            Transform::Scale {
+                factor: 0.5,
+                offset: -1.0
+            },
+            10.0
+        ),
+        Some(4.0)
+    );
+    let round = |value: Option<f64>| value.map(|v| (v * 1e6).round() / 1e6);
+    let convert = |from, to, value| round(apply(Transform::Convert { from, to }, value));
+    assert_eq!(convert(Unit::Fahrenheit, Unit::Celsius, 212.0), Some(100.0));
+    assert_eq!(convert(Unit::Celsius, Unit::Kelvin, 0.0), Some(273.15));
+    assert_eq!(convert(Unit::Mile, Unit::Kilometer, 1.0), Some(1.609344));
+    assert_eq!(
+        convert(Unit::KilometerPerHour, Unit::MeterPerSecond, 36.0),
+        Some(10.0)
+    );
+    // units of different quantities do not convert
+    assert_eq!(convert(Unit::Bar, Unit::Meter, 1.0), None);
+
+    let clamp = Transform::Clamp {
+        min: Some(0.0),
+        max: None,
+    };
+    assert_eq!(apply(clamp.clone(), -3.0), Some(0.0));
+    assert_eq!(apply(clamp, 3.0), Some(3.0));
+    assert_eq!(apply(Transform::Round { decimals: 1 }, 21.46), Some(21.5));
+    assert_eq!(apply(Transform::Round { decimals: 0 }, 21.46), Some(21.0));
+
+    let sample = json!({"current": 2.0, "zero": 0});
+    let multiply = Transform::Arithmetic {
+        op: Operator::Mul,
+        pointer: "/current".to_string(),
+    };
+    assert_eq!(multiply.apply(230.0, &sample), Some(460.0));
+    let divide = Transform::Arithmetic {
+        op: Operator::Div,
+        pointer: "/zero".to_string(),
+    };
+    assert_eq!(divide.apply(230.0, &sample), None);
+    let missing = Transform::Arithmetic {
+        op: Operator::Add,
+        pointer: "/missing".to_string(),
+    };
+    assert_eq!(missing.apply(230.0, &sample), None);
+}
+
+#[test]
+fn test_extract_metrics_with_transforms() {
+    let config: DataConfig = serde_json::from_value(json!({
+        "metrics": [
+            {
+                "json_pointer": "/adc",
+                "name": "temperature",
+                "data_type": "Float",
+                "transforms": [
+                    {"Scale": {"factor": 0.1}},
+                    {"Convert": {"from": "fahrenheit", "to": "celsius"}},
+                    {"Round": {"decimals": 1}}
+                ]
+            },
+            {
+                "json_pointer": "/voltage",
+                "name": "power",
+                "data_type": "Int",
+                "transforms": [{"Arithmetic": {"op": "mul", "pointer": "/current"}}]
+            },
+            {
+                "json_pointer": "/level",
+                "name": "level",
+                "data_type": "Float",
+                "transforms": [{"Clamp": {"min": 0.0, "max": 100.0}}]
+            }
+        ]
+    }))
+    .unwrap();
+
+    let payload = json!({"adc": 986, "voltage": 230, "current": 1.5, "level": 104.2});
+    let metrics = config.extract_metrics_from_json(payload, 1000);
+    let values: Vec<(&str, &MetricValue)> = metrics
+        .iter()
+        .map(|(name, ts)| (name.as_str(), ts.latest().unwrap().1))
+        .collect();
+    assert_eq!(
+        values,
+        vec![
+            ("temperature", &MetricValue::Float(37.0)),
+            ("power", &MetricValue::Int(345)),
+            ("level", &MetricValue::Float(100.0)),
+        ]
+    );
+
+    // derived metrics are dropped if an operand is missing
+    let metrics = config.extract_metrics_from_json(json!({"voltage": 230}), 1000);
+    assert!(metrics.is_empty());
+}
+
+#[test]
+fn test_extract_wildcard_arrays() {
+    let config = DataConfig {
+        metrics: vec![metric("temp_{id}", "/sensors/*/v", DataType::Float)],
+    };
+    let payload = json!({
+        "sensors": [
+            {"id": "t1", "v": 21.3},
+            {"id": 2, "v": 19.0},
+            {"id": "t#/3", "v": 18.5},
+            {"v": 17.0},
+            {"id": "t4", "v": "invalid"}
+        ]
+    });
+    let metrics = config.extract_metrics_from_json(payload, 1000);
+    let values: Vec<(&str, &MetricValue)> = metrics
+        .iter()
+        .map(|(name, ts)| (name.as_str(), ts.latest().unwrap().1))
+        .collect();
+    assert_eq!(
+        values,
+        vec![
+            ("temp_t1", &MetricValue::Float(21.3)),
+            ("temp_2", &MetricValue::Float(19.0)),
+            // separators in names are replaced
+            ("temp_t__3", &MetricValue::Float(18.5)),
+        ]
+    );
+}
+
+#[test]
+fn test_extract_wildcard_object_keys() {
+    let mut humidity = metric("humidity_{key}", "/rooms/*", DataType::Float);
+    humidity.samples_pointer = Some("/samples".to_string());
+    humidity.timestamp_pointer = Some("/ts".to_string());
+    let config = DataConfig {
+        metrics: vec![
+            humidity,
+            metric("{room}_{key}", "/floors/*/*", DataType::Int),
+        ],
+    };
+    let payload = json!({
+        "samples": [
+            {"ts": 1700000000, "rooms": {"kitchen": 40.0, "bath": 65.5}},
+            {"ts": 1700000060, "rooms": {"kitchen": 41.0}}
+        ],
+        "floors": [{"room": "cellar", "co2": 800, "lux": 12}]
+    });
+    let metrics = config.extract_metrics_from_json(payload, TS);
+
+    // points of the same generated name end up in one series
+    let kitchen: Vec<(u64, &MetricValue)> = metrics
+        .iter()
+        .find(|(name, _)| name == "humidity_kitchen")
+        .unwrap()
+        .1
+        .iter()
+        .collect();
+    assert_eq!(
+        kitchen,
+        vec![
+            (TS, &MetricValue::Float(40.0)),
+            (TS + 60 * MICROS_PER_SECOND, &MetricValue::Float(41.0))
+        ]
+    );
+    let mut names: Vec<&str> = metrics.iter().map(|(name, _)| name.as_str()).collect();
+    names.sort();
+    // the `room` member itself is no number and therefore skipped
+    assert_eq!(
+        names,
+        vec![
+            "cellar_co2",
+            "cellar_lux",
+            "humidity_bath",
+            "humidity_kitchen"
+        ]
+    );
+}
+
+#[test]
+fn test_name_templates() {
+    use pattern::name_matches;
+
+    assert!(name_matches("temperature", "temperature"));
+    assert!(!name_matches("temperature", "temperature_1"));
+    assert!(name_matches("temp_{id}", "temp_t1"));
+    assert!(!name_matches("temp_{id}", "humidity_t1"));
+    assert!(name_matches("{room}_{key}", "cellar_co2"));
+    assert!(name_matches("{floor}/{room}_temp", "1/kitchen_temp"));
+    assert!(!name_matches("{floor}/{room}_temp", "1/kitchen_hum"));
+
+    let config = metric("temp_{id}", "/sensors/*/v", DataType::Float);
+    assert!(config.matches_name("temp_outdoor"));
+}
+
+#[test]
+fn test_from_json() {
+    let config = DataConfig::from_json(
+        r#"{"metrics": [{"json_pointer": "/temp", "name": "temperature", "data_type": "Float"}]}"#,
+    )
+    .unwrap();
+    assert_eq!(config.metrics[0].data_type, DataType::Float);
+
+    assert!(matches!(
+        DataConfig::from_json("{\"metrics\": ["),
+        Err(DataConfigError::JsonError(_))
+    ));
+    // unknown data types are reported with the known ones
+    let err = DataConfig::from_json(
+        r#"{"metrics": [{"json_pointer": "/temp", "name": "temperature", "data_type": "Double"}]}"#,
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("unknown variant `Double`"));
+}
+
+#[test]
+fn test_validate() {
+    let validate = |metrics: Vec<MetricConfig>| DataConfig { metrics }.validate();
+
+    let mut samples = metric("temp_{id}", "/sensors/*/v", DataType::Float);
+    samples.samples_pointer = Some("/samples".to_string());
+    samples.timestamp_pointer = Some("/ts".to_string());
+    samples.transforms = vec![Transform::Round { decimals: 1 }];
+    assert!(validate(vec![
+        samples,
+        metric("temperature", "/temp", DataType::Float),
+        metric("raw", "", DataType::String),
+        metric("escaped", "/a~1b/c~0d", DataType::Int),
+    ])
+    .is_ok());
+
+    assert!(matches!(
+        validate(vec![
+            metric("temperature", "/temp", DataType::Float),
+            metric("temperature", "/t", DataType::Int),
+        ]),
+        Err(DataConfigError::DuplicateName(name)) if name == "temperature"
+    ));
+    for pointer in ["temp", "/a~2", "/a~"] {
+        assert!(matches!(
+            validate(vec![metric("temperature", pointer, DataType::Float)]),
+            Err(DataConfigError::InvalidPointer { .. })
+        ));
+    }
+    let mut timestamp = metric("temperature", "/temp", DataType::Float);
+    timestamp.timestamp_pointer = Some("ts".to_string());
+    assert!(matches!(
+        validate(vec![timestamp]),
+        Err(DataConfigError::InvalidPointer { pointer, .. }) if pointer == "ts"
+    ));
+
+    for name in ["", "a#b", "a/b", "temp_{id", "temp_}", "temp_{}"] {
+        assert!(matches!(
+            validate(vec![metric(name, "/sensors/*", DataType::Float)]),
+            Err(DataConfigError::InvalidName { .. })
+        ));
+    }
+    // placeholders can only be filled from wildcard matches
+    assert!(matches!(
+        validate(vec![metric("temp_{id}", "/temp", DataType::Float)]),
+        Err(DataConfigError::InvalidName { .. })
+    ));
+
+    assert!(matches!(
+        validate(vec![metric(
+            "state",
+            "/state",
+            DataType::Categorical(Vec::new())
+        )]),
+        Err(DataConfigError::InvalidMetric { .. })
+    ));
+    let mut transformed = metric("firmware", "/fw", DataType::String);
+    transformed.transforms = vec![Transform::Round { decimals: 1 }];
+    assert!(validate(vec![transformed]).is_err());
+    for transform in [
+        Transform::Convert {
+            from: transform::Unit::Bar,
+            to: transform::Unit::Celsius,
+        },
+        Transform::Clamp {
+            min: Some(10.0),
+            max: Some(0.0),
+        },
+        Transform::Arithmetic {
+            op: transform::Operator::Mul,
+            pointer: "current".to_string(),
+        },
+    ] {
+        let mut transformed = metric("temperature", "/temp", DataType::Float);
+        transformed.transforms = vec![transform];
+        let err = validate(vec![transformed]).unwrap_err();
+        assert!(
+            matches!(err, DataConfigError::InvalidMetric { .. }),
+            "{}",
+            err
+        );
+    }
+}
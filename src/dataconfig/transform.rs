@@ -0,0 +1,200 @@
+//! Transformations applied to numeric metric values after extraction.
+//!
+//! A `MetricConfig` carries a list of transforms that are evaluated in order,
+//! e.g. to turn raw ADC counts into a temperature in degrees Celsius:
+//!
+//! ```json
+//! "transforms": [
+//!     {"Scale": {"factor": 0.1, "offset": -40.0}},
+//!     {"Convert": {"from": "fahrenheit", "to": "celsius"}},
+//!     {"Clamp": {"min": -50.0, "max": 150.0}},
+//!     {"Round": {"decimals": 1}}
+//! ]
+//! ```
+
+use super::pattern::is_valid_pointer;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Transform {
+    /// `value * factor + offset`
+    Scale {
+        #[serde(default = "default_factor")]
+        factor: f64,
+        #[serde(default)]
+        offset: f64,
+    },
+    /// Conversion between two units of the same quantity
+    Convert { from: Unit, to: Unit },
+    /// Limit the value to the given bounds
+    Clamp {
+        #[serde(default)]
+        min: Option<f64>,
+        #[serde(default)]
+        max: Option<f64>,
+    },
+    /// Round to the given number of decimal places
+    Round {
+        #[serde(default)]
+        decimals: u32,
+    },
+    /// Combine the value with the value at another pointer of the same payload,
+    /// e.g. `power = voltage * current`
+    Arithmetic { op: Operator, pointer: String },
+}
+
+fn default_factor() -> f64 {
+    1.0
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Operator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Unit {
+    // temperature
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+    // length
+    Millimeter,
+    Centimeter,
+    Meter,
+    Kilometer,
+    Inch,
+    Foot,
+    Mile,
+    // pressure
+    Pascal,
+    Hectopascal,
+    Kilopascal,
+    Bar,
+    Psi,
+    // speed
+    #[serde(rename = "m/s")]
+    MeterPerSecond,
+    #[serde(rename = "km/h")]
+    KilometerPerHour,
+    Mph,
+    Knot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Quantity {
+    Temperature,
+    Length,
+    Pressure,
+    Speed,
+}
+
+impl Unit {
+    // Quantity, factor and offset to convert into the base unit of the quantity
+    fn to_base(self) -> (Quantity, f64, f64) {
+        match self {
+            Unit::Kelvin => (Quantity::Temperature, 1.0, 0.0),
+            Unit::Celsius => (Quantity::Temperature, 1.0, 273.15),
+            Unit::Fahrenheit => (Quantity::Temperature, 5.0 / 9.0, 459.67 * 5.0 / 9.0),
+            Unit::Meter => (Quantity::Length, 1.0, 0.0),
+            Unit::Millimeter => (Quantity::Length, 0.001, 0.0),
+            Unit::Centimeter => (Quantity::Length, 0.01, 0.0),
+            Unit::Kilometer => (Quantity::Length, 1000.0, 0.0),
+            Unit::Inch => (Quantity::Length, 0.0254, 0.0),
+            Unit::Foot => (Quantity::Length, 0.3048, 0.0),
+            Unit::Mile => (Quantity::Length, 1609.344, 0.0),
+            Unit::Pascal => (Quantity::Pressure, 1.0, 0.0),
+            Unit::Hectopascal => (Quantity::Pressure, 100.0, 0.0),
+            Unit::Kilopascal => (Quantity::Pressure, 1000.0, 0.0),
+            Unit::Bar => (Quantity::Pressure, 100_000.0, 0.0),
+            Unit::Psi => (Quantity::Pressure, 6894.757293168, 0.0),
+            Unit::MeterPerSecond => (Quantity::Speed, 1.0, 0.0),
+            Unit::KilometerPerHour => (Quantity::Speed, 1.0 / 3.6, 0.0),
+            Unit::Mph => (Quantity::Speed, 0.44704, 0.0),
+            Unit::Knot => (Quantity::Speed, 1852.0 / 3600.0, 0.0),
+        }
+    }
+
+    /// Convert a value into another unit, `None` if the units measure different quantities
+    pub fn convert(self, value: f64, to: Unit) -> Option<f64> {
+        let (from_quantity, from_factor, from_offset) = self.to_base();
+        let (to_quantity, to_factor, to_offset) = to.to_base();
+        if from_quantity != to_quantity {
+            return None;
+        }
+        let base = value * from_factor + from_offset;
+        Some((base - to_offset) / to_factor)
+    }
+
+    /// True if both units measure the same quantity
+    pub fn converts_to(self, to: Unit) -> bool {
+        self.to_base().0 == to.to_base().0
+    }
+}
+
+impl Transform {
+    /// Apply the transform, `sample` is the payload the value was extracted from.
+    /// Returns `None` if the value cannot be computed, e.g. on division by zero.
+    pub fn apply(&self, value: f64, sample: &Value) -> Option<f64> {
+        let result = match self {
+            Transform::Scale { factor, offset } => value * factor + offset,
+            Transform::Convert { from, to } => from.convert(value, *to)?,
+            Transform::Clamp { min, max } => {
+                let value = min.map_or(value, |min| value.max(min));
+                max.map_or(value, |max| value.min(max))
+            }
+            Transform::Round { decimals } => {
+                let factor = 10f64.powi(*decimals as i32);
+                (value * factor).round() / factor
+            }
+            Transform::Arithmetic { op, pointer } => {
+                let other = sample.pointer(pointer)?.as_f64()?;
+                match op {
+                    Operator::Add => value + other,
+                    Operator::Sub => value - other,
+                    Operator::Mul => value * other,
+                    Operator::Div if other == 0.0 => return None,
+                    Operator::Div => value / other,
+                }
+            }
+        };
+        result.is_finite().then_some(result)
+    }
+
+    /// Check the parameters of the transform, returns a description of the problem
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Transform::Scale { factor, offset } if !factor.is_finite() || !offset.is_finite() => {
+                Err("Scale factor and offset must be finite".to_string())
+            }
+            Transform::Convert { from, to } if !from.converts_to(*to) => {
+                Err(format!("cannot convert {:?} to {:?}", from, to))
+            }
+            Transform::Clamp {
+                min: Some(min),
+                max: Some(max),
+            } if min > max => Err(format!("Clamp min {} is greater than max {}", min, max)),
+            Transform::Round { decimals } if *decimals > 15 => Err(format!(
+                "cannot round to {} decimals, at most 15 are supported",
+                decimals
+            )),
+            Transform::Arithmetic { pointer, .. } if !is_valid_pointer(pointer) => {
+                Err(format!("invalid JSON pointer {:?}", pointer))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Apply all transforms in order
+pub fn apply_transforms(transforms: &[Transform], value: f64, sample: &Value) -> Option<f64> {
+    transforms
+        .iter()
+        .try_fold(value, |value, transform| transform.apply(value, sample))
+}
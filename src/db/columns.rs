@@ -0,0 +1,97 @@
+//! Column families of the database, one per kind of data.
+//!
+//! Every kind of data gets its own key space, compression, block cache and
+//! compaction settings. Keys keep their format, so shadows and metrics of the
+//! same device can no longer collide. The default column family only holds
+//! internal `meta#` entries.
+
+use super::merge;
+use rocksdb::{
+    BlockBasedOptions, Cache, ColumnFamilyDescriptor, DBCompressionType, Options,
+    DEFAULT_COLUMN_FAMILY_NAME,
+};
+
+/// Current shadow documents, `tenant#device#shadow`
+pub const CF_SHADOWS: &str = "shadows";
+/// Previous shadow versions, `history#tenant#device#shadow#version`
+pub const CF_SHADOW_HISTORY: &str = "shadow_history";
+/// Hourly raw time series buckets, `tenant#device#metric#bucket`
+pub const CF_METRICS: &str = "metrics";
+/// Rollup buckets and the rollup state of every series
+pub const CF_ROLLUPS: &str = "rollups";
+/// Data configs, `dc#tenant[#prefix]`
+pub const CF_CONFIGS: &str = "configs";
+/// Device metadata, `device#tenant#device`
+pub const CF_DEVICES: &str = "devices";
+
+pub const COLUMN_FAMILIES: [&str; 6] = [
+    CF_SHADOWS,
+    CF_SHADOW_HISTORY,
+    CF_METRICS,
+    CF_ROLLUPS,
+    CF_CONFIGS,
+    CF_DEVICES,
+];
+
+const MB: usize = 1024 * 1024;
+
+// Share of the block cache per column family in percent, time series are read
+// in ranges and get most of it
+fn block_cache_share(name: &str) -> usize {
+    match name {
+        CF_METRICS => 50,
+        CF_ROLLUPS => 15,
+        CF_SHADOWS => 20,
+        _ => 5,
+    }
+}
+
+fn column_family_options(name: &str, block_cache_size: usize) -> Options {
+    let mut opts = Options::default();
+    let mut table = BlockBasedOptions::default();
+    let cache_size = (block_cache_size * block_cache_share(name) / 100).max(MB);
+    table.set_block_cache(&Cache::new_lru_cache(cache_size));
+    opts.set_level_compaction_dynamic_level_bytes(true);
+
+    match name {
+        // append heavy, read in key ranges, values are already compressed
+        CF_METRICS | CF_ROLLUPS => {
+            opts.set_merge_operator_associative(merge::MERGE_OPERATOR_NAME, merge::merge_values);
+            opts.set_compression_type(DBCompressionType::Lz4);
+            opts.set_bottommost_compression_type(DBCompressionType::Zstd);
+            opts.set_write_buffer_size(if name == CF_METRICS { 64 * MB } else { 16 * MB });
+            table.set_block_size(32 * 1024);
+        }
+        // written once and rarely read
+        CF_SHADOW_HISTORY => {
+            opts.set_compression_type(DBCompressionType::Zstd);
+            opts.set_write_buffer_size(16 * MB);
+            table.set_block_size(16 * 1024);
+        }
+        // small documents read by key
+        _ => {
+            opts.set_compression_type(DBCompressionType::Lz4);
+            opts.set_write_buffer_size(8 * MB);
+            table.set_bloom_filter(10.0, false);
+        }
+    }
+    opts.set_block_based_table_factory(&table);
+    opts
+}
+
+/// Descriptors of all column families, `existing` column families that are
+/// unknown to this version are opened with default options
+pub fn descriptors(existing: &[String], block_cache_size: usize) -> Vec<ColumnFamilyDescriptor> {
+    let mut descriptors: Vec<ColumnFamilyDescriptor> = COLUMN_FAMILIES
+        .iter()
+        .map(|name| {
+            ColumnFamilyDescriptor::new(*name, column_family_options(name, block_cache_size))
+        })
+        .collect();
+    for name in existing {
+        if name != DEFAULT_COLUMN_FAMILY_NAME && !COLUMN_FAMILIES.contains(&name.as_str()) {
+            descriptors.push(ColumnFamilyDescriptor::new(name, Options::default()));
+        }
+    }
+    descriptors
+}
@@ -0,0 +1,47 @@
+//! RocksDB merge operator for blind appends.
+//!
+//! Time series buckets are written as merge operands holding only the new
+//! points, RocksDB combines them with the stored bucket on read and during
+//! compaction. Writers therefore never read the bucket, the operands are
+//! written through a plain write batch so concurrent appends don't conflict.
+//! Rollup states are merged the same way so marking a series as pending is
+//! part of the same batch.
+
+use super::rollup::{is_rollup_state_key, merge_rollup_states};
+use crate::timeseries::{MetricTimeSeries, TimeSeriesConversions};
+use rocksdb::MergeOperands;
+use tracing::warn;
+
+pub(super) const MERGE_OPERATOR_NAME: &str = "forest_merge";
+
+/// Associative merge of the stored value and all operands. RocksDB also calls
+/// it without a stored value to combine operands, so the result is a valid operand.
+pub(super) fn merge_values(
+    key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &MergeOperands,
+) -> Option<Vec<u8>> {
+    let values = existing.into_iter().chain(operands);
+    if is_rollup_state_key(key) {
+        return Some(merge_rollup_states(values));
+    }
+    merge_timeseries(key, values)
+}
+
+// Later points replace earlier ones with the same timestamp, like
+// `MetricTimeSeries::merge`. Undecodable values are dropped instead of
+// failing the merge, which would make the key unreadable.
+fn merge_timeseries<'a>(key: &[u8], values: impl Iterator<Item = &'a [u8]>) -> Option<Vec<u8>> {
+    let mut merged = MetricTimeSeries::new();
+    for value in values {
+        match MetricTimeSeries::from_binary(value) {
+            Ok(series) => merged.merge(&series),
+            Err(e) => warn!(
+                key = %String::from_utf8_lossy(key),
+                error = ?e,
+                "Dropping undecodable time series value in merge"
+            ),
+        }
+    }
+    merged.to_binary().ok()
+}
@@ -0,0 +1,143 @@
+//! One-off migrations of the stored data, applied when the database is opened.
+//!
+//! Every migration records its completion under a `meta#` key so it only runs once.
+
+use super::columns::{
+    CF_CONFIGS, CF_DEVICES, CF_METRICS, CF_ROLLUPS, CF_SHADOWS, CF_SHADOW_HISTORY,
+};
+use super::{DatabaseError, DB};
+use crate::timeseries::{MetricTimeSeries, TimeSeriesConversions};
+use tracing::info;
+
+const COLUMN_FAMILIES_KEY: &str = "meta#column_families";
+const TIMESTAMP_PRECISION_KEY: &str = "meta#timestamp_precision";
+const DEVICE_KEYS_KEY: &str = "meta#device_keys";
+
+/// Number of entries moved per transaction
+const MIGRATION_CHUNK_SIZE: usize = 1000;
+
+// Column family of an entry written by a version without column families,
+// `None` for entries that stay in the default column family
+fn legacy_column_family(key: &[u8]) -> Option<&'static str> {
+    if key.starts_with(b"meta#") {
+        return None;
+    }
+    let prefixes = [
+        ("dc#", CF_CONFIGS),
+        ("device#", CF_DEVICES),
+        ("history#", CF_SHADOW_HISTORY),
+        ("rollup#", CF_ROLLUPS),
+    ];
+    if let Some((_, cf)) = prefixes.iter().find(|(p, _)| key.starts_with(p.as_bytes())) {
+        return Some(cf);
+    }
+    // metric buckets are `tenant#device#metric#bucket`, shadows `tenant#device#shadow`
+    match DB::_from_ts_key(key) {
+        Ok((series_key, _)) if series_key.splitn(3, |b| *b == b'#').count() == 3 => {
+            Some(CF_METRICS)
+        }
+        _ => Some(CF_SHADOWS),
+    }
+}
+
+impl DB {
+    pub(super) fn migrate(&self) -> Result<(), DatabaseError> {
+        // earlier migrations expect the data in its column family
+        if self.get_data(COLUMN_FAMILIES_KEY)?.is_none() {
+            let migrated = self._migrate_column_families()?;
+            info!(migrated, "Moved entries into column families");
+            self.set_data(COLUMN_FAMILIES_KEY, b"1")?;
+        }
+        if self.get_data(TIMESTAMP_PRECISION_KEY)?.is_none() {
+            let migrated = self._migrate_timestamp_precision()?;
+            info!(migrated, "Migrated time series to microsecond precision");
+            self.set_data(TIMESTAMP_PRECISION_KEY, b"us")?;
+        }
+        if self.get_data(DEVICE_KEYS_KEY)?.is_none() {
+            let migrated = self._migrate_device_keys()?;
+            info!(migrated, "Removed private keys from the device metadata");
+            self.set_data(DEVICE_KEYS_KEY, b"1")?;
+        }
+        Ok(())
+    }
+
+    // Move all entries of the default column family into the column family of
+    // their kind, the keys themselves are unchanged
+    fn _migrate_column_families(&self) -> Result<usize, DatabaseError> {
+        let db = self.db.as_ref().ok_or(DatabaseError::DatabaseConnectionError)?;
+        let mut migrated = 0;
+        // moved entries are deleted, so each chunk continues behind the last one
+        let mut resume_key: Option<Box<[u8]>> = None;
+        loop {
+            let mode = match &resume_key {
+                Some(key) => rocksdb::IteratorMode::From(key, rocksdb::Direction::Forward),
+                None => rocksdb::IteratorMode::Start,
+            };
+            let mut chunk = Vec::new();
+            for item in db.iterator(mode) {
+                let (key, value) = item?;
+                if let Some(cf_name) = legacy_column_family(&key) {
+                    chunk.push((cf_name, key, value));
+                    if chunk.len() == MIGRATION_CHUNK_SIZE {
+                        break;
+                    }
+                }
+            }
+            if chunk.is_empty() {
+                return Ok(migrated);
+            }
+            let txn = db.transaction();
+            for (cf_name, key, value) in &chunk {
+                let (_, cf) = self._cf(cf_name)?;
+                txn.put_cf(cf, key, value)?;
+                txn.delete(key)?;
+            }
+            txn.commit()?;
+            migrated += chunk.len();
+            resume_key = chunk.pop().map(|(_, key, _)| key);
+        }
+    }
+
+    // Rewrite all buckets stored with second precision. Legacy buckets are also
+    // converted on read, so a failed migration only costs performance.
+    fn _migrate_timestamp_precision(&self) -> Result<usize, DatabaseError> {
+        let (db, cf) = self._cf(CF_METRICS)?;
+        let mut migrated = 0;
+        for item in db.iterator_cf(cf, rocksdb::IteratorMode::Start) {
+            let (key, value) = item?;
+            if !MetricTimeSeries::is_legacy_binary(&value) || DB::_from_ts_key(&key).is_err() {
+                continue;
+            }
+            let ts = MetricTimeSeries::from_binary(&value)?;
+            db.put_cf(cf, &key, ts.to_binary()?)?;
+            migrated += 1;
+        }
+        Ok(migrated)
+    }
+
+    // Remove the private keys earlier versions stored with the device metadata,
+    // keys are only returned when a device is created
+    fn _migrate_device_keys(&self) -> Result<usize, DatabaseError> {
+        let (db, cf) = self._cf(CF_DEVICES)?;
+        let mut migrated = 0;
+        for item in db.iterator_cf(cf, rocksdb::IteratorMode::Start) {
+            let (key, value) = item?;
+            let mut metadata: serde_json::Value = match serde_json::from_slice(&value) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            let removed = metadata
+                .as_object_mut()
+                .and_then(|metadata| metadata.remove("key"));
+            if removed.is_none() {
+                continue;
+            }
+            let data = serde_json::to_vec(&metadata).map_err(|e| {
+                DatabaseError::DatabaseValueError(format!("Failed to serialize device metadata: {}", e))
+            })?;
+            db.put_cf(cf, &key, data)?;
+            migrated += 1;
+        }
+        Ok(migrated)
+    }
+}
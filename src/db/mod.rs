@@ -1,22 +1,38 @@
-use crate::dataconfig::{DataConfig, DataConfigEntry};
+pub mod columns;
+mod merge;
+mod migration;
+pub mod retention;
+pub mod rollup;
+
+use crate::dataconfig::{DataConfig, DataConfigEntry, DataConfigError};
 use crate::shadow::{
-    Shadow, ShadowError, ShadowSerializationError, StateUpdateDocument,
+    Shadow, ShadowError, ShadowHistoryEntry, ShadowHistoryRecord, ShadowSerializationError,
+    StateUpdateDocument, UpdateSource,
 };
 use crate::models::{DeviceMetadata, ShadowName, TenantId};
+use columns::{CF_CONFIGS, CF_DEVICES, CF_METRICS, CF_ROLLUPS, CF_SHADOWS, CF_SHADOW_HISTORY};
 use crate::timeseries::{
-    MetricTimeSeries, MetricValue, TimeSeriesConversions, TimeseriesSerializationError,
+    now_micros, Aggregation, AggregationError, MetricTimeSeries, MetricValue,
+    TimeSeriesConversions, TimeseriesSerializationError, MICROS_PER_SECOND,
 };
+use retention::RetentionConfig;
+use rollup::RollupConfig;
 use rocksdb::backup::{BackupEngine, BackupEngineOptions};
 use rocksdb::Env;
+use rocksdb::ColumnFamily;
 pub use rocksdb::{OptimisticTransactionDB, Options};
 use serde::{Deserialize, Serialize};
 use tracing::warn;
+use std::collections::BTreeMap;
 use std::path::Path;
 use std::sync::Arc;
 use thiserror::Error;
 
 const MAX_FUTURE_SECONDS: u64 = 60 * 60 * 24 * 365;
 
+/// Metrics extracted from one message: tenant, device and the series per metric name
+pub type MetricWrite = (TenantId, String, Vec<(String, MetricTimeSeries)>);
+
 #[derive(Error, Debug)]
 pub enum DatabaseError {
     #[error("RocksDB Error: {0}")]
@@ -39,6 +55,12 @@ pub enum DatabaseError {
     DatabaseTransactionError(String),
     #[error("NotFound Error {0}")]
     NotFoundError(String),
+    #[error("Aggregation Error: {0}")]
+    AggregationError(#[from] AggregationError),
+    #[error("Stored DataConfig {0} is invalid: {1}")]
+    DataConfigError(String, DataConfigError),
+    #[error("Missing column family: {0}")]
+    MissingColumnFamily(String),
 }
 
 impl From<Box<bincode::ErrorKind>> for DatabaseError {
@@ -52,6 +74,15 @@ pub struct DatabaseConfig {
     pub path: String,
     pub create_if_missing: bool,
     pub backup_path: String,
+    /// Size of the block caches of all column families together in MB
+    #[serde(default = "default_block_cache_size")]
+    pub block_cache_size: usize,
+    #[serde(default)]
+    pub shadow_history: ShadowHistoryConfig,
+    #[serde(default)]
+    pub rollup: RollupConfig,
+    #[serde(default)]
+    pub retention: RetentionConfig,
 }
 
 impl Default for DatabaseConfig {
@@ -60,6 +91,35 @@ impl Default for DatabaseConfig {
             path: String::from("./.rocksdb/"),
             create_if_missing: true,
             backup_path: String::from("./.rocksdb_backup/"),
+            block_cache_size: default_block_cache_size(),
+            shadow_history: ShadowHistoryConfig::default(),
+            rollup: RollupConfig::default(),
+            retention: RetentionConfig::default(),
+        }
+    }
+}
+
+fn default_block_cache_size() -> usize {
+    256
+}
+
+/// Opt-in history of accepted shadow updates
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShadowHistoryConfig {
+    pub enabled: bool,
+    /// Number of versions kept per shadow
+    pub max_versions: usize,
+    /// Maximum age of history entries in seconds
+    pub max_age: Option<u64>,
+}
+
+impl Default for ShadowHistoryConfig {
+    fn default() -> Self {
+        ShadowHistoryConfig {
+            enabled: false,
+            max_versions: 100,
+            max_age: None,
         }
     }
 }
@@ -67,6 +127,9 @@ impl Default for DatabaseConfig {
 pub struct DB {
     pub path: String,
     pub backup_path: String,
+    pub shadow_history: ShadowHistoryConfig,
+    pub rollup: RollupConfig,
+    pub retention: RetentionConfig,
     pub db: Option<Arc<OptimisticTransactionDB>>,
 }
 
@@ -80,12 +143,23 @@ impl DB {
     pub fn open(config: &DatabaseConfig) -> Result<Self, DatabaseError> {
         let mut opts = Options::default();
         opts.create_if_missing(config.create_if_missing);
-        let db = OptimisticTransactionDB::open(&opts, &config.path)?;
-        Ok(DB {
+        opts.create_missing_column_families(true);
+        // older versions merged into the default column family, needed until it is migrated
+        opts.set_merge_operator_associative(merge::MERGE_OPERATOR_NAME, merge::merge_values);
+        // column families of newer versions have to be opened as well, a new database has none
+        let existing = rocksdb::DB::list_cf(&opts, &config.path).unwrap_or_default();
+        let descriptors = columns::descriptors(&existing, config.block_cache_size * 1024 * 1024);
+        let db = OptimisticTransactionDB::open_cf_descriptors(&opts, &config.path, descriptors)?;
+        let db = DB {
             path: config.path.to_owned(),
             backup_path: config.backup_path.to_owned(),
+            shadow_history: config.shadow_history.to_owned(),
+            rollup: config.rollup.to_owned(),
+            retention: config.retention.to_owned(),
             db: Some(Arc::new(db)),
-        })
+        };
+        db.migrate()?;
+        Ok(db)
     }
 
     pub fn destroy(path: &str, opts: Option<&Options>) -> Result<(), DatabaseError> {
@@ -97,6 +171,32 @@ impl DB {
         Ok(())
     }
 
+    // Database and handle of a column family
+    fn _cf(&self, name: &str) -> Result<(&OptimisticTransactionDB, &ColumnFamily), DatabaseError> {
+        let db = self.db.as_ref().ok_or(DatabaseError::DatabaseConnectionError)?;
+        let cf = db
+            .cf_handle(name)
+            .ok_or(DatabaseError::MissingColumnFamily(name.to_string()))?;
+        Ok((db, cf))
+    }
+
+    fn _put_cf(&self, cf_name: &str, key: &[u8], data: &[u8]) -> Result<(), DatabaseError> {
+        let (db, cf) = self._cf(cf_name)?;
+        db.put_cf(cf, key, data)?;
+        Ok(())
+    }
+
+    fn _get_cf(&self, cf_name: &str, key: &[u8]) -> Result<Option<Vec<u8>>, DatabaseError> {
+        let (db, cf) = self._cf(cf_name)?;
+        Ok(db.get_cf(cf, key)?)
+    }
+
+    fn _delete_cf(&self, cf_name: &str, key: &[u8]) -> Result<(), DatabaseError> {
+        let (db, cf) = self._cf(cf_name)?;
+        db.delete_cf(cf, key)?;
+        Ok(())
+    }
+
     pub fn set_data(&self, key: &str, data: &[u8]) -> Result<(), DatabaseError> {
         if let Some(db) = &self.db {
             db.put(key.as_bytes(), data)?;
@@ -143,19 +243,48 @@ impl DB {
     }
 
     pub fn _put_timeseries(&self, key: &[u8], ts: &MetricTimeSeries) -> Result<(), DatabaseError> {
+        self._put_timeseries_batch([(key.to_vec(), ts)])
+    }
+
+    // Write several series in one transaction, buckets of the same key are merged first
+    fn _put_timeseries_batch<'a>(
+        &self,
+        series: impl IntoIterator<Item = (Vec<u8>, &'a MetricTimeSeries)>,
+    ) -> Result<(), DatabaseError> {
         // split timeseries into hourly buckets
-        // Generate Vector of buckets and keys
-        let mut ts_buckets: Vec<(Vec<u8>, MetricTimeSeries)> = Vec::new();
-        for ts_bucket in ts.buckets() {
-            if let Some(first_ts) = ts_bucket.first_timestamp() {
-                // Generate key with timestamp
-                let full_key = DB::_to_ts_key(key, first_ts);
-                // Add key and bucket to vector
-                ts_buckets.push((full_key, ts_bucket));
+        let mut ts_buckets: BTreeMap<Vec<u8>, MetricTimeSeries> = BTreeMap::new();
+        let mut rollup_pending: BTreeMap<Vec<u8>, (u64, u64)> = BTreeMap::new();
+        for (key, ts) in series {
+            for ts_bucket in ts.buckets() {
+                if let Some(first_ts) = ts_bucket.first_timestamp() {
+                    // Generate key with timestamp
+                    let full_key = DB::_to_ts_key(&key, first_ts);
+                    match ts_buckets.get_mut(&full_key) {
+                        Some(existing) => existing.merge(&ts_bucket),
+                        None => {
+                            ts_buckets.insert(full_key, ts_bucket);
+                        }
+                    }
+                }
+            }
+            // remember the written range for the next rollup run
+            if let (true, Some(from), Some((to, _))) =
+                (self.rollup.enabled, ts.first_timestamp(), ts.latest())
+            {
+                let range = rollup_pending.entry(key).or_insert((from, to));
+                *range = (range.0.min(from), range.1.max(to));
             }
         }
+        let rollup_pending: Vec<(Vec<u8>, u64, u64)> = rollup_pending
+            .into_iter()
+            .map(|(key, (from, to))| (key, from, to))
+            .collect();
         // write batch to db
-        self._upsert_timeseries_buckets(ts_buckets)
+        self._upsert_timeseries_buckets(CF_METRICS, ts_buckets.into_iter().collect(), &rollup_pending)
+    }
+
+    fn _to_metric_key(tenant_id: &TenantId, device_id: &str, metric_name: &str) -> Vec<u8> {
+        format!("{}#{}#{}", tenant_id, device_id, metric_name).into_bytes()
     }
 
     pub fn put_metric(
@@ -165,79 +294,102 @@ impl DB {
         metric_name: &str,
         value: MetricValue,
     ) -> Result<(), DatabaseError> {
-        let key = format!("{}#{}#{}", tenant_id, device_id, metric_name).into_bytes();
-        let generic_ts = value.as_timeseries(chrono::Utc::now().timestamp() as u64);
-        self._put_timeseries(&key, &generic_ts)
+        let generic_ts = value.as_timeseries(now_micros());
+        self.put_metric_timeseries(tenant_id, device_id, metric_name, &generic_ts)
     }
 
-    // Upsert timeseries data into the database
-    // the ts_buckets are tuples of key and timeseries data
-    fn _upsert_timeseries_buckets(
+    /// Store a series of timestamped values of a metric
+    pub fn put_metric_timeseries(
         &self,
-        ts_buckets: Vec<(Vec<u8>, MetricTimeSeries)>,
+        tenant_id: &TenantId,
+        device_id: &str,
+        metric_name: &str,
+        ts: &MetricTimeSeries,
     ) -> Result<(), DatabaseError> {
-        const MAX_RETRIES: u32 = 5;
-        let mut retry_count = 0;
-
-        while retry_count < MAX_RETRIES {
-            if let Some(db) = &self.db {
-                let txn = db.transaction();
-
-                // Process each bucket
-                for (key, new_ts) in &ts_buckets {
-                    // Get existing bucket if it exists
-                    let mut final_ts = match txn.get_for_update(key, false)? {
-                        Some(data) => MetricTimeSeries::from_binary(&data)?,
-                        None => MetricTimeSeries::new(),
-                    };
+        let key = Self::_to_metric_key(tenant_id, device_id, metric_name);
+        self._put_timeseries(&key, ts)
+    }
 
-                    // Merge new data
-                    final_ts.merge(new_ts);
+    /// Store the series of several metrics of a device in a single transaction
+    pub fn put_metrics(
+        &self,
+        tenant_id: &TenantId,
+        device_id: &str,
+        metrics: &[(String, MetricTimeSeries)],
+    ) -> Result<(), DatabaseError> {
+        self._put_timeseries_batch(
+            metrics
+                .iter()
+                .map(|(name, ts)| (Self::_to_metric_key(tenant_id, device_id, name), ts)),
+        )
+    }
 
-                    // Serialize and prepare write
-                    let ts_data = final_ts.to_binary()?;
-                    txn.put(key, &ts_data)?;
-                }
+    /// Store the metrics of several messages, e.g. a micro-batch of ingested
+    /// payloads of different devices, in a single transaction
+    pub fn put_metrics_batch(
+        &self,
+        batch: &[MetricWrite],
+    ) -> Result<(), DatabaseError> {
+        self._put_timeseries_batch(batch.iter().flat_map(|(tenant_id, device_id, metrics)| {
+            metrics
+                .iter()
+                .map(|(name, ts)| (Self::_to_metric_key(tenant_id, device_id, name), ts))
+        }))
+    }
 
-                // Try to commit transaction
-                match txn.commit() {
-                    Ok(_) => return Ok(()),
-                    Err(e) => {
-                        retry_count += 1;
-                        if retry_count < MAX_RETRIES {
-                            continue;
-                        } else {
-                            return Err(DatabaseError::RocksDBError(e));
-                        }
-                    }
-                }
-            } else {
-                return Err(DatabaseError::DatabaseConnectionError);
-            }
+    // Append timeseries data to the database
+    // the ts_buckets are tuples of key and timeseries data, they are combined with
+    // the stored buckets by the merge operator. The merges go through a plain write
+    // batch instead of a transaction, an optimistic transaction tracks merged keys
+    // and would fail with Busy when another writer touches the same bucket
+    fn _upsert_timeseries_buckets(
+        &self,
+        cf_name: &str,
+        ts_buckets: Vec<(Vec<u8>, MetricTimeSeries)>,
+        rollup_pending: &[(Vec<u8>, u64, u64)],
+    ) -> Result<(), DatabaseError> {
+        let (db, cf) = self._cf(cf_name)?;
+        let (_, rollups_cf) = self._cf(CF_ROLLUPS)?;
+        let mut batch = rocksdb::WriteBatchWithTransaction::<true>::default();
+        for (key, new_ts) in &ts_buckets {
+            batch.merge_cf(cf, key, new_ts.to_binary()?);
         }
-
-        Err(DatabaseError::DatabaseTransactionError(
-            "Failed to commit timeseries update after max retries".to_string(),
-        ))
+        for (series_key, from, to) in rollup_pending {
+            Self::_mark_rollup_pending(&mut batch, rollups_cf, series_key, *from, *to)?;
+        }
+        db.write(batch)?;
+        Ok(())
     }
 
-    // Return the timeseries data for a given key and timestamp range
+    // Return the raw timeseries data for a given key and timestamp range
     pub fn _get_timeseries(
         &self,
         key: &[u8],
         min_ts: u64,
         max_ts: u64,
+    ) -> Result<MetricTimeSeries, DatabaseError> {
+        self._get_timeseries_cf(CF_METRICS, key, min_ts, max_ts)
+    }
+
+    // Return the timeseries data of a column family for a given key and timestamp range
+    fn _get_timeseries_cf(
+        &self,
+        cf_name: &str,
+        key: &[u8],
+        min_ts: u64,
+        max_ts: u64,
     ) -> Result<MetricTimeSeries, DatabaseError> {
         let mut merged_ts = MetricTimeSeries::new();
 
-        if let Some(db) = &self.db {
+        if self.db.is_some() {
+            let (db, cf) = self._cf(cf_name)?;
             let full_min_key = DB::_to_ts_key(key, min_ts);
             let full_max_key = DB::_to_ts_key(key, max_ts);
 
             // println!("Full min key: {:?}", String::from_utf8_lossy(&full_min_key).to_string());
             // println!("Full max key: {:?}", String::from_utf8_lossy(&full_max_key).to_string());
 
-            let iter = db.iterator(rocksdb::IteratorMode::From(
+            let iter = db.iterator_cf(cf, rocksdb::IteratorMode::From(
                 &full_max_key,
                 rocksdb::Direction::Forward,
             ));
@@ -265,6 +417,7 @@ impl DB {
         }
     }
 
+    /// Get a metric between `start` and `end`, both inclusive and in microseconds
     pub fn get_metric(
         &self,
         tenant_id: &TenantId,
@@ -273,8 +426,24 @@ impl DB {
         start: u64,
         end: u64,
     ) -> Result<MetricTimeSeries, DatabaseError> {
-        let key = format!("{}#{}#{}", tenant_id, device_id, metric_name).into_bytes();
-        self._get_timeseries(&key, start, end)
+        let key = Self::_to_metric_key(tenant_id, device_id, metric_name);
+        self._get_metric_series(&key, start, end)
+    }
+
+    /// Get a metric aggregated into buckets of `bucket_width` microseconds
+    #[allow(clippy::too_many_arguments)]
+    pub fn get_metric_aggregated(
+        &self,
+        tenant_id: &TenantId,
+        device_id: &str,
+        metric_name: &str,
+        start: u64,
+        end: u64,
+        bucket_width: u64,
+        aggregation: Aggregation,
+    ) -> Result<MetricTimeSeries, DatabaseError> {
+        let key = Self::_to_metric_key(tenant_id, device_id, metric_name);
+        self._get_metric_aggregated(&key, start, end, bucket_width, aggregation)
     }
 
     pub fn get_last_metric(
@@ -284,7 +453,7 @@ impl DB {
         metric_name: &str,
         limit: u64,
     ) -> Result<MetricTimeSeries, DatabaseError> {
-        let key = format!("{}#{}#{}", tenant_id, device_id, metric_name).into_bytes();
+        let key = Self::_to_metric_key(tenant_id, device_id, metric_name);
         self._get_timeseries_last(&key, limit)
     }
 
@@ -295,11 +464,12 @@ impl DB {
         limit: u64,
     ) -> Result<MetricTimeSeries, DatabaseError> {
         let mut merged_ts = MetricTimeSeries::new();
-        let max_ts = chrono::Utc::now().timestamp() as u64 + MAX_FUTURE_SECONDS;
+        let max_ts = now_micros() + MAX_FUTURE_SECONDS * MICROS_PER_SECOND;
 
-        if let Some(db) = &self.db {
+        if self.db.is_some() {
+            let (db, cf) = self._cf(CF_METRICS)?;
             let full_max_key = DB::_to_ts_key(key_prefix, max_ts);
-            let iter = db.iterator(rocksdb::IteratorMode::From(
+            let iter = db.iterator_cf(cf, rocksdb::IteratorMode::From(
                 &full_max_key,
                 rocksdb::Direction::Forward,
             ));
@@ -342,11 +512,11 @@ impl DB {
     }
 
     pub fn _from_ts_key(full_key: &[u8]) -> Result<(&[u8], u64), DatabaseError> {
-        // Find separator position
+        // Find separator position, the key itself may contain separators
         let sep_pos =
             full_key
                 .windows(1)
-                .position(|w| w == b"#")
+                .rposition(|w| w == b"#")
                 .ok_or(DatabaseError::InvalidKeyError(
                     String::from_utf8_lossy(full_key).to_string(),
                 ))?;
@@ -369,24 +539,58 @@ impl DB {
         format!("{}#{}#{}", tenant_id, device_id, shadow_name.as_str()).into_bytes()
     }
 
+    fn _to_shadow_history_prefix(
+        device_id: &str,
+        shadow_name: &ShadowName,
+        tenant_id: &TenantId,
+    ) -> Vec<u8> {
+        format!("history#{}#{}#{}#", tenant_id, device_id, shadow_name.as_str()).into_bytes()
+    }
+
+    fn _to_shadow_history_key(shadow: &Shadow) -> Vec<u8> {
+        let mut key =
+            Self::_to_shadow_history_prefix(&shadow.device_id, &shadow.shadow_name, &shadow.tenant_id);
+        // zero padded so versions sort numerically
+        key.extend_from_slice(format!("{:020}", shadow.get_version()).as_bytes());
+        key
+    }
+
+    /// Upsert a shadow from the API
     pub fn _upsert_shadow(&self, update: &StateUpdateDocument) -> Result<Shadow, DatabaseError> {
+        let (_previous, shadow) = self._update_shadow(update, UpdateSource::Api)?;
+        Ok(shadow)
+    }
+
+    /// Apply an update and return the shadow before and after the update
+    pub fn _update_shadow(
+        &self,
+        update: &StateUpdateDocument,
+        source: UpdateSource,
+    ) -> Result<(Option<Shadow>, Shadow), DatabaseError> {
         const MAX_RETRIES: u32 = 5;
         let mut retry_count = 0;
         let key = Self::_to_shadow_key(&update.device_id, &update.shadow_name, &update.tenant_id);
 
         while retry_count < MAX_RETRIES {
-            if let Some(db) = &self.db {
+            if self.db.is_some() {
+                let (db, cf) = self._cf(CF_SHADOWS)?;
                 let txn = db.transaction();
 
                 // Get existing shadow or create new
-                let mut shadow = match txn.get_for_update(&key, false)? {
+                let previous = match txn.get_for_update_cf(cf, &key, false)? {
                     Some(data) => {
                         let shadow_str = String::from_utf8(data).map_err(|_e| {
                             DatabaseError::DatabaseValueError("Invalid UTF-8".to_string())
                         })?;
-                        Shadow::from_json(&shadow_str)?
+                        Some(Shadow::from_json(&shadow_str)?)
                     }
-                    None => Shadow::new(&update.device_id, &update.shadow_name, &update.tenant_id),
+                    None => None,
+                };
+                let mut shadow = match &previous {
+                    Some(previous) => previous.clone(),
+                    // the history of a deleted shadow is kept, a new one continues its versions
+                    None => Shadow::new(&update.device_id, &update.shadow_name, &update.tenant_id)
+                        .with_version(self._last_history_version(&txn, update)?.unwrap_or(0)),
                 };
 
                 // Apply update
@@ -395,10 +599,14 @@ impl DB {
                 // Serialize and write
                 let shadow_data = shadow.to_json()?.into_bytes();
 
-                txn.put(&key, &shadow_data)?;
+                txn.put_cf(cf, &key, &shadow_data)?;
+
+                if self.shadow_history.enabled {
+                    self._record_shadow_history(&txn, update, &shadow, source)?;
+                }
 
                 match txn.commit() {
-                    Ok(_) => return Ok(shadow),
+                    Ok(_) => return Ok((previous, shadow)),
                     Err(e) => {
                         retry_count += 1;
                         if retry_count < MAX_RETRIES {
@@ -424,10 +632,10 @@ impl DB {
         shadow_name: &ShadowName,
         tenant_id: &TenantId,
     ) -> Result<Shadow, DatabaseError> {
-        if let Some(db) = &self.db {
+        if self.db.is_some() {
             let key = Self::_to_shadow_key(device_id, shadow_name, tenant_id);
 
-            match db.get(&key)? {
+            match self._get_cf(CF_SHADOWS, &key)? {
                 Some(data) => {
                     let shadow_str = String::from_utf8(data).map_err(|_e| {
                         DatabaseError::DatabaseValueError("Invalid UTF-8".to_string())
@@ -444,6 +652,258 @@ impl DB {
         }
     }
 
+    /// List the names of all shadows of a device
+    pub fn list_shadow_names(
+        &self,
+        device_id: &str,
+        tenant_id: &TenantId,
+    ) -> Result<Vec<ShadowName>, DatabaseError> {
+        let mut shadow_names = Vec::new();
+        let prefix = format!("{}#{}#", tenant_id, device_id);
+
+        if self.db.is_some() {
+            let (db, cf) = self._cf(CF_SHADOWS)?;
+            let iter = db.iterator_cf(cf, rocksdb::IteratorMode::From(
+                prefix.as_bytes(),
+                rocksdb::Direction::Forward,
+            ));
+            for item in iter {
+                let (key, _) = item?;
+                let key_str = String::from_utf8_lossy(&key);
+                let shadow_name = match key_str.strip_prefix(&prefix) {
+                    Some(name) => name,
+                    None => break,
+                };
+                // shadow keys have three parts, longer keys (e.g. metric buckets) are no shadows
+                if shadow_name.contains('#') {
+                    continue;
+                }
+                shadow_names.push(ShadowName::from_str(shadow_name));
+            }
+        } else {
+            return Err(DatabaseError::DatabaseConnectionError);
+        }
+
+        Ok(shadow_names)
+    }
+
+    /// Delete a shadow and return the deleted document, its history is kept
+    pub fn _delete_shadow(
+        &self,
+        device_id: &str,
+        shadow_name: &ShadowName,
+        tenant_id: &TenantId,
+    ) -> Result<Shadow, DatabaseError> {
+        const MAX_RETRIES: u32 = 5;
+        let mut retry_count = 0;
+        let key = Self::_to_shadow_key(device_id, shadow_name, tenant_id);
+
+        while retry_count < MAX_RETRIES {
+            if self.db.is_some() {
+                let (db, cf) = self._cf(CF_SHADOWS)?;
+                let txn = db.transaction();
+
+                let shadow = match txn.get_for_update_cf(cf, &key, false)? {
+                    Some(data) => {
+                        let shadow_str = String::from_utf8(data).map_err(|_e| {
+                            DatabaseError::DatabaseValueError("Invalid UTF-8".to_string())
+                        })?;
+                        Shadow::from_json(&shadow_str)?
+                    }
+                    None => {
+                        return Err(DatabaseError::NotFoundError(format!(
+                            "Shadow not found for device = {} name = {} tenant = {}",
+                            device_id, shadow_name, tenant_id
+                        )))
+                    }
+                };
+
+                txn.delete_cf(cf, &key)?;
+
+                match txn.commit() {
+                    Ok(_) => return Ok(shadow),
+                    Err(e) => {
+                        retry_count += 1;
+                        if retry_count < MAX_RETRIES {
+                            continue;
+                        } else {
+                            return Err(DatabaseError::RocksDBError(e));
+                        }
+                    }
+                }
+            } else {
+                return Err(DatabaseError::DatabaseConnectionError);
+            }
+        }
+
+        Err(DatabaseError::DatabaseTransactionError(
+            "Failed to commit shadow delete after max retries".to_string(),
+        ))
+    }
+
+    // Store the accepted update and drop entries beyond the retention limits
+    fn _record_shadow_history(
+        &self,
+        txn: &rocksdb::Transaction<'_, OptimisticTransactionDB>,
+        update: &StateUpdateDocument,
+        shadow: &Shadow,
+        source: UpdateSource,
+    ) -> Result<(), DatabaseError> {
+        let (_, cf) = self._cf(CF_SHADOW_HISTORY)?;
+        let prefix =
+            Self::_to_shadow_history_prefix(&shadow.device_id, &shadow.shadow_name, &shadow.tenant_id);
+        let min_timestamp = self
+            .shadow_history
+            .max_age
+            .map(|max_age| shadow.get_last_updated().saturating_sub(max_age));
+
+        // existing entries, oldest first
+        let mut existing = Vec::new();
+        for item in txn.iterator_cf(cf, rocksdb::IteratorMode::From(
+            &prefix,
+            rocksdb::Direction::Forward,
+        )) {
+            let (key, value) = item?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            let timestamp = match min_timestamp {
+                Some(_) => serde_json::from_slice::<ShadowHistoryEntry>(&value)
+                    .map(|entry| entry.timestamp)
+                    .unwrap_or(0),
+                None => 0,
+            };
+            existing.push((key, timestamp));
+        }
+
+        let excess = (existing.len() + 1).saturating_sub(self.shadow_history.max_versions);
+        for (i, (key, timestamp)) in existing.iter().enumerate() {
+            let expired = min_timestamp.is_some_and(|min| *timestamp < min);
+            if i < excess || expired {
+                txn.delete_cf(cf, key)?;
+            }
+        }
+
+        if self.shadow_history.max_versions > 0 {
+            let record = ShadowHistoryRecord::new(update, shadow, source);
+            let data = serde_json::to_vec(&record).map_err(ShadowSerializationError::from)?;
+            txn.put_cf(cf, Self::_to_shadow_history_key(shadow), data)?;
+        }
+        Ok(())
+    }
+
+    // Latest version recorded in the history of a shadow
+    fn _last_history_version(
+        &self,
+        txn: &rocksdb::Transaction<'_, OptimisticTransactionDB>,
+        update: &StateUpdateDocument,
+    ) -> Result<Option<u64>, DatabaseError> {
+        let (_, cf) = self._cf(CF_SHADOW_HISTORY)?;
+        let prefix =
+            Self::_to_shadow_history_prefix(&update.device_id, &update.shadow_name, &update.tenant_id);
+        let mut version = None;
+        for item in txn.iterator_cf(cf, rocksdb::IteratorMode::From(
+            &prefix,
+            rocksdb::Direction::Forward,
+        )) {
+            let (key, _) = item?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            version = std::str::from_utf8(&key[prefix.len()..])
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .or(version);
+        }
+        Ok(version)
+    }
+
+    fn _get_shadow_history_records(
+        &self,
+        device_id: &str,
+        shadow_name: &ShadowName,
+        tenant_id: &TenantId,
+    ) -> Result<Vec<ShadowHistoryRecord>, DatabaseError> {
+        let mut records = Vec::new();
+        if self.db.is_some() {
+            let (db, cf) = self._cf(CF_SHADOW_HISTORY)?;
+            let prefix = Self::_to_shadow_history_prefix(device_id, shadow_name, tenant_id);
+            let iter = db.iterator_cf(cf, rocksdb::IteratorMode::From(
+                &prefix,
+                rocksdb::Direction::Forward,
+            ));
+            for item in iter {
+                let (key, value) = item?;
+                if !key.starts_with(&prefix) {
+                    break;
+                }
+                let record = serde_json::from_slice(&value).map_err(ShadowSerializationError::from)?;
+                records.push(record);
+            }
+            Ok(records)
+        } else {
+            Err(DatabaseError::DatabaseConnectionError)
+        }
+    }
+
+    /// List the recorded updates of a shadow, oldest first
+    pub fn list_shadow_history(
+        &self,
+        device_id: &str,
+        shadow_name: &ShadowName,
+        tenant_id: &TenantId,
+    ) -> Result<Vec<ShadowHistoryEntry>, DatabaseError> {
+        let records = self._get_shadow_history_records(device_id, shadow_name, tenant_id)?;
+        Ok(records.into_iter().map(|r| r.entry).collect())
+    }
+
+    /// Get the shadow as it was after the update with the given version
+    pub fn get_shadow_at_version(
+        &self,
+        device_id: &str,
+        shadow_name: &ShadowName,
+        tenant_id: &TenantId,
+        version: u64,
+    ) -> Result<Shadow, DatabaseError> {
+        if self.db.is_some() {
+            let mut key = Self::_to_shadow_history_prefix(device_id, shadow_name, tenant_id);
+            key.extend_from_slice(format!("{:020}", version).as_bytes());
+            match self._get_cf(CF_SHADOW_HISTORY, &key)? {
+                Some(data) => {
+                    let record: ShadowHistoryRecord =
+                        serde_json::from_slice(&data).map_err(ShadowSerializationError::from)?;
+                    Ok(record.shadow)
+                }
+                None => Err(DatabaseError::NotFoundError(format!(
+                    "Shadow version {} not found for device = {} name = {} tenant = {}",
+                    version, device_id, shadow_name, tenant_id
+                ))),
+            }
+        } else {
+            Err(DatabaseError::DatabaseConnectionError)
+        }
+    }
+
+    /// Get the shadow as it was at the given timestamp
+    pub fn get_shadow_at_timestamp(
+        &self,
+        device_id: &str,
+        shadow_name: &ShadowName,
+        tenant_id: &TenantId,
+        timestamp: u64,
+    ) -> Result<Shadow, DatabaseError> {
+        let records = self._get_shadow_history_records(device_id, shadow_name, tenant_id)?;
+        records
+            .into_iter()
+            .rev()
+            .find(|r| r.entry.timestamp <= timestamp)
+            .map(|r| r.shadow)
+            .ok_or(DatabaseError::NotFoundError(format!(
+                "No shadow version at {} for device = {} name = {} tenant = {}",
+                timestamp, device_id, shadow_name, tenant_id
+            )))
+    }
+
     pub fn flush(&self) -> Result<(), DatabaseError> {
         if let Some(db) = &self.db {
             db.flush()?;
@@ -477,7 +937,7 @@ impl DB {
     ) -> Result<(), DatabaseError> {
         let key = Self::_to_dataconfig_key(tenant_id, None);
         let data = config.to_json().into_bytes();
-        self.set_data(&String::from_utf8_lossy(&key), &data)
+        self._put_cf(CF_CONFIGS, &key, &data)
     }
 
     pub fn store_device_data_config(
@@ -488,7 +948,13 @@ impl DB {
     ) -> Result<(), DatabaseError> {
         let key = Self::_to_dataconfig_key(tenant_id, Some(device_id_prefix));
         let data = config.to_json().into_bytes();
-        self.set_data(&String::from_utf8_lossy(&key), &data)
+        self._put_cf(CF_CONFIGS, &key, &data)
+    }
+
+    // Corrupt stored configs are reported with their key instead of panicking
+    fn _parse_data_config(key: &str, value: &[u8]) -> Result<DataConfig, DatabaseError> {
+        DataConfig::from_json(&String::from_utf8_lossy(value))
+            .map_err(|e| DatabaseError::DataConfigError(key.to_string(), e))
     }
 
     pub fn get_data_config(
@@ -499,8 +965,8 @@ impl DB {
         // Get tenant config first
         let tenant_key = Self::_to_dataconfig_key(tenant_id, None);
         let tenant_key_str = String::from_utf8_lossy(&tenant_key);
-        let maybe_tenant_cfg = match self.get_data(&tenant_key_str)? {
-            Some(bytes) => Some(DataConfig::from_json(&String::from_utf8_lossy(&bytes))),
+        let maybe_tenant_cfg = match self._get_cf(CF_CONFIGS, &tenant_key)? {
+            Some(bytes) => Some(Self::_parse_data_config(&tenant_key_str, &bytes)?),
             None => None,
         };
 
@@ -510,18 +976,21 @@ impl DB {
         }
 
         // Search for device config using prefix
-        if let Some(db) = &self.db {
+        if self.db.is_some() {
+            let (db, cf) = self._cf(CF_CONFIGS)?;
             let search_key = Self::_to_dataconfig_key(tenant_id, device_id);
-            let mut iter = db.iterator(rocksdb::IteratorMode::From(
+            let mut iter = db.iterator_cf(cf, rocksdb::IteratorMode::From(
                 &search_key,
                 rocksdb::Direction::Reverse,
             ));
 
             // Look for longest matching prefix
+            // device configs of this tenant are stored below "dc#{tenant}#"
+            let device_key_prefix = format!("{}#", tenant_key_str);
             while let Some(Ok((key, value))) = iter.next() {
                 let key_str = String::from_utf8_lossy(&key);
-                if key_str.starts_with(tenant_key_str.as_ref()) {
-                    let device_cfg = DataConfig::from_json(&String::from_utf8_lossy(&value));
+                if key_str.starts_with(&device_key_prefix) {
+                    let device_cfg = Self::_parse_data_config(&key_str, &value)?;
                     // if we have a tenant config, merge with device config
                     if let Some(tenant_cfg) = maybe_tenant_cfg {
                         return Ok(Some(tenant_cfg.merge_with(&device_cfg)));
@@ -542,7 +1011,7 @@ impl DB {
         device_id_prefix: Option<&str>,
     ) -> Result<(), DatabaseError> {
         let key = Self::_to_dataconfig_key(tenant_id, device_id_prefix);
-        self.delete_data(&String::from_utf8_lossy(&key))
+        self._delete_cf(CF_CONFIGS, &key)
     }
 
     pub fn list_data_configs(
@@ -550,11 +1019,13 @@ impl DB {
         tenant_id: &TenantId,
     ) -> Result<Vec<DataConfigEntry>, DatabaseError> {
         let mut configs = Vec::new();
-        let tenant_prefix = format!("dc#{}", tenant_id);
+        let tenant_key = format!("dc#{}", tenant_id);
+        let device_key_prefix = format!("{}#", tenant_key);
 
-        if let Some(db) = &self.db {
-            let iter = db.iterator(rocksdb::IteratorMode::From(
-                tenant_prefix.as_bytes(),
+        if self.db.is_some() {
+            let (db, cf) = self._cf(CF_CONFIGS)?;
+            let iter = db.iterator_cf(cf, rocksdb::IteratorMode::From(
+                tenant_key.as_bytes(),
                 rocksdb::Direction::Forward,
             ));
 
@@ -562,10 +1033,21 @@ impl DB {
                 match item {
                     Ok((key, value)) => {
                         let key_str = String::from_utf8_lossy(&key);
-                        if !key_str.starts_with(&tenant_prefix) {
+                        if !key_str.starts_with(&tenant_key) {
                             break;
                         }
-                        let config = DataConfig::from_json(&String::from_utf8_lossy(&value));
+                        // skip configs of other tenants sharing the same prefix (e.g. "dc#acme2")
+                        if key_str != tenant_key && !key_str.starts_with(&device_key_prefix) {
+                            continue;
+                        }
+                        // a corrupt config is listed with its error so it can be replaced
+                        let (metrics, error) = match Self::_parse_data_config(&key_str, &value) {
+                            Ok(config) => (config.metrics, None),
+                            Err(e) => {
+                                warn!(key = %key_str, error = %e, "Listing corrupt data config");
+                                (Vec::new(), Some(e.to_string()))
+                            }
+                        };
                         // split key_str into tenant_id and device_prefix (seperated by #)
                         let parts: Vec<&str> = key_str.split('#').collect();
                         let device_prefix = {
@@ -578,7 +1060,8 @@ impl DB {
                         configs.push(DataConfigEntry {
                             tenant_id: tenant_id.to_owned(),
                             device_prefix: device_prefix,
-                            metrics: config.metrics,
+                            metrics,
+                            error,
                         });
                     }
                     Err(e) => return Err(DatabaseError::RocksDBError(e)),
@@ -601,16 +1084,11 @@ impl DB {
     }
 
     pub fn put_device_metadata(&self, metadata: &DeviceMetadata) -> Result<(), DatabaseError> {
-        if let Some(db) = &self.db {
-            let key = Self::_to_device_metadata_key(&metadata.tenant_id, &metadata.device_id);
-            let data = serde_json::to_vec(metadata).map_err(|e| {
-                DatabaseError::DatabaseValueError(format!("Failed to serialize device metadata: {}", e))
-            })?;
-            db.put(key, data)?;
-            Ok(())
-        } else {
-            Err(DatabaseError::DatabaseConnectionError)
-        }
+        let key = Self::_to_device_metadata_key(&metadata.tenant_id, &metadata.device_id);
+        let data = serde_json::to_vec(metadata).map_err(|e| {
+            DatabaseError::DatabaseValueError(format!("Failed to serialize device metadata: {}", e))
+        })?;
+        self._put_cf(CF_DEVICES, &key, &data)
     }
 
     pub fn get_device_metadata(
@@ -618,9 +1096,9 @@ impl DB {
         tenant_id: &TenantId,
         device_id: &str,
     ) -> Result<Option<DeviceMetadata>, DatabaseError> {
-        if let Some(db) = &self.db {
+        if self.db.is_some() {
             let key = Self::_to_device_metadata_key(tenant_id, device_id);
-            match db.get(&key)? {
+            match self._get_cf(CF_DEVICES, &key)? {
                 Some(data) => {
                     let metadata = serde_json::from_slice(&data).map_err(|e| {
                         DatabaseError::DatabaseValueError(format!("Failed to deserialize device metadata: {}", e))
@@ -635,11 +1113,20 @@ impl DB {
     }
 
     pub fn list_devices(&self, tenant_id: &TenantId) -> Result<Vec<DeviceMetadata>, DatabaseError> {
+        self._list_devices_with_prefix(&format!("device#{}#", tenant_id))
+    }
+
+    /// List the devices of all tenants
+    pub fn list_all_devices(&self) -> Result<Vec<DeviceMetadata>, DatabaseError> {
+        self._list_devices_with_prefix("device#")
+    }
+
+    fn _list_devices_with_prefix(&self, prefix: &str) -> Result<Vec<DeviceMetadata>, DatabaseError> {
         let mut devices = Vec::new();
-        let prefix = format!("device#{}", tenant_id);
 
-        if let Some(db) = &self.db {
-            let iter = db.iterator(rocksdb::IteratorMode::From(
+        if self.db.is_some() {
+            let (db, cf) = self._cf(CF_DEVICES)?;
+            let iter = db.iterator_cf(cf, rocksdb::IteratorMode::From(
                 prefix.as_bytes(),
                 rocksdb::Direction::Forward,
             ));
@@ -649,7 +1136,7 @@ impl DB {
                     Ok((key, value)) => {
                         let key_str = String::from_utf8_lossy(&key);
                         // Stop iteration when we reach keys that don't match our prefix
-                        if !key_str.starts_with(&prefix) {
+                        if !key_str.starts_with(prefix) {
                             break;
                         }
 
@@ -679,7 +1166,7 @@ impl DB {
         device_id: &str,
     ) -> Result<(), DatabaseError> {
         let key = Self::_to_device_metadata_key(tenant_id, device_id);
-        self.delete_data(&String::from_utf8_lossy(&key))
+        self._delete_cf(CF_DEVICES, &key)
     }
 }
 
@@ -0,0 +1,193 @@
+//! Time-based deletion of time series data.
+//!
+//! A background worker periodically walks all hourly time series buckets and
+//! rollup buckets and deletes those that lie completely before the retention
+//! of their series. The retention of a metric is taken from its data config,
+//! falling back to the retention of its tenant and the global default. Runs are
+//! skipped as long as no retention is set anywhere.
+
+use super::columns::{CF_CONFIGS, CF_METRICS, CF_ROLLUPS};
+use super::rollup::{is_rollup_state_key, parse_rollup_bucket_key};
+use super::{DatabaseError, DB};
+use crate::models::TenantId;
+use crate::timeseries::{now_micros, MICROS_PER_SECOND};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+const HOUR: u64 = 60 * 60 * MICROS_PER_SECOND;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetentionConfig {
+    /// Seconds between two retention runs
+    pub interval: u64,
+    /// Time series data older than this many seconds is deleted, `None` keeps it forever
+    pub default: Option<u64>,
+    /// Retention per tenant id, overrides the default (`null` keeps data forever)
+    pub tenants: HashMap<String, Option<u64>>,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            interval: 3600,
+            default: None,
+            tenants: HashMap::new(),
+        }
+    }
+}
+
+impl RetentionConfig {
+    /// Retention of a tenant in seconds, `None` if its data is kept forever
+    pub fn tenant_retention(&self, tenant_id: &TenantId) -> Option<u64> {
+        match self.tenants.get(tenant_id.as_str()) {
+            Some(retention) => *retention,
+            None => self.default,
+        }
+    }
+
+    /// True if the default or the retention of any tenant is set
+    pub fn is_configured(&self) -> bool {
+        self.default.is_some() || self.tenants.values().any(Option::is_some)
+    }
+}
+
+// Series key and end of the covered time span of a bucket key
+type BucketKeyParser = fn(&[u8]) -> Option<(&[u8], u64)>;
+
+// Series key and end of the covered time span of a raw bucket
+fn parse_raw_bucket_key(key: &[u8]) -> Option<(&[u8], u64)> {
+    let (series_key, ts) = DB::_from_ts_key(key).ok()?;
+    Some((series_key, ts - ts % HOUR + HOUR))
+}
+
+// Series key and end of the covered time span of a rollup bucket, rollup states are skipped
+fn parse_rollup_key(key: &[u8]) -> Option<(&[u8], u64)> {
+    if is_rollup_state_key(key) {
+        return None;
+    }
+    parse_rollup_bucket_key(key)
+}
+
+impl DB {
+    /// Retention of a metric in seconds, `None` if its data is kept forever
+    pub fn metric_retention(
+        &self,
+        tenant_id: &TenantId,
+        device_id: &str,
+        metric_name: &str,
+    ) -> Result<Option<u64>, DatabaseError> {
+        // a corrupt config must not stop the retention of all other series
+        let data_config = match self.get_data_config(tenant_id, Some(device_id)) {
+            Ok(data_config) => data_config,
+            Err(e) => {
+                warn!(%tenant_id, device_id, error = %e, "Ignoring data config for retention");
+                None
+            }
+        };
+        let metric_retention = data_config
+            .and_then(|config| {
+                config
+                    .metrics
+                    .iter()
+                    .find(|m| m.matches_name(metric_name))
+                    .and_then(|m| m.retention)
+            });
+        Ok(metric_retention.or(self.retention.tenant_retention(tenant_id)))
+    }
+
+    /// True if a retention is configured or set for a metric in any data config
+    pub fn has_retention(&self) -> Result<bool, DatabaseError> {
+        if self.retention.is_configured() {
+            return Ok(true);
+        }
+        let (db, cf) = self._cf(CF_CONFIGS)?;
+        for item in db.iterator_cf(cf, rocksdb::IteratorMode::Start) {
+            let (key, value) = item?;
+            // corrupt configs fall back to the tenant retention
+            let config = match Self::_parse_data_config(&String::from_utf8_lossy(&key), &value) {
+                Ok(config) => config,
+                Err(_) => continue,
+            };
+            if config.metrics.iter().any(|m| m.retention.is_some()) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn _series_retention(&self, series_key: &[u8]) -> Result<Option<u64>, DatabaseError> {
+        let series_key = String::from_utf8_lossy(series_key);
+        let mut parts = series_key.splitn(3, '#');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(tenant), Some(device), Some(metric)) => {
+                self.metric_retention(&TenantId::new(tenant), device, metric)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Delete all time series buckets that lie completely before their retention
+    /// at `now` (microseconds), returns the number of deleted buckets
+    pub fn run_retention(&self, now: u64) -> Result<usize, DatabaseError> {
+        let mut retentions: HashMap<Vec<u8>, Option<u64>> = HashMap::new();
+        let mut deleted = 0;
+        let parsers: [(&str, BucketKeyParser); 2] =
+            [(CF_METRICS, parse_raw_bucket_key), (CF_ROLLUPS, parse_rollup_key)];
+        for (cf_name, parse_bucket_key) in parsers {
+            let (db, cf) = self._cf(cf_name)?;
+            for item in db.iterator_cf(cf, rocksdb::IteratorMode::Start) {
+                let (key, _) = item?;
+                let (series_key, bucket_end) = match parse_bucket_key(&key) {
+                    Some(bucket) => bucket,
+                    None => continue,
+                };
+                let retention = match retentions.get(series_key) {
+                    Some(retention) => *retention,
+                    None => {
+                        let retention = self._series_retention(series_key)?;
+                        retentions.insert(series_key.to_vec(), retention);
+                        retention
+                    }
+                };
+                if let Some(retention) = retention {
+                    let cutoff = now.saturating_sub(retention.saturating_mul(MICROS_PER_SECOND));
+                    if bucket_end <= cutoff {
+                        db.delete_cf(cf, &key)?;
+                        deleted += 1;
+                    }
+                }
+            }
+        }
+        debug!(deleted, "Applied retention");
+        Ok(deleted)
+    }
+}
+
+/// Periodically apply the retention in the background, the time series are only
+/// scanned once a retention is set
+pub fn start_retention_worker(db: Arc<DB>) -> tokio::task::JoinHandle<()> {
+    let interval = Duration::from_secs(db.retention.interval.max(1));
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let db = db.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                if !db.has_retention()? {
+                    return Ok(0);
+                }
+                db.run_retention(now_micros())
+            })
+            .await;
+            match result {
+                Ok(Err(e)) => warn!(error = ?e, "Retention failed"),
+                Err(e) => warn!(error = ?e, "Retention task failed"),
+                Ok(Ok(_)) => {}
+            }
+        }
+    })
+}
@@ -0,0 +1,513 @@
+//! Pre-aggregated minute/hour/day series for long-term metric storage.
+//!
+//! Every write marks its series as pending, a background worker periodically
+//! aggregates the pending hours of the raw data into minute and hour rollups,
+//! combines the hour rollups of their days into day rollups and deletes
+//! raw buckets older than the configured retention. Rollups are stored per
+//! resolution and aggregate under `rollup#{resolution}#{aggregate}#{series}`
+//! in the rollups column family.
+
+use super::columns::{CF_METRICS, CF_ROLLUPS};
+use super::{DatabaseError, DB};
+use crate::timeseries::{now_micros, Aggregation, MetricTimeSeries, MetricValue, MICROS_PER_SECOND};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+const MINUTE: u64 = 60 * MICROS_PER_SECOND;
+const HOUR: u64 = 60 * MINUTE;
+const DAY: u64 = 24 * HOUR;
+
+/// Longest range served from raw data while it is still retained
+const RAW_QUERY_MAX_SPAN: u64 = 6 * HOUR;
+const MINUTE_QUERY_MAX_SPAN: u64 = 2 * DAY;
+const HOUR_QUERY_MAX_SPAN: u64 = 90 * DAY;
+
+/// Aggregates stored for every rollup bucket, averages are derived from sum and count
+const STORED_AGGREGATIONS: [Aggregation; 6] = [
+    Aggregation::Min,
+    Aggregation::Max,
+    Aggregation::Sum,
+    Aggregation::Count,
+    Aggregation::First,
+    Aggregation::Last,
+];
+
+const STATE_PREFIX: &str = "rollup#series#";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RollupConfig {
+    pub enabled: bool,
+    /// Seconds between two rollup runs
+    pub interval: u64,
+    /// Raw points older than this many seconds are deleted, rollups are kept
+    pub raw_retention: Option<u64>,
+}
+
+impl Default for RollupConfig {
+    fn default() -> Self {
+        RollupConfig {
+            enabled: false,
+            interval: 300,
+            raw_retention: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    Raw,
+    Minute,
+    Hour,
+    Day,
+}
+
+impl Resolution {
+    /// Width of a single rollup point in microseconds
+    pub fn width(&self) -> u64 {
+        match self {
+            Resolution::Raw => 1,
+            Resolution::Minute => MINUTE,
+            Resolution::Hour => HOUR,
+            Resolution::Day => DAY,
+        }
+    }
+
+    // Time span stored under a single database key
+    fn container_width(&self) -> u64 {
+        match self {
+            Resolution::Raw | Resolution::Minute => HOUR,
+            Resolution::Hour => DAY,
+            Resolution::Day => 30 * DAY,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Resolution::Raw => "raw",
+            Resolution::Minute => "m",
+            Resolution::Hour => "h",
+            Resolution::Day => "d",
+        }
+    }
+}
+
+/// Series key and end of the covered time span of a stored rollup bucket
+pub(super) fn parse_rollup_bucket_key(key: &[u8]) -> Option<(&[u8], u64)> {
+    let rest = key.strip_prefix(b"rollup#")?;
+    let resolution = [Resolution::Minute, Resolution::Hour, Resolution::Day]
+        .into_iter()
+        .find(|r| rest.starts_with(format!("{}#", r.name()).as_bytes()))?;
+    let rest = &rest[resolution.name().len() + 1..];
+    // skip the aggregate
+    let sep = rest.iter().position(|b| *b == b'#')?;
+    let (series_key, ts) = DB::_from_ts_key(&rest[sep + 1..]).ok()?;
+    Some((series_key, ts + resolution.container_width()))
+}
+
+// Range of raw data that still needs to be rolled up and the start of the
+// raw data that is still complete. `writes` counts the writes to the series, a
+// rollup run only clears the pending range if no write happened in the meantime.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(super) struct RollupState {
+    pub(super) pending: Option<(u64, u64)>,
+    #[serde(default)]
+    pub(super) retained_from: u64,
+    #[serde(default)]
+    pub(super) writes: u64,
+}
+
+pub(super) fn is_rollup_state_key(key: &[u8]) -> bool {
+    key.starts_with(STATE_PREFIX.as_bytes())
+}
+
+// Merge operator for rollup states: pending ranges are combined, write counts are
+// summed and the retained start only moves forward. States written by a rollup run are plain puts and
+// replace all earlier operands.
+pub(super) fn merge_rollup_states<'a>(values: impl Iterator<Item = &'a [u8]>) -> Vec<u8> {
+    let mut merged = RollupState::default();
+    for value in values {
+        let state: RollupState = serde_json::from_slice(value).unwrap_or_default();
+        merged.pending = match (merged.pending, state.pending) {
+            (Some((from, to)), Some((other_from, other_to))) => {
+                Some((from.min(other_from), to.max(other_to)))
+            }
+            (pending, other) => pending.or(other),
+        };
+        merged.retained_from = merged.retained_from.max(state.retained_from);
+        merged.writes = merged.writes.wrapping_add(state.writes);
+    }
+    serde_json::to_vec(&merged).unwrap_or_default()
+}
+
+fn align(ts: u64, width: u64) -> u64 {
+    ts - ts % width
+}
+
+// Pointwise sum / count, series without sums (e.g. locations, strings) fall back to `last`
+fn divide_series(sum: &MetricTimeSeries, count: &MetricTimeSeries) -> MetricTimeSeries {
+    let mut avg = MetricTimeSeries::new();
+    for (ts, value) in sum.iter() {
+        let count = count
+            .get_value_for_timestamp(ts)
+            .and_then(|c| c.clone().into_float());
+        if let (Some(sum), Some(count)) = (value.clone().into_float(), count) {
+            if count > 0.0 {
+                avg.add_point(ts, MetricValue::Float(sum / count));
+            }
+        }
+    }
+    avg
+}
+
+impl RollupConfig {
+    fn raw_cutoff(&self, now: u64) -> Option<u64> {
+        // whole days only, so day rollups are never computed from partial raw data
+        self.raw_retention
+            .map(|retention| align(now.saturating_sub(retention.saturating_mul(MICROS_PER_SECOND)), DAY))
+    }
+
+    /// Picks the resolution for a query so that long ranges do not return raw points
+    pub fn resolution_for_range(&self, start: u64, end: u64, now: u64) -> Resolution {
+        if !self.enabled {
+            return Resolution::Raw;
+        }
+        let span = end.saturating_sub(start);
+        let raw_available = self.raw_cutoff(now).is_none_or(|cutoff| start >= cutoff);
+        if raw_available && span <= RAW_QUERY_MAX_SPAN {
+            Resolution::Raw
+        } else if span <= MINUTE_QUERY_MAX_SPAN {
+            Resolution::Minute
+        } else if span <= HOUR_QUERY_MAX_SPAN {
+            Resolution::Hour
+        } else {
+            Resolution::Day
+        }
+    }
+}
+
+impl DB {
+    fn _to_rollup_key(series_key: &[u8], resolution: Resolution, aggregation: Aggregation) -> Vec<u8> {
+        let prefix = format!("rollup#{}#{}#", resolution.name(), aggregation);
+        [prefix.as_bytes(), series_key].concat()
+    }
+
+    pub(super) fn _to_rollup_state_key(series_key: &[u8]) -> Vec<u8> {
+        [STATE_PREFIX.as_bytes(), series_key].concat()
+    }
+
+    // Extend the pending range of a series, added to the write batch of the data.
+    // The state is combined by the merge operator, the batch does not read it.
+    pub(super) fn _mark_rollup_pending(
+        batch: &mut rocksdb::WriteBatchWithTransaction<true>,
+        cf: &rocksdb::ColumnFamily,
+        series_key: &[u8],
+        from: u64,
+        to: u64,
+    ) -> Result<(), DatabaseError> {
+        let state_key = Self::_to_rollup_state_key(series_key);
+        let state = RollupState {
+            pending: Some((from, to)),
+            retained_from: 0,
+            writes: 1,
+        };
+        let data = serde_json::to_vec(&state)
+            .map_err(|e| DatabaseError::DatabaseValueError(e.to_string()))?;
+        batch.merge_cf(cf, &state_key, data);
+        Ok(())
+    }
+
+    /// Aggregate all pending raw data and apply the raw retention at `now` (microseconds)
+    pub fn run_rollups(&self, now: u64) -> Result<(), DatabaseError> {
+        let (db, cf) = self._cf(CF_ROLLUPS)?;
+        let mut states = Vec::new();
+        let iter = db.iterator_cf(cf, rocksdb::IteratorMode::From(
+            STATE_PREFIX.as_bytes(),
+            rocksdb::Direction::Forward,
+        ));
+        for item in iter {
+            let (key, value) = item?;
+            if !key.starts_with(STATE_PREFIX.as_bytes()) {
+                break;
+            }
+            let state: RollupState = serde_json::from_slice(&value).unwrap_or_default();
+            states.push((key[STATE_PREFIX.len()..].to_vec(), state));
+        }
+
+        let cutoff = self.rollup.raw_cutoff(now);
+        for (series_key, state) in states {
+            if let Some((from, to)) = state.pending {
+                self._rollup_series(&series_key, from, to, state.retained_from)?;
+            }
+            if let Some(cutoff) = cutoff {
+                self._delete_raw_before(&series_key, cutoff)?;
+            }
+            self._finish_rollup(&series_key, &state, cutoff)?;
+        }
+        Ok(())
+    }
+
+    fn _rollup_series(
+        &self,
+        series_key: &[u8],
+        from: u64,
+        to: u64,
+        retained_from: u64,
+    ) -> Result<(), DatabaseError> {
+        // recompute the hours of the pending range, late points for hours whose raw
+        // data was already deleted are not rolled up as the hour would be incomplete
+        let start = align(from, HOUR).max(retained_from);
+        let end = align(to, HOUR) + HOUR - 1;
+        if start > end {
+            return Ok(());
+        }
+        let raw = self._get_timeseries(series_key, start, end)?;
+
+        let mut buckets = Vec::new();
+        for resolution in [Resolution::Minute, Resolution::Hour] {
+            for aggregation in STORED_AGGREGATIONS {
+                // e.g. min/max of locations and strings, those only support count, first and last
+                if let Ok(rollup) = raw.aggregate(resolution.width(), aggregation) {
+                    Self::_push_rollup_buckets(&mut buckets, series_key, resolution, aggregation, &rollup);
+                }
+            }
+        }
+        let hour_buckets = buckets.len();
+        self._upsert_timeseries_buckets(CF_ROLLUPS, buckets, &[])?;
+
+        // days are combined from their hour rollups, those are kept when the raw data is deleted
+        let (day_start, day_end) = (align(start, DAY), align(end, DAY) + DAY - 1);
+        let mut buckets = Vec::new();
+        for aggregation in STORED_AGGREGATIONS {
+            let hours = self._get_rollup(series_key, Resolution::Hour, aggregation, day_start, day_end)?;
+            let combine = match aggregation {
+                Aggregation::Count => Aggregation::Sum,
+                aggregation => aggregation,
+            };
+            if let Ok(rollup) = hours.aggregate(DAY, combine) {
+                Self::_push_rollup_buckets(&mut buckets, series_key, Resolution::Day, aggregation, &rollup);
+            }
+        }
+        debug!(
+            series = %String::from_utf8_lossy(series_key),
+            buckets = hour_buckets + buckets.len(),
+            "Rolled up series"
+        );
+        self._upsert_timeseries_buckets(CF_ROLLUPS, buckets, &[])
+    }
+
+    // Split a rollup into the buckets stored under a single key each
+    fn _push_rollup_buckets(
+        buckets: &mut Vec<(Vec<u8>, MetricTimeSeries)>,
+        series_key: &[u8],
+        resolution: Resolution,
+        aggregation: Aggregation,
+        rollup: &MetricTimeSeries,
+    ) {
+        let rollup_key = Self::_to_rollup_key(series_key, resolution, aggregation);
+        let mut container: Option<(u64, MetricTimeSeries)> = None;
+        for (ts, value) in rollup.iter() {
+            let container_start = align(ts, resolution.container_width());
+            match &mut container {
+                Some((start, series)) if *start == container_start => {
+                    series.add_point(ts, value.clone());
+                }
+                _ => {
+                    if let Some((start, series)) = container.take() {
+                        buckets.push((DB::_to_ts_key(&rollup_key, start), series));
+                    }
+                    container = Some((container_start, value.as_timeseries(ts)));
+                }
+            }
+        }
+        if let Some((start, series)) = container {
+            buckets.push((DB::_to_ts_key(&rollup_key, start), series));
+        }
+    }
+
+    // Clear the pending range unless new data was written in the meantime
+    pub(super) fn _finish_rollup(
+        &self,
+        series_key: &[u8],
+        processed: &RollupState,
+        cutoff: Option<u64>,
+    ) -> Result<(), DatabaseError> {
+        let (db, cf) = self._cf(CF_ROLLUPS)?;
+        let state_key = Self::_to_rollup_state_key(series_key);
+        let txn = db.transaction();
+        let mut state: RollupState = match txn.get_for_update_cf(cf, &state_key, false)? {
+            Some(data) => serde_json::from_slice(&data).unwrap_or_default(),
+            None => RollupState::default(),
+        };
+        // a write within the processed range leaves the range unchanged, only the
+        // write count tells whether it was included in the rollup
+        if state.pending == processed.pending && state.writes == processed.writes {
+            state.pending = None;
+        }
+        if let Some(cutoff) = cutoff {
+            state.retained_from = state.retained_from.max(cutoff);
+        }
+        if state == *processed {
+            return Ok(());
+        }
+        let data = serde_json::to_vec(&state)
+            .map_err(|e| DatabaseError::DatabaseValueError(e.to_string()))?;
+        txn.put_cf(cf, &state_key, data)?;
+        // a conflicting write keeps the series pending for the next run
+        if let Err(e) = txn.commit() {
+            debug!(error = ?e, "Rollup state changed during rollup");
+        }
+        Ok(())
+    }
+
+    // Delete raw hourly buckets that end before the cutoff
+    fn _delete_raw_before(&self, series_key: &[u8], cutoff: u64) -> Result<(), DatabaseError> {
+        let (db, cf) = self._cf(CF_METRICS)?;
+        let prefix = [series_key, b"#"].concat();
+        let start_key = DB::_to_ts_key(series_key, cutoff.saturating_sub(HOUR));
+        let iter = db.iterator_cf(cf, rocksdb::IteratorMode::From(
+            &start_key,
+            rocksdb::Direction::Forward,
+        ));
+        for item in iter {
+            let (key, _) = item?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            let (_, bucket_ts) = DB::_from_ts_key(&key)?;
+            if bucket_ts + HOUR <= cutoff {
+                db.delete_cf(cf, &key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn _get_rollup(
+        &self,
+        series_key: &[u8],
+        resolution: Resolution,
+        aggregation: Aggregation,
+        start: u64,
+        end: u64,
+    ) -> Result<MetricTimeSeries, DatabaseError> {
+        let read = |aggregation: Aggregation| {
+            let key = Self::_to_rollup_key(series_key, resolution, aggregation);
+            let mut series =
+                self._get_timeseries_cf(CF_ROLLUPS, &key, align(start, resolution.container_width()), end)?;
+            series.trim(align(start, resolution.width()), end);
+            Ok::<_, DatabaseError>(series)
+        };
+        match aggregation {
+            Aggregation::Avg => {
+                let sum = read(Aggregation::Sum)?;
+                if sum.is_empty() {
+                    return read(Aggregation::Last);
+                }
+                Ok(divide_series(&sum, &read(Aggregation::Count)?))
+            }
+            _ => read(aggregation),
+        }
+    }
+
+    /// Series at the resolution that fits the requested range
+    pub(super) fn _get_metric_series(
+        &self,
+        series_key: &[u8],
+        start: u64,
+        end: u64,
+    ) -> Result<MetricTimeSeries, DatabaseError> {
+        let now = now_micros();
+        match self.rollup.resolution_for_range(start, end, now) {
+            Resolution::Raw => self._get_timeseries(series_key, start, end),
+            resolution => self._get_rollup(series_key, resolution, Aggregation::Avg, start, end),
+        }
+    }
+
+    /// Aggregated series, served from the coarsest rollup that divides the bucket width
+    pub(super) fn _get_metric_aggregated(
+        &self,
+        series_key: &[u8],
+        start: u64,
+        end: u64,
+        bucket_width: u64,
+        aggregation: Aggregation,
+    ) -> Result<MetricTimeSeries, DatabaseError> {
+        let now = now_micros();
+        let resolution = match self.rollup.resolution_for_range(start, end, now) {
+            Resolution::Raw => None,
+            _ => [Resolution::Day, Resolution::Hour, Resolution::Minute]
+                .into_iter()
+                .find(|r| bucket_width % r.width() == 0),
+        };
+        let resolution = match resolution {
+            Some(resolution) => resolution,
+            None => {
+                let raw = self._get_timeseries(series_key, start, end)?;
+                return Ok(raw.aggregate(bucket_width, aggregation)?);
+            }
+        };
+
+        let series = match aggregation {
+            Aggregation::Avg => {
+                let sum = self
+                    ._get_rollup(series_key, resolution, Aggregation::Sum, start, end)?
+                    .aggregate(bucket_width, Aggregation::Sum)?;
+                let count = self
+                    ._get_rollup(series_key, resolution, Aggregation::Count, start, end)?
+                    .aggregate(bucket_width, Aggregation::Sum)?;
+                if sum.is_empty() && !count.is_empty() {
+                    // no sums are stored for locations and strings
+                    return Err(DatabaseError::AggregationError(
+                        crate::timeseries::AggregationError::UnsupportedAggregation(aggregation),
+                    ));
+                }
+                divide_series(&sum, &count)
+            }
+            Aggregation::Count => self
+                ._get_rollup(series_key, resolution, Aggregation::Count, start, end)?
+                .aggregate(bucket_width, Aggregation::Sum)?,
+            _ => {
+                let rollup = self._get_rollup(series_key, resolution, aggregation, start, end)?;
+                if rollup.is_empty() {
+                    let count =
+                        self._get_rollup(series_key, resolution, Aggregation::Count, start, end)?;
+                    if !count.is_empty() {
+                        return Err(DatabaseError::AggregationError(
+                            crate::timeseries::AggregationError::UnsupportedAggregation(
+                                aggregation,
+                            ),
+                        ));
+                    }
+                }
+                rollup.aggregate(bucket_width, aggregation)?
+            }
+        };
+        Ok(series)
+    }
+}
+
+/// Periodically run the rollups in the background
+pub fn start_rollup_worker(db: Arc<DB>) -> Option<tokio::task::JoinHandle<()>> {
+    if !db.rollup.enabled {
+        return None;
+    }
+    let interval = Duration::from_secs(db.rollup.interval.max(1));
+    Some(tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let db = db.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                db.run_rollups(now_micros())
+            })
+            .await;
+            match result {
+                Ok(Err(e)) => warn!(error = ?e, "Rollup failed"),
+                Err(e) => warn!(error = ?e, "Rollup task failed"),
+                Ok(Ok(_)) => {}
+            }
+        }
+    }))
+}
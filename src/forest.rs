@@ -5,11 +5,12 @@ use std::sync::Arc;
 
 use forest::config::ForestConfig;
 use forest::db::DB;
-use forest::models::TenantId;
+use forest::models::{is_valid_tenant_id, to_client_id, TenantId};
 use forest::server::start_server;
 use forest::cli::{Cli, Commands};
 use forest::api::client::create_backup;
 use forest::api::services::create_device as create_device_api;
+use forest::api::services::{enroll_device as enroll_device_api, revoke_device_certificate};
 use forest::certs::CertificateManager;
 use tokio::runtime::Runtime;
 use tracing::Level;
@@ -50,15 +51,15 @@ fn main() {
     // Print Config
     tracing::info!("Config: {}", serde_json::to_string_pretty(&config).unwrap());
 
-    // Print Tenant Warnings
+    // Set Tenant (used for device commands, the server always handles all tenants)
     if let Some(tenant) = &cli.tenant {
-        tracing::warn!("Set Tenant: {}", tenant);
-        tracing::warn!("Tenant is not implemented yet");
-        config.tenant_id = None;
-    } else {
-        if !config.tenant_id.is_none() {
-            tracing::warn!("Tenant is not implemented yet");
-            config.tenant_id = None;
+        tracing::info!("Set Tenant: {}", tenant);
+        config.tenant_id = Some(tenant.clone());
+    }
+    if let Some(tenant) = &config.tenant_id {
+        if !is_valid_tenant_id(tenant) {
+            tracing::error!("Invalid tenant id: {}", tenant);
+            return;
         }
     }
 
@@ -92,6 +93,12 @@ fn main() {
         Commands::CreateDevice { device_id } => {
             create_device(device_id, config);
         },
+        Commands::EnrollDevice { device_id, csr } => {
+            enroll_device(device_id, csr, config);
+        },
+        Commands::RevokeCertificate { device_id, serial, reason } => {
+            revoke_certificate(device_id.as_deref(), serial.as_deref(), reason.as_deref(), config);
+        },
     }
 }
 
@@ -128,9 +135,9 @@ fn run_create_backup(rt: Runtime, config: ForestConfig) {
 }
 
 fn get_certificate_manager(config: &ForestConfig) -> CertificateManager {
-    let tenant_id = config.tenant_id.clone();
-    let cert_manager = match CertificateManager::new(&config.cert_dir, tenant_id) {
-        Ok(manager) => manager,
+    // all tenants share the CA of the broker, the tenant is encoded in the client certificates
+    let cert_manager = match CertificateManager::new(&config.cert_dir, None) {
+        Ok(manager) => manager.with_config(&config.certificates),
         Err(e) => {
             tracing::error!("Failed to create certificate manager: {}", e);
             panic!("Failed to create certificate manager");
@@ -174,12 +181,12 @@ fn create_device(device_id: &str, config: ForestConfig) {
     let tenant = TenantId::from_option(tenant_id);
 
     match create_device_api(device_id, &tenant, db, cert_manager) {
-        Ok(device) => {
+        Ok((device, key)) => {
             tracing::info!("Device successfully created");
             println!("\nDevice ID: \n{}", device.device_id);
-            if let Some(key) = &device.key {
-                println!("\nDevice Key: \n{}", key);
-            }
+            println!("\nClient ID: \n{}", to_client_id(&device.tenant_id, &device.device_id));
+            // the key is not stored, it is only shown here
+            println!("\nDevice Key: \n{}", key);
             if let Some(cert) = &device.certificate {
                 println!("\nDevice Cert: \n{}", cert);
             }
@@ -189,3 +196,77 @@ fn create_device(device_id: &str, config: ForestConfig) {
         },
     }
 }
+
+fn enroll_device(device_id: &str, csr_path: &PathBuf, config: ForestConfig) {
+    println!("Enrolling device: {}", device_id);
+
+    let csr = match std::fs::read_to_string(csr_path) {
+        Ok(csr) => csr,
+        Err(e) => {
+            tracing::error!("Failed to read CSR {}: {}", csr_path.display(), e);
+            return;
+        }
+    };
+    let cert_manager = Arc::new(get_certificate_manager(&config));
+    let db_path = PathBuf::from(&config.database.path);
+    let db = match DB::open_default(db_path.to_str().unwrap()) {
+        Ok(db) => Arc::new(db),
+        Err(e) => {
+            panic!("Failed to open RocksDB: {:?}", e);
+        }
+    };
+
+    let tenant = TenantId::from_option(config.tenant_id.as_deref());
+
+    match enroll_device_api(device_id, &tenant, &csr, db, cert_manager) {
+        Ok(device) => {
+            tracing::info!("Device successfully enrolled");
+            println!("\nDevice ID: \n{}", device.device_id);
+            println!("\nClient ID: \n{}", to_client_id(&device.tenant_id, &device.device_id));
+            if let Some(cert) = &device.certificate {
+                println!("\nDevice Cert: \n{}", cert);
+            }
+        },
+        Err(e) => {
+            tracing::error!("Failed to enroll device: {}", e);
+        },
+    }
+}
+
+fn revoke_certificate(device_id: Option<&str>, serial: Option<&str>, reason: Option<&str>, config: ForestConfig) {
+    let cert_manager = Arc::new(get_certificate_manager(&config));
+
+    let result = match (device_id, serial) {
+        (Some(device_id), _) => {
+            println!("Revoking certificate of device: {}", device_id);
+            let db_path = PathBuf::from(&config.database.path);
+            let db = match DB::open_default(db_path.to_str().unwrap()) {
+                Ok(db) => Arc::new(db),
+                Err(e) => {
+                    panic!("Failed to open RocksDB: {:?}", e);
+                }
+            };
+            let tenant = TenantId::from_option(config.tenant_id.as_deref());
+            revoke_device_certificate(device_id, &tenant, db, cert_manager, reason)
+                .map_err(|e| e.to_string())
+        },
+        (None, Some(serial)) => {
+            println!("Revoking certificate: {}", serial);
+            cert_manager.revoke_serial(serial, reason).map_err(|e| e.to_string())
+        },
+        (None, None) => Err("Either a device id or a serial number is required".to_string()),
+    };
+
+    match result {
+        Ok(revoked) => {
+            tracing::info!("Certificate successfully revoked");
+            println!("\nSerial: \n{}", revoked.serial);
+            if let Some(common_name) = &revoked.common_name {
+                println!("\nCommon Name: \n{}", common_name);
+            }
+        },
+        Err(e) => {
+            tracing::error!("Failed to revoke certificate: {}", e);
+        },
+    }
+}
@@ -73,13 +73,58 @@ impl DefaultString {
 pub type ShadowName = DefaultString;
 pub type TenantId = DefaultString;
 
+/// Tenant ids end up in mqtt client ids (`tenant.device`), database keys (`#` separated)
+/// and certificate organizations, so only alphanumerics, `-` and `_` are allowed.
+pub fn is_valid_tenant_id(tenant_id: &str) -> bool {
+    !tenant_id.is_empty()
+        && tenant_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Split a mqtt client id of the form `tenant.device` into tenant and device id.
+/// Client ids without a tenant part belong to the default tenant.
+pub fn split_client_id(client_id: &str) -> (TenantId, String) {
+    match client_id.split_once('.') {
+        Some((tenant_str, device_id)) => (TenantId::from_str(tenant_str), device_id.to_string()),
+        None => (TenantId::Default, client_id.to_string()),
+    }
+}
+
+/// Build the mqtt client id for a device, this is the inverse of `split_client_id`
+pub fn to_client_id(tenant_id: &TenantId, device_id: &str) -> String {
+    match tenant_id {
+        TenantId::Default => device_id.to_string(),
+        TenantId::Custom(tenant) => format!("{}.{}", tenant, device_id),
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeviceMetadata {
     pub device_id: String,
     pub tenant_id: TenantId,
     pub certificate: Option<String>,
-    pub key: Option<String>,
     pub created_at: u64,
+    /// Disabled devices are rejected by the mqtt broker
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Hash of the mqtt password, devices without one need a client certificate
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<String>,
+    /// Certificate replaced by the last renewal, valid until its grace period ends
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_certificate: Option<PreviousCertificate>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PreviousCertificate {
+    pub certificate: String,
+    /// Unix timestamp in seconds, the certificate is revoked afterwards
+    pub valid_until: u64,
+}
+
+fn default_enabled() -> bool {
+    true
 }
 
 // Add this struct to your models.rs file
@@ -92,20 +137,55 @@ pub struct DeviceInformation {
     pub last_shadow_update: Option<u64>,
 }
 
+/// Device metadata as returned by the api, without the password hash
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceResponse {
+    pub device_id: String,
+    pub tenant_id: TenantId,
+    pub certificate: Option<String>,
+    pub created_at: u64,
+    pub enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_certificate: Option<PreviousCertificate>,
+}
+
+impl From<DeviceMetadata> for DeviceResponse {
+    fn from(metadata: DeviceMetadata) -> Self {
+        Self {
+            device_id: metadata.device_id,
+            tenant_id: metadata.tenant_id,
+            certificate: metadata.certificate,
+            created_at: metadata.created_at,
+            enabled: metadata.enabled,
+            previous_certificate: metadata.previous_certificate,
+        }
+    }
+}
+
+/// Response to the creation of a device, the generated private key is not
+/// stored and only returned here
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreatedDeviceResponse {
+    #[serde(flatten)]
+    pub device: DeviceResponse,
+    pub key: String,
+}
+
 impl DeviceMetadata {
     pub fn new(device_id: &str, tenant_id: &TenantId) -> Self {
         Self {
             device_id: device_id.to_string(),
             tenant_id: tenant_id.to_owned(),
             certificate: None,
-            key: None,
             created_at: chrono::Utc::now().timestamp() as u64,
+            enabled: true,
+            password_hash: None,
+            previous_certificate: None,
         }
     }
     
-    pub fn with_credentials(mut self, certificate: String, key: String) -> Self {
+    pub fn with_certificate(mut self, certificate: String) -> Self {
         self.certificate = Some(certificate);
-        self.key = Some(key);
         self
     }
 }
\ No newline at end of file
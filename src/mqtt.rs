@@ -1,3 +1,6 @@
+pub mod acl;
+pub mod auth;
+
 pub use rumqttd::local::{LinkError, LinkRx, LinkTx};
 use rumqttd::meters::MetersLink;
 use rumqttd::Meter::Router;
@@ -14,7 +17,12 @@ use tokio::select;
 use tokio::sync::broadcast::{Receiver, Sender};
 use tokio::task::JoinSet;
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error, info_span, warn, info};
+use tracing::{debug, error, warn, info};
+
+use acl::AclConfig;
+#[cfg(feature = "broker-acl")]
+use acl::{Acl, AclAction};
+use auth::{Authenticator, DeviceRegistry};
 
 pub const DEFAULT_CONFIG: &str = r#"{
   "id": 0,
@@ -97,6 +105,9 @@ pub struct MqttConfig {
     pub bind_v3: String,
     pub bind_v5: String,
     pub bind_ws: Option<String>,
+    /// Topic authorization of the clients
+    #[serde(default)]
+    pub acl: AclConfig,
 }
 
 impl Default for MqttConfig {
@@ -111,6 +122,7 @@ impl Default for MqttConfig {
             bind_v3: "127.0.0.1:1883".to_string(),
             bind_v5: "127.0.0.1:1884".to_string(),
             bind_ws: None,
+            acl: AclConfig::default(),
         }
     }
 }
@@ -402,25 +414,9 @@ async fn start_event_handlers(
     }
 }
 
-async fn auth(
-    client_id: String,
-    username: String,
-    _password: String,
-    common_name: String,
-    organization: String,
-) -> bool {
-    let _span = info_span!("authentication", client_id = %client_id, username = %username, common_name = %common_name, organization = %organization).entered();
-    // we can do auth on username and password or on common_name (from client certificate)
-    // if we have a common_name we need to check that it matches the client_id
-    if !common_name.is_empty() && client_id != common_name {
-        warn!("Client ID does not match certificate common name");
-        return false;
-    }
-
-    true
-}
-
-pub async fn start_broker(mqtt_config: Option<MqttConfig>) -> MqttServer {
+/// Start the broker, clients are authenticated against the device `registry`
+/// if one is given
+pub async fn start_broker(mqtt_config: Option<MqttConfig>, registry: Option<DeviceRegistry>) -> MqttServer {
     let mut config = get_default_config();
 
     let mqtt_config = match mqtt_config {
@@ -465,8 +461,42 @@ pub async fn start_broker(mqtt_config: Option<MqttConfig>) -> MqttServer {
         .expect("Invalid v5_listen address");
     server_v5.listen = v5_socket_addr;
 
-    server_v3.set_auth_handler(auth);
-    server_v5.set_auth_handler(auth);
+    // brokers with the `broker-peer-serial` feature pass the serial of the client
+    // certificate, so revoked certificates are refused even after renewal
+    let authenticator = Arc::new(Authenticator::new(registry));
+    #[cfg(feature = "broker-peer-serial")]
+    let auth = move |client_id, username, password, common_name, organization, serial: String| {
+        let serial = Some(serial).filter(|serial| !serial.is_empty());
+        authenticator.clone().authenticate(client_id, username, password, common_name, organization, serial)
+    };
+    #[cfg(not(feature = "broker-peer-serial"))]
+    let auth = move |client_id, username, password, common_name, organization| {
+        authenticator.clone().authenticate(client_id, username, password, common_name, organization, None)
+    };
+    server_v3.set_auth_handler(auth.clone());
+    server_v5.set_auth_handler(auth.clone());
+
+    // publishes and subscriptions are checked against the topic rules of the client,
+    // this needs a broker build with an acl hook (`broker-acl` feature)
+    #[cfg(feature = "broker-acl")]
+    let acl_handler = {
+        let acl = Arc::new(Acl::new(mqtt_config.acl.clone()));
+        let acl_handler = move |client_id: &str, topic: &str, subscribe: bool| {
+            let action = if subscribe {
+                AclAction::Subscribe
+            } else {
+                AclAction::Publish
+            };
+            acl.is_allowed(client_id, action, topic)
+        };
+        server_v3.set_acl_handler(acl_handler.clone());
+        server_v5.set_acl_handler(acl_handler.clone());
+        acl_handler
+    };
+    #[cfg(not(feature = "broker-acl"))]
+    if mqtt_config.acl.enabled {
+        panic!("Topic ACLs are enabled but cannot be enforced, the broker is built without the broker-acl feature");
+    }
 
     //  Enable or disable websockets
     if let Some(ws) = mqtt_config.bind_ws {
@@ -481,6 +511,8 @@ pub async fn start_broker(mqtt_config: Option<MqttConfig>) -> MqttServer {
             });
         }
         ws_server.set_auth_handler(auth);
+        #[cfg(feature = "broker-acl")]
+        ws_server.set_acl_handler(acl_handler);
     }
     else {
         let ws = config.ws.as_mut();
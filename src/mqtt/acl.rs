@@ -0,0 +1,132 @@
+//! Topic authorization of mqtt clients.
+//!
+//! The topics a client may use are derived from its client id: rules are topic
+//! filters where `{client_id}`, `{tenant}` and `{device}` are replaced with the
+//! parts of the client id. By default a device may only use the topics below
+//! `things/{client_id}/` and subscribe to the public topics. Backend service
+//! clients get additional rules by their client id.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::warn;
+
+use crate::models::split_client_id;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AclAction {
+    Publish,
+    Subscribe,
+}
+
+/// Topic filters a client may publish and subscribe to
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AclRules {
+    pub publish: Vec<String>,
+    pub subscribe: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AclConfig {
+    /// Enabled by default in brokers built with the `broker-acl` feature, the broker
+    /// refuses to start with enabled ACLs it cannot enforce
+    pub enabled: bool,
+    /// Rules of every client, the topic prefix has to match the `shadow_topic_prefix`
+    /// of the processor
+    pub devices: AclRules,
+    /// Additional rules of backend service clients by client id
+    pub clients: HashMap<String, AclRules>,
+}
+
+impl Default for AclConfig {
+    fn default() -> Self {
+        AclConfig {
+            enabled: cfg!(feature = "broker-acl"),
+            devices: AclRules {
+                publish: vec!["things/{client_id}/#".to_string()],
+                subscribe: vec!["things/{client_id}/#".to_string(), "public/#".to_string()],
+            },
+            clients: HashMap::new(),
+        }
+    }
+}
+
+// Wildcards in a client id would widen its rules, e.g. `acme.#`
+fn is_valid_client_id(client_id: &str) -> bool {
+    !client_id.is_empty() && !client_id.contains(['+', '#', '/'])
+}
+
+fn render_rule(rule: &str, client_id: &str) -> String {
+    let (tenant_id, device_id) = split_client_id(client_id);
+    rule.replace("{client_id}", client_id)
+        .replace("{tenant}", &tenant_id.to_string())
+        .replace("{device}", &device_id)
+}
+
+/// True if the topic is matched by the filter
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    if topic.contains(['+', '#']) {
+        return false;
+    }
+    filter_covers(filter, topic)
+}
+
+/// True if every topic matched by `requested` is also matched by `allowed`
+pub fn filter_covers(allowed: &str, requested: &str) -> bool {
+    let mut allowed_levels = allowed.split('/');
+    let mut requested_levels = requested.split('/');
+    loop {
+        match (allowed_levels.next(), requested_levels.next()) {
+            // `a/#` also matches `a`
+            (Some("#"), _) => return allowed_levels.next().is_none(),
+            (Some(_), Some("#")) => return false,
+            (Some("+"), Some(_)) => continue,
+            (Some(_), Some("+")) => return false,
+            (Some(a), Some(r)) if a == r => continue,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+pub struct Acl {
+    config: AclConfig,
+}
+
+impl Acl {
+    pub fn new(config: AclConfig) -> Self {
+        Acl { config }
+    }
+
+    fn rules<'a>(&'a self, client_id: &str) -> impl Iterator<Item = &'a AclRules> {
+        std::iter::once(&self.config.devices).chain(self.config.clients.get(client_id))
+    }
+
+    /// Check whether a client may publish to a topic or subscribe to a filter
+    pub fn is_allowed(&self, client_id: &str, action: AclAction, topic: &str) -> bool {
+        if !self.config.enabled {
+            return true;
+        }
+        if !is_valid_client_id(client_id) {
+            return false;
+        }
+        let allowed = self.rules(client_id).any(|rules| {
+            let filters = match action {
+                AclAction::Publish => &rules.publish,
+                AclAction::Subscribe => &rules.subscribe,
+            };
+            filters.iter().any(|rule| {
+                let filter = render_rule(rule, client_id);
+                match action {
+                    AclAction::Publish => topic_matches(&filter, topic),
+                    AclAction::Subscribe => filter_covers(&filter, topic),
+                }
+            })
+        });
+        if !allowed {
+            warn!(client_id, topic, action = ?action, "Topic not authorized");
+        }
+        allowed
+    }
+}
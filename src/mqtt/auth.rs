@@ -0,0 +1,381 @@
+//! Authentication of mqtt clients against the device registry.
+//!
+//! Clients connect with their client id `tenant.device` (just `device` for the
+//! default tenant). A registered and enabled device authenticates either with
+//! its client certificate, whose common name and organization have to match the
+//! client id and tenant, or with the client id as username and its password.
+//! Passwords are stored as salted PBKDF2 hashes in the device metadata.
+//! Certificate logins are refused once the presented certificate has been
+//! revoked. Brokers that do not pass its serial cannot tell the certificates of
+//! a client id apart, once one of them has been revoked or the grace period of
+//! a replaced one has ended all are refused and the device has to log in with
+//! its password. Repeated failed password logins lock
+//! a client id out for a growing time, certificate logins are never throttled.
+
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::rand::rand_bytes;
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tracing::{info_span, warn};
+
+use crate::certs::{tenant_from_organization, CertificateError, CertificateManager};
+use crate::db::DB;
+use crate::models::{is_valid_tenant_id, split_client_id, DeviceMetadata, TenantId};
+
+const HASH_SCHEME: &str = "pbkdf2_sha256";
+const HASH_ITERATIONS: usize = 100_000;
+const SALT_LENGTH: usize = 16;
+const HASH_LENGTH: usize = 32;
+
+/// Failed logins of a client id before it is locked out
+const MAX_FAILED_LOGINS: u32 = 5;
+const MAX_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+/// Number of tracked client ids before expired lockouts are removed
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum AuthError {
+    #[error("Client ID does not match certificate common name")]
+    CommonNameMismatch,
+    #[error("Client ID contains an invalid tenant")]
+    InvalidTenant,
+    #[error("Client tenant does not match certificate organization")]
+    OrganizationMismatch,
+    #[error("Device is not registered")]
+    UnknownDevice,
+    #[error("Device is disabled")]
+    DeviceDisabled,
+    #[error("Username does not match client ID")]
+    UsernameMismatch,
+    #[error("Invalid credentials")]
+    InvalidCredentials,
+    #[error("Device has no registered certificate")]
+    UnknownCertificate,
+    #[error("Certificate has been revoked")]
+    CertificateRevoked,
+    #[error("Certificate has been replaced")]
+    CertificateSuperseded,
+    #[error("Certificate cannot be told apart from a revoked one without its serial")]
+    AmbiguousCertificate,
+    #[error("Device registry error: {0}")]
+    RegistryError(String),
+}
+
+/// The device metadata and the revocation list of the issuing CA
+#[derive(Clone)]
+pub struct DeviceRegistry {
+    pub db: Arc<DB>,
+    pub certs: Arc<CertificateManager>,
+}
+
+/// Failed logins per client id. After `MAX_FAILED_LOGINS` failures the client id
+/// is locked out, the lockout doubles with every further failure.
+#[derive(Default)]
+pub struct LoginThrottle {
+    failures: DashMap<String, (u32, Instant)>,
+}
+
+impl LoginThrottle {
+    fn lockout(failures: u32) -> Duration {
+        match failures.checked_sub(MAX_FAILED_LOGINS) {
+            Some(exceeded) => Duration::from_secs(1 << exceeded.min(10)).min(MAX_LOCKOUT),
+            None => Duration::ZERO,
+        }
+    }
+
+    /// True if the client id has to wait before its next login
+    pub fn is_locked_out(&self, client_id: &str, now: Instant) -> bool {
+        self.failures.get(client_id).is_some_and(|entry| {
+            let (failures, last_failure) = *entry;
+            now < last_failure + Self::lockout(failures)
+        })
+    }
+
+    pub fn record_success(&self, client_id: &str) {
+        self.failures.remove(client_id);
+    }
+
+    pub fn record_failure(&self, client_id: &str, now: Instant) {
+        if self.failures.len() >= MAX_TRACKED_CLIENTS {
+            self.failures
+                .retain(|_, (_, last_failure)| now < *last_failure + MAX_LOCKOUT);
+        }
+        let mut entry = self
+            .failures
+            .entry(client_id.to_string())
+            .or_insert((0, now));
+        entry.0 = entry.0.saturating_add(1);
+        entry.1 = now;
+    }
+}
+
+/// Hash a device password for storage in the device metadata
+pub fn hash_password(password: &str) -> Result<String, ErrorStack> {
+    let mut salt = [0u8; SALT_LENGTH];
+    rand_bytes(&mut salt)?;
+    let hash = derive_key(password, &salt, HASH_ITERATIONS)?;
+    Ok(format!(
+        "{}${}${}${}",
+        HASH_SCHEME,
+        HASH_ITERATIONS,
+        to_hex(&salt),
+        to_hex(&hash)
+    ))
+}
+
+/// Check a password against a hash created by `hash_password`
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    let parts: Vec<&str> = password_hash.split('$').collect();
+    let (iterations, salt, expected) = match parts.as_slice() {
+        [HASH_SCHEME, iterations, salt, hash] => {
+            match (iterations.parse::<usize>(), from_hex(salt), from_hex(hash)) {
+                (Ok(iterations), Some(salt), Some(hash)) if hash.len() == HASH_LENGTH => {
+                    (iterations, salt, hash)
+                }
+                _ => return false,
+            }
+        }
+        _ => return false,
+    };
+    match derive_key(password, &salt, iterations) {
+        Ok(hash) => memcmp::eq(&hash, &expected),
+        Err(_) => false,
+    }
+}
+
+fn derive_key(
+    password: &str,
+    salt: &[u8],
+    iterations: usize,
+) -> Result<[u8; HASH_LENGTH], ErrorStack> {
+    let mut key = [0u8; HASH_LENGTH];
+    pbkdf2_hmac(
+        password.as_bytes(),
+        salt,
+        iterations,
+        MessageDigest::sha256(),
+        &mut key,
+    )?;
+    Ok(key)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// True if the serial belongs to the registered certificate of the device or to
+// the previous one during its grace period
+fn is_current_certificate(
+    device: &DeviceMetadata,
+    serial: &str,
+    now: u64,
+) -> Result<bool, CertificateError> {
+    let serial = CertificateManager::normalize_serial(serial)?;
+    let is_serial = |certificate: &str| -> Result<bool, CertificateError> {
+        Ok(CertificateManager::certificate_serial(certificate)? == serial)
+    };
+    if let Some(certificate) = &device.certificate {
+        if is_serial(certificate)? {
+            return Ok(true);
+        }
+    }
+    match &device.previous_certificate {
+        Some(previous) if now < previous.valid_until => is_serial(&previous.certificate),
+        _ => Ok(false),
+    }
+}
+
+// Without the serial of the presented certificate any certificate issued for the
+// client id passes, refuse them all once one of them has been revoked or the grace
+// period of the previous one has ended
+fn is_ambiguous_certificate(
+    registry: &DeviceRegistry,
+    device: &DeviceMetadata,
+    client_id: &str,
+    now: u64,
+) -> Result<bool, CertificateError> {
+    if let Some(previous) = &device.previous_certificate {
+        if now >= previous.valid_until
+            || registry.certs.is_certificate_revoked(&previous.certificate)?
+        {
+            return Ok(true);
+        }
+    }
+    registry.certs.is_common_name_revoked(client_id)
+}
+
+/// Check the connect credentials of a client, `serial` is the serial number of
+/// the presented client certificate. Without a registry only the consistency of
+/// client id and certificate is checked.
+pub fn check_client(
+    registry: Option<&DeviceRegistry>,
+    client_id: &str,
+    username: &str,
+    password: &str,
+    common_name: &str,
+    organization: &str,
+    serial: Option<&str>,
+) -> Result<(), AuthError> {
+    // if we have a common_name (from client certificate) it has to match the client_id
+    if !common_name.is_empty() && client_id != common_name {
+        return Err(AuthError::CommonNameMismatch);
+    }
+
+    // the tenant is encoded in the client id (tenant.device)
+    let (tenant_id, device_id) = split_client_id(client_id);
+    if let TenantId::Custom(tenant) = &tenant_id {
+        if !is_valid_tenant_id(tenant) {
+            return Err(AuthError::InvalidTenant);
+        }
+    }
+
+    // if we have an organization (from client certificate) it has to match the tenant
+    if !organization.is_empty() && tenant_from_organization(organization) != tenant_id {
+        return Err(AuthError::OrganizationMismatch);
+    }
+
+    let registry = match registry {
+        Some(registry) => registry,
+        None => return Ok(()),
+    };
+    let device = registry
+        .db
+        .get_device_metadata(&tenant_id, &device_id)
+        .map_err(|e| AuthError::RegistryError(e.to_string()))?
+        .ok_or(AuthError::UnknownDevice)?;
+    if !device.enabled {
+        return Err(AuthError::DeviceDisabled);
+    }
+
+    // the certificate was verified against our CA, it has to be issued for the tenant
+    // and must not be revoked
+    if !common_name.is_empty() {
+        if organization.is_empty() {
+            return Err(AuthError::OrganizationMismatch);
+        }
+        let certificate = device
+            .certificate
+            .as_deref()
+            .ok_or(AuthError::UnknownCertificate)?;
+        let now = chrono::Utc::now().timestamp() as u64;
+        let revoked = match serial {
+            // a revoked certificate stays refused after a new one has been registered,
+            // a replaced one is only accepted during its grace period
+            Some(serial) => {
+                if !is_current_certificate(&device, serial, now)
+                    .map_err(|e| AuthError::RegistryError(e.to_string()))?
+                {
+                    return Err(AuthError::CertificateSuperseded);
+                }
+                registry.certs.is_serial_revoked(serial)
+            }
+            None => registry.certs.is_certificate_revoked(certificate),
+        }
+        .map_err(|e| AuthError::RegistryError(e.to_string()))?;
+        if revoked {
+            return Err(AuthError::CertificateRevoked);
+        }
+        if serial.is_none()
+            && is_ambiguous_certificate(registry, &device, client_id, now)
+                .map_err(|e| AuthError::RegistryError(e.to_string()))?
+        {
+            return Err(AuthError::AmbiguousCertificate);
+        }
+        return Ok(());
+    }
+
+    // without a client certificate the device needs its password
+    if username != client_id {
+        return Err(AuthError::UsernameMismatch);
+    }
+    match &device.password_hash {
+        Some(password_hash) if verify_password(password, password_hash) => Ok(()),
+        _ => Err(AuthError::InvalidCredentials),
+    }
+}
+
+/// Authenticates the clients of the broker
+pub(super) struct Authenticator {
+    registry: Option<DeviceRegistry>,
+    throttle: LoginThrottle,
+}
+
+impl Authenticator {
+    pub(super) fn new(registry: Option<DeviceRegistry>) -> Self {
+        Self {
+            registry,
+            throttle: LoginThrottle::default(),
+        }
+    }
+
+    /// Check a client on the blocking thread pool, the registry lookup and the
+    /// password hash must not block the runtime
+    pub(super) async fn authenticate(
+        self: Arc<Self>,
+        client_id: String,
+        username: String,
+        password: String,
+        common_name: String,
+        organization: String,
+        serial: Option<String>,
+    ) -> bool {
+        let span = info_span!("authentication", client_id = %client_id, username = %username, common_name = %common_name, organization = %organization, serial = ?serial);
+        // only password logins can be guessed, a client verified by its certificate
+        // must not be locked out by someone failing passwords for its client id
+        let throttled = common_name.is_empty();
+        if throttled && self.throttle.is_locked_out(&client_id, Instant::now()) {
+            span.in_scope(|| warn!("Client is locked out after failed logins"));
+            return false;
+        }
+        let authenticator = self.clone();
+        let checked_client_id = client_id.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            check_client(
+                authenticator.registry.as_ref(),
+                &checked_client_id,
+                &username,
+                &password,
+                &common_name,
+                &organization,
+                serial.as_deref(),
+            )
+        })
+        .await;
+        let _span = span.entered();
+        match result {
+            Ok(Ok(())) => {
+                if throttled {
+                    self.throttle.record_success(&client_id);
+                }
+                true
+            }
+            Ok(Err(e)) => {
+                // registry errors are not the fault of the client
+                if throttled && !matches!(e, AuthError::RegistryError(_)) {
+                    self.throttle.record_failure(&client_id, Instant::now());
+                }
+                warn!(error = %e, "Client authentication failed");
+                false
+            }
+            Err(e) => {
+                warn!(error = %e, "Client authentication task failed");
+                false
+            }
+        }
+    }
+}
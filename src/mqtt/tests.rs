@@ -13,7 +13,7 @@ fn get_test_config() -> Option<MqttConfig> {
 #[tokio::test]
 async fn test_server_start_stop() {
     let config = get_test_config();
-    let mut server = start_broker(config).await;
+    let mut server = start_broker(config, None).await;
 
     let shutdown_received = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
     let shutdown_received_clone = shutdown_received.clone();
@@ -35,7 +35,7 @@ async fn test_server_start_stop() {
 #[tokio::test]
 async fn test_publish_subscribe() {
     let config = get_test_config();
-    let mut server = start_broker(config).await;
+    let mut server = start_broker(config, None).await;
 
     // Create receiver
     let receiver = server.message_receiver();
@@ -63,3 +63,347 @@ async fn test_publish_subscribe() {
 
     server.shutdown();
 }
+
+fn setup_registry() -> (auth::DeviceRegistry, tempfile::TempDir) {
+    use crate::certs::CertificateManager;
+    use crate::db::DB;
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let db = DB::open_default(temp_dir.path().join("db").to_str().unwrap()).unwrap();
+    let certs = CertificateManager::new(temp_dir.path().join("certs"), None).unwrap();
+    certs.ensure_ca_exists().unwrap();
+    let registry = auth::DeviceRegistry {
+        db: Arc::new(db),
+        certs: Arc::new(certs),
+    };
+    (registry, temp_dir)
+}
+
+#[test]
+fn test_password_hash() {
+    let hash = auth::hash_password("secret").unwrap();
+    assert!(hash.starts_with("pbkdf2_sha256$"));
+    assert!(!hash.contains("secret"));
+    assert!(auth::verify_password("secret", &hash));
+    assert!(!auth::verify_password("Secret", &hash));
+    // salted, the same password gives a different hash
+    assert_ne!(hash, auth::hash_password("secret").unwrap());
+    assert!(!auth::verify_password("secret", "secret"));
+    assert!(!auth::verify_password("secret", "pbkdf2_sha256$1$zz$00"));
+}
+
+#[test]
+fn test_check_client_without_registry() {
+    use auth::{check_client, AuthError};
+    assert_eq!(check_client(None, "acme.device1", "", "", "", "", None), Ok(()));
+    assert_eq!(
+        check_client(None, "acme.device1", "", "", "acme.device2", "acme", None),
+        Err(AuthError::CommonNameMismatch)
+    );
+    assert_eq!(
+        check_client(None, "acme.device1", "", "", "acme.device1", "other", None),
+        Err(AuthError::OrganizationMismatch)
+    );
+}
+
+#[test]
+fn test_check_client_with_registry() {
+    use crate::models::{DeviceMetadata, TenantId};
+    use auth::{check_client, AuthError};
+    let (registry, _temp) = setup_registry();
+    let db = registry.db.clone();
+    let tenant_id = TenantId::new("acme");
+    let cert_data = registry
+        .certs
+        .create_tenant_client_cert(&tenant_id, "device1")
+        .unwrap();
+    let mut device = DeviceMetadata::new("device1", &tenant_id)
+        .with_certificate(cert_data.cert.clone());
+    device.password_hash = Some(auth::hash_password("secret").unwrap());
+    db.put_device_metadata(&device).unwrap();
+    db.put_device_metadata(&DeviceMetadata::new("device2", &tenant_id))
+        .unwrap();
+    let certs = registry.certs.clone();
+    let registry = Some(&registry);
+
+    // certificate of a registered device
+    assert_eq!(
+        check_client(registry, "acme.device1", "", "", "acme.device1", "acme", None),
+        Ok(())
+    );
+    assert_eq!(
+        check_client(registry, "acme.device1", "", "", "acme.device1", "", None),
+        Err(AuthError::OrganizationMismatch)
+    );
+    assert_eq!(
+        check_client(registry, "acme.device3", "", "", "acme.device3", "acme", None),
+        Err(AuthError::UnknownDevice)
+    );
+    assert_eq!(
+        check_client(registry, "device1", "", "", "device1", "Forest", None),
+        Err(AuthError::UnknownDevice)
+    );
+    assert_eq!(
+        check_client(registry, "acme.device2", "", "", "acme.device2", "acme", None),
+        Err(AuthError::UnknownCertificate)
+    );
+
+    // password of a registered device
+    assert_eq!(
+        check_client(registry, "acme.device1", "acme.device1", "secret", "", "", None),
+        Ok(())
+    );
+    assert_eq!(
+        check_client(registry, "acme.device1", "acme.device1", "wrong", "", "", None),
+        Err(AuthError::InvalidCredentials)
+    );
+    assert_eq!(
+        check_client(registry, "acme.device1", "device1", "secret", "", "", None),
+        Err(AuthError::UsernameMismatch)
+    );
+    // no password set, only the certificate is accepted
+    assert_eq!(
+        check_client(registry, "acme.device2", "acme.device2", "", "", "", None),
+        Err(AuthError::InvalidCredentials)
+    );
+
+    // a revoked certificate is refused, the password still works
+    certs.revoke_certificate(&cert_data.cert, None).unwrap();
+    assert_eq!(
+        check_client(registry, "acme.device1", "", "", "acme.device1", "acme", None),
+        Err(AuthError::CertificateRevoked)
+    );
+    assert_eq!(
+        check_client(registry, "acme.device1", "acme.device1", "secret", "", "", None),
+        Ok(())
+    );
+
+    // disabled devices are rejected with valid credentials
+    device.enabled = false;
+    db.put_device_metadata(&device).unwrap();
+    assert_eq!(
+        check_client(registry, "acme.device1", "acme.device1", "secret", "", "", None),
+        Err(AuthError::DeviceDisabled)
+    );
+}
+
+#[test]
+fn test_check_client_refuses_revoked_presented_certificate() {
+    use crate::certs::CertificateManager;
+    use crate::models::{DeviceMetadata, PreviousCertificate, TenantId};
+    use auth::{check_client, AuthError};
+    let (registry, _temp) = setup_registry();
+    let tenant_id = TenantId::new("acme");
+    let old_cert = registry
+        .certs
+        .create_tenant_client_cert(&tenant_id, "device1")
+        .unwrap()
+        .cert;
+    let new_cert = registry
+        .certs
+        .create_tenant_client_cert(&tenant_id, "device1")
+        .unwrap()
+        .cert;
+    let old_serial = CertificateManager::certificate_serial(&old_cert).unwrap();
+    let new_serial = CertificateManager::certificate_serial(&new_cert).unwrap();
+
+    // the old certificate was stolen and revoked during the grace period of a renewal
+    registry.certs.revoke_serial(&old_serial, None).unwrap();
+    let mut device = DeviceMetadata::new("device1", &tenant_id);
+    device.certificate = Some(new_cert);
+    device.previous_certificate = Some(PreviousCertificate {
+        certificate: old_cert,
+        valid_until: chrono::Utc::now().timestamp() as u64 + 3600,
+    });
+    registry.db.put_device_metadata(&device).unwrap();
+
+    let login = |serial: &str| {
+        check_client(Some(&registry), "acme.device1", "", "", "acme.device1", "acme", Some(serial))
+    };
+    assert_eq!(login(&old_serial), Err(AuthError::CertificateRevoked));
+    assert_eq!(login(&new_serial.to_lowercase()), Ok(()));
+
+    // without the serial the presented certificate could be the revoked one
+    let login_without_serial = || {
+        check_client(Some(&registry), "acme.device1", "", "", "acme.device1", "acme", None)
+    };
+    assert_eq!(login_without_serial(), Err(AuthError::AmbiguousCertificate));
+    // the same for a revoked certificate the device no longer tracks
+    device.previous_certificate = None;
+    registry.db.put_device_metadata(&device).unwrap();
+    let stolen_cert = registry
+        .certs
+        .create_tenant_client_cert(&tenant_id, "device1")
+        .unwrap()
+        .cert;
+    registry.certs.revoke_certificate(&stolen_cert, None).unwrap();
+    assert_eq!(login_without_serial(), Err(AuthError::AmbiguousCertificate));
+}
+
+#[test]
+fn test_check_client_ends_grace_period_of_previous_certificate() {
+    use crate::certs::CertificateManager;
+    use crate::models::{DeviceMetadata, PreviousCertificate, TenantId};
+    use auth::{check_client, AuthError};
+    let (registry, _temp) = setup_registry();
+    let tenant_id = TenantId::new("acme");
+    let serial_of = |cert: &str| CertificateManager::certificate_serial(cert).unwrap();
+    let create_cert = || {
+        registry
+            .certs
+            .create_tenant_client_cert(&tenant_id, "device1")
+            .unwrap()
+            .cert
+    };
+    let (old_cert, new_cert, other_cert) = (create_cert(), create_cert(), create_cert());
+    let now = chrono::Utc::now().timestamp() as u64;
+    let mut device = DeviceMetadata::new("device1", &tenant_id);
+    device.certificate = Some(new_cert.clone());
+    device.previous_certificate = Some(PreviousCertificate {
+        certificate: old_cert.clone(),
+        valid_until: now + 3600,
+    });
+    registry.db.put_device_metadata(&device).unwrap();
+
+    let login = |serial: &str| {
+        check_client(Some(&registry), "acme.device1", "", "", "acme.device1", "acme", Some(serial))
+    };
+    // the replaced certificate works during its grace period
+    assert_eq!(login(&serial_of(&new_cert)), Ok(()));
+    assert_eq!(login(&serial_of(&old_cert)), Ok(()));
+    assert_eq!(login(&serial_of(&other_cert)), Err(AuthError::CertificateSuperseded));
+    let login_without_serial = || {
+        check_client(Some(&registry), "acme.device1", "", "", "acme.device1", "acme", None)
+    };
+    assert_eq!(login_without_serial(), Ok(()));
+
+    // and is refused once it has ended, even before it is revoked
+    device.previous_certificate.as_mut().unwrap().valid_until = now - 1;
+    registry.db.put_device_metadata(&device).unwrap();
+    assert_eq!(login(&serial_of(&old_cert)), Err(AuthError::CertificateSuperseded));
+    assert_eq!(login(&serial_of(&new_cert)), Ok(()));
+    // without the serial the old certificate cannot be told apart from the new one
+    assert_eq!(login_without_serial(), Err(AuthError::AmbiguousCertificate));
+}
+
+#[test]
+fn test_login_throttle() {
+    use auth::LoginThrottle;
+    use std::time::{Duration, Instant};
+    let throttle = LoginThrottle::default();
+    let now = Instant::now();
+
+    for _ in 0..4 {
+        throttle.record_failure("acme.device1", now);
+    }
+    assert!(!throttle.is_locked_out("acme.device1", now));
+    throttle.record_failure("acme.device1", now);
+    assert!(throttle.is_locked_out("acme.device1", now));
+    assert!(!throttle.is_locked_out("acme.device1", now + Duration::from_secs(1)));
+    // the lockout doubles with every further failure
+    throttle.record_failure("acme.device1", now);
+    assert!(throttle.is_locked_out("acme.device1", now + Duration::from_secs(1)));
+    assert!(!throttle.is_locked_out("acme.device1", now + Duration::from_secs(2)));
+    assert!(!throttle.is_locked_out("acme.device2", now));
+
+    throttle.record_success("acme.device1");
+    assert!(!throttle.is_locked_out("acme.device1", now));
+}
+
+#[tokio::test]
+async fn test_authenticator_does_not_throttle_certificates() {
+    use crate::models::{DeviceMetadata, TenantId};
+    let (registry, _temp) = setup_registry();
+    let tenant_id = TenantId::new("acme");
+    let cert_data = registry
+        .certs
+        .create_tenant_client_cert(&tenant_id, "device1")
+        .unwrap();
+    let mut device =
+        DeviceMetadata::new("device1", &tenant_id).with_certificate(cert_data.cert);
+    device.password_hash = Some(auth::hash_password("secret").unwrap());
+    registry.db.put_device_metadata(&device).unwrap();
+    let authenticator = Arc::new(auth::Authenticator::new(Some(registry)));
+    let login = |password: &str, common_name: &str, organization: &str| {
+        authenticator.clone().authenticate(
+            "acme.device1".to_string(),
+            "acme.device1".to_string(),
+            password.to_string(),
+            common_name.to_string(),
+            organization.to_string(),
+            None,
+        )
+    };
+
+    // failed passwords lock out password logins
+    for _ in 0..6 {
+        assert!(!login("wrong", "", "").await);
+    }
+    assert!(!login("secret", "", "").await);
+    // the certificate of the device is still accepted
+    assert!(login("", "acme.device1", "acme").await);
+}
+
+#[test]
+fn test_filter_covers() {
+    use acl::{filter_covers, topic_matches};
+    assert!(filter_covers("things/a/#", "things/a/#"));
+    assert!(filter_covers("things/a/#", "things/a"));
+    assert!(filter_covers("things/a/#", "things/a/shadow/+"));
+    assert!(filter_covers("things/+/data", "things/a/data"));
+    assert!(!filter_covers("things/a/#", "#"));
+    assert!(!filter_covers("things/a/#", "things/+/shadow/update"));
+    assert!(!filter_covers("things/a/#", "things/ab/data"));
+    assert!(!filter_covers("things/+/data", "things/a/data/x"));
+    assert!(topic_matches("things/a/#", "things/a/shadow/update"));
+    assert!(!topic_matches("things/a/#", "things/a/+"));
+}
+
+#[test]
+fn test_device_acl() {
+    use acl::{Acl, AclAction, AclConfig};
+    let acl = Acl::new(AclConfig {
+        enabled: true,
+        ..AclConfig::default()
+    });
+    assert!(acl.is_allowed("acme.device1", AclAction::Publish, "things/acme.device1/shadow/update"));
+    assert!(acl.is_allowed("acme.device1", AclAction::Subscribe, "things/acme.device1/shadow/#"));
+    assert!(acl.is_allowed("acme.device1", AclAction::Subscribe, "public/heartbeat"));
+    assert!(acl.is_allowed("device1", AclAction::Publish, "things/device1/data"));
+    // other devices and tenants
+    assert!(!acl.is_allowed("acme.device1", AclAction::Publish, "things/acme.device2/shadow/update"));
+    assert!(!acl.is_allowed("acme.device1", AclAction::Subscribe, "#"));
+    assert!(!acl.is_allowed("acme.device1", AclAction::Subscribe, "things/+/shadow/update/delta"));
+    assert!(!acl.is_allowed("acme.device1", AclAction::Publish, "public/heartbeat"));
+    // wildcards in the client id do not widen the rules
+    assert!(!acl.is_allowed("acme.#", AclAction::Subscribe, "things/acme.#/#"));
+    assert!(!acl.is_allowed("acme.+", AclAction::Publish, "things/acme.+/data"));
+}
+
+#[test]
+fn test_service_client_acl() {
+    use acl::{Acl, AclAction, AclConfig, AclRules};
+    let mut config = AclConfig {
+        enabled: true,
+        ..AclConfig::default()
+    };
+    config.clients.insert(
+        "acme.backend".to_string(),
+        AclRules {
+            publish: vec!["things/+/shadow/+/update".to_string()],
+            subscribe: vec!["things/+/data".to_string(), "tenants/{tenant}/#".to_string()],
+        },
+    );
+    let acl = Acl::new(config);
+    assert!(acl.is_allowed("acme.backend", AclAction::Subscribe, "things/+/data"));
+    assert!(acl.is_allowed("acme.backend", AclAction::Subscribe, "tenants/acme/#"));
+    assert!(acl.is_allowed("acme.backend", AclAction::Publish, "things/acme.device1/shadow/config/update"));
+    // the own device topics are still allowed
+    assert!(acl.is_allowed("acme.backend", AclAction::Publish, "things/acme.backend/data"));
+    assert!(!acl.is_allowed("acme.backend", AclAction::Subscribe, "tenants/other/#"));
+    assert!(!acl.is_allowed("acme.backend", AclAction::Subscribe, "things/+/shadow/update"));
+    assert!(!acl.is_allowed("acme.device1", AclAction::Subscribe, "things/+/data"));
+
+    let mut config = AclConfig::default();
+    config.enabled = false;
+    assert!(Acl::new(config).is_allowed("acme.device1", AclAction::Subscribe, "#"));
+}
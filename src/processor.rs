@@ -1,8 +1,13 @@
-use crate::db::DB;
+use crate::db::{DatabaseError, MetricWrite, DB};
 use crate::mqtt::{ClientStatus, MqttError, MqttMessage, MqttSender};
 use crate::server::ConnectionSet;
-use crate::shadow::{Shadow, StateUpdateDocument};
-use crate::models::{ShadowName, TenantId};
+use crate::shadow::{
+    ErrorResponse, NestedStateDocument, Shadow, ShadowError, ShadowRequest,
+    ShadowSerializationError,
+    StateUpdateDocument, UpdateSource,
+};
+use crate::timeseries::now_micros;
+use crate::models::{is_valid_tenant_id, split_client_id, to_client_id, ShadowName, TenantId};
 use futures_util::stream::StreamExt;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
@@ -25,25 +30,59 @@ pub enum ProcessorError {
     InvalidShadowUpdate(String),
     #[error("Invalid Json: {0}")]
     InvalidJson(String),
+    #[error("Metric writer stopped")]
+    MetricWriterStopped,
 }
 
+/// Most messages whose metrics are written in a single transaction
+const METRIC_BATCH_SIZE: usize = 100;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ProcessorConfig {
     pub shadow_topic_prefix: String,
+    /// Topic filters the processor subscribes to, relative to `shadow_topic_prefix`.
+    /// Patterns may be narrowed to single clients, e.g. `acme.sensor1/data`.
+    #[serde(default = "default_ingest_topics")]
+    pub ingest_topics: Vec<String>,
+}
+
+fn default_ingest_topics() -> Vec<String> {
+    vec![
+        "+/shadow/update".to_string(),
+        "+/shadow/+/update".to_string(),
+        "+/shadow/get".to_string(),
+        "+/shadow/+/get".to_string(),
+        "+/shadow/delete".to_string(),
+        "+/shadow/+/delete".to_string(),
+        "+/data".to_string(),
+    ]
 }
 
 impl Default for ProcessorConfig {
     fn default() -> Self {
         ProcessorConfig {
             shadow_topic_prefix: "things/".to_string(),
+            ingest_topics: default_ingest_topics(),
         }
     }
 }
 
+impl ProcessorConfig {
+    /// Full topic filters including the topic prefix
+    pub fn ingest_topic_patterns(&self) -> Vec<String> {
+        self.ingest_topics
+            .iter()
+            .map(|topic| format!("{}{}", self.shadow_topic_prefix, topic))
+            .collect()
+    }
+}
+
 type DeviceId = String;
 
 pub enum TopicType {
     ShadowUpdate(TenantId, DeviceId, ShadowName),
+    ShadowGet(TenantId, DeviceId, ShadowName),
+    ShadowDelete(TenantId, DeviceId, ShadowName),
     DataUpdate(TenantId, DeviceId),
     ShadowDelta(TenantId, DeviceId, ShadowName),
     Other,
@@ -53,6 +92,7 @@ pub enum TopicType {
 pub struct ProcessorState {
     db: Arc<DB>,
     mqtt_sender: MqttSender,
+    metric_sender: flume::Sender<MetricWrite>,
     config: Arc<ProcessorConfig>,
 }
 
@@ -63,7 +103,7 @@ pub struct Processor {
 }
 
 impl Processor {
-    pub async fn subscribe_shadow_updates(
+    pub async fn subscribe_topics(
         &mut self,
         topic_patterns: Vec<String>,
     ) -> Result<(), ProcessorError> {
@@ -74,22 +114,54 @@ impl Processor {
     }
 }
 
-fn get_delta_return_topic(device_id: &str, shadow_name: &ShadowName, topic_prefix: &str) -> String {
+// Build a shadow topic like `{prefix}{client_id}/shadow[/{name}]/{suffix}`
+fn get_shadow_topic(
+    tenant_id: &TenantId,
+    device_id: &str,
+    shadow_name: &ShadowName,
+    suffix: &str,
+    topic_prefix: &str,
+) -> String {
+    let client_id = to_client_id(tenant_id, device_id);
     match shadow_name {
-        ShadowName::Default => format!("{}{}/shadow/update/delta", topic_prefix, device_id),
+        ShadowName::Default => format!("{}{}/shadow/{}", topic_prefix, client_id, suffix),
         ShadowName::Custom(name) => {
-            format!("{}{}/shadow/{}/update/delta", topic_prefix, device_id, name)
+            format!("{}{}/shadow/{}/{}", topic_prefix, client_id, name, suffix)
         }
     }
 }
 
+fn get_delta_return_topic(
+    tenant_id: &TenantId,
+    device_id: &str,
+    shadow_name: &ShadowName,
+    topic_prefix: &str,
+) -> String {
+    get_shadow_topic(tenant_id, device_id, shadow_name, "update/delta", topic_prefix)
+}
+
+fn publish_response<T: Serialize>(
+    state: &ProcessorState,
+    topic: String,
+    response: &T,
+) -> Result<(), ProcessorError> {
+    let payload = serde_json::to_vec(response).map_err(ShadowSerializationError::from)?;
+    state.mqtt_sender.publish(topic.clone(), payload)?;
+    debug!(topic, "Response sent to device");
+    Ok(())
+}
+
 pub fn send_delta_to_mqtt(
     shadow: &Shadow,
     mqtt_sender: &MqttSender,
     shadow_topic_prefix: &str,
 ) -> Result<bool, ProcessorError> {
-    let return_topic =
-        get_delta_return_topic(&shadow.device_id, &shadow.shadow_name, shadow_topic_prefix);
+    let return_topic = get_delta_return_topic(
+        &shadow.tenant_id,
+        &shadow.device_id,
+        &shadow.shadow_name,
+        shadow_topic_prefix,
+    );
     // Send delta to the device
     let delta_json = shadow.get_delta_response_json()?;
     match delta_json {
@@ -102,78 +174,55 @@ pub fn send_delta_to_mqtt(
     }
 }
 
-fn process_update_document(
-    update_doc: &StateUpdateDocument,
-    state: &ProcessorState,
-) -> Result<(), ProcessorError> {
-    let shadow = state.db._upsert_shadow(update_doc)?;
-    let delta_sent = send_delta_to_mqtt(
-        &shadow,
-        &state.mqtt_sender,
-        &state.config.shadow_topic_prefix,
-    )?;
-    info!(
-        %update_doc.tenant_id,
-        update_doc.device_id, %update_doc.shadow_name, delta_sent, "Processed shadow update"
-    );
-    Ok(())
-}
-
-fn split_device_id(device_id: &str) -> (TenantId, DeviceId) {
-    match device_id.split_once('.') {
-        Some((tenant_str, device_id)) => (TenantId::from_str(tenant_str), device_id.to_string()),
-        None => (TenantId::Default, device_id.to_string()),
+// Split the client id part of a topic into tenant and device,
+// topics with an invalid tenant are never processed
+fn split_device_id(device_id: &str) -> Option<(TenantId, DeviceId)> {
+    let (tenant, device) = split_client_id(device_id);
+    match &tenant {
+        _ if device.is_empty() => None,
+        TenantId::Custom(t) if !is_valid_tenant_id(t) => None,
+        _ => Some((tenant, device)),
     }
 }
 
-fn get_topic_type(msg: &MqttMessage, processor_state: &ProcessorState) -> TopicType {
+fn get_topic_type(topic: &str, shadow_topic_prefix: &str) -> TopicType {
     // check if the topic is a shadow update and strip prefix
-    let shadow_topic = match msg
-        .topic
-        .strip_prefix(processor_state.config.shadow_topic_prefix.as_str())
-    {
+    let shadow_topic = match topic.strip_prefix(shadow_topic_prefix) {
         Some(t) => t,
         None => return TopicType::Other,
     };
 
     let parts: Vec<&str> = shadow_topic.split('/').collect();
     // determine the type of message
-    // first part is always the device_id
+    // first part is always the device_id (optionally prefixed with the tenant)
     // second part is always shadow or data -> shadow update or data update
     // return (type, tenant_id, device_id, shadow_name)
+    let (tenant, device) = match split_device_id(parts[0]) {
+        Some(ids) => ids,
+        None => return TopicType::Other,
+    };
 
-    match &parts[..] {
-        [device_id, "shadow", "update"] => {
-            let (tenant, device) = split_device_id(device_id);
-            return TopicType::ShadowUpdate(tenant, device, ShadowName::Default);
-        }
-        [device_id, "shadow", shadow_name, "update"] => {
-            let (tenant, device) = split_device_id(device_id);
-            return TopicType::ShadowUpdate(
-                tenant,
-                device,
-                ShadowName::from_str(shadow_name),
-            );
-        }
-        [device_id, "data"] => {
-            let (tenant, device) = split_device_id(device_id);
-            return TopicType::DataUpdate(tenant, device);
-        }
-        [device_id, "shadow", "update", "delta"] => {
-            let (tenant, device) = split_device_id(device_id);
-            return TopicType::ShadowDelta(tenant, device, ShadowName::Default);
-        }
-        [device_id, "shadow", shadow_name, "update", "delta"] => {
-            let (tenant, device) = split_device_id(device_id);
-            return TopicType::ShadowDelta(
-                tenant,
-                device,
-                ShadowName::from_str(shadow_name),
-            );
+    match &parts[1..] {
+        ["shadow", "update"] => TopicType::ShadowUpdate(tenant, device, ShadowName::Default),
+        ["shadow", shadow_name, "update"] => {
+            TopicType::ShadowUpdate(tenant, device, ShadowName::from_str(shadow_name))
         }
-        _ => {
-            return TopicType::Other;
+        ["shadow", "get"] => TopicType::ShadowGet(tenant, device, ShadowName::Default),
+        ["shadow", shadow_name, "get"] => {
+            TopicType::ShadowGet(tenant, device, ShadowName::from_str(shadow_name))
+        }
+        ["shadow", "delete"] => TopicType::ShadowDelete(tenant, device, ShadowName::Default),
+        ["shadow", shadow_name, "delete"] => {
+            TopicType::ShadowDelete(tenant, device, ShadowName::from_str(shadow_name))
+        }
+        ["data"] => TopicType::DataUpdate(tenant, device),
+        ["shadow", "update", "delta"] => {
+            TopicType::ShadowDelta(tenant, device, ShadowName::Default)
         }
+        ["shadow", shadow_name, "update", "delta"] => {
+            TopicType::ShadowDelta(tenant, device, ShadowName::from_str(shadow_name))
+        }
+        _ => TopicType::Other,
     }
 }
 
@@ -184,21 +233,185 @@ async fn handle_shadow_update(
     payload: Vec<u8>,
     state: ProcessorState,
 ) -> Result<(), ProcessorError> {
-    if let Ok(json_str) = String::from_utf8(payload) {
-        if let Ok(update_doc) =
-            StateUpdateDocument::from_nested_json(&json_str, device_id, shadow_name, tenant_id)
-        {
-            process_update_document(&update_doc, &state)?;
-        } else {
+    let topic = |suffix: &str| {
+        get_shadow_topic(
+            tenant_id,
+            device_id,
+            shadow_name,
+            suffix,
+            &state.config.shadow_topic_prefix,
+        )
+    };
+    let reject = |code: u16, message: &str, client_token: Option<String>| {
+        let response = ErrorResponse::new(code, message, client_token);
+        publish_response(&state, topic("update/rejected"), &response)
+    };
+
+    let json = match serde_json::from_slice::<serde_json::Value>(&payload) {
+        Ok(json) => json,
+        Err(_) => {
+            reject(400, "Invalid JSON", None)?;
             return Err(ProcessorError::InvalidShadowUpdate(
                 "Failed to parse JSON".to_string(),
             ));
         }
-    } else {
+    };
+    let client_token = json
+        .get("clientToken")
+        .and_then(|t| t.as_str())
+        .map(|t| t.to_string());
+    if json.get("state").is_none() {
+        reject(400, "Missing required node: state", client_token)?;
         return Err(ProcessorError::InvalidShadowUpdate(
-            "Not able to convert payload to utf-8 string".to_string(),
+            "Missing state".to_string(),
         ));
     }
+    let nested = match serde_json::from_value::<NestedStateDocument>(json) {
+        Ok(nested) => nested,
+        Err(e) => {
+            reject(400, "Invalid state document", client_token)?;
+            return Err(ProcessorError::InvalidShadowUpdate(e.to_string()));
+        }
+    };
+
+    let update_doc =
+        StateUpdateDocument::from_nested_state(nested, device_id, shadow_name, tenant_id);
+    let (previous, shadow) = match state.db._update_shadow(&update_doc, UpdateSource::Mqtt) {
+        Ok(result) => result,
+        Err(DatabaseError::ShadowError(ShadowError::VersionConflict { expected, current })) => {
+            reject(409, "Version conflict", client_token)?;
+            return Err(ProcessorError::InvalidShadowUpdate(format!(
+                "Version conflict: expected {}, current {}",
+                expected, current
+            )));
+        }
+        Err(e) => {
+            reject(500, "Internal service failure", client_token)?;
+            return Err(ProcessorError::DatabaseError(e));
+        }
+    };
+
+    publish_response(
+        &state,
+        topic("update/accepted"),
+        &shadow.update_accepted_response(&update_doc, client_token.clone()),
+    )?;
+    publish_response(
+        &state,
+        topic("update/documents"),
+        &shadow.documents_response(previous.as_ref(), client_token),
+    )?;
+    let delta_sent = send_delta_to_mqtt(
+        &shadow,
+        &state.mqtt_sender,
+        &state.config.shadow_topic_prefix,
+    )?;
+    info!(
+        %tenant_id,
+        device_id, %shadow_name, delta_sent, "Processed shadow update"
+    );
+    Ok(())
+}
+
+// Get and delete requests carry an optional client token, the payload may be empty
+fn parse_shadow_request(payload: &[u8]) -> Result<ShadowRequest, serde_json::Error> {
+    if payload.iter().all(|b| b.is_ascii_whitespace()) {
+        return Ok(ShadowRequest::default());
+    }
+    serde_json::from_slice(payload)
+}
+
+async fn handle_shadow_get(
+    tenant_id: &TenantId,
+    device_id: &str,
+    shadow_name: &ShadowName,
+    payload: Vec<u8>,
+    state: ProcessorState,
+) -> Result<(), ProcessorError> {
+    let topic = |suffix: &str| {
+        get_shadow_topic(
+            tenant_id,
+            device_id,
+            shadow_name,
+            suffix,
+            &state.config.shadow_topic_prefix,
+        )
+    };
+
+    let request = match parse_shadow_request(&payload) {
+        Ok(request) => request,
+        Err(e) => {
+            let response = ErrorResponse::new(400, "Invalid JSON", None);
+            publish_response(&state, topic("get/rejected"), &response)?;
+            return Err(ProcessorError::InvalidJson(e.to_string()));
+        }
+    };
+
+    match state.db._get_shadow(device_id, shadow_name, tenant_id) {
+        Ok(shadow) => {
+            let response = shadow.get_accepted_response(request.client_token);
+            publish_response(&state, topic("get/accepted"), &response)?;
+        }
+        Err(DatabaseError::NotFoundError(_)) => {
+            let message = format!("No shadow exists with name: '{}'", shadow_name);
+            let response = ErrorResponse::new(404, &message, request.client_token);
+            publish_response(&state, topic("get/rejected"), &response)?;
+        }
+        Err(e) => {
+            let response =
+                ErrorResponse::new(500, "Internal service failure", request.client_token);
+            publish_response(&state, topic("get/rejected"), &response)?;
+            return Err(ProcessorError::DatabaseError(e));
+        }
+    }
+    debug!(%tenant_id, device_id, %shadow_name, "Processed shadow get");
+    Ok(())
+}
+
+async fn handle_shadow_delete(
+    tenant_id: &TenantId,
+    device_id: &str,
+    shadow_name: &ShadowName,
+    payload: Vec<u8>,
+    state: ProcessorState,
+) -> Result<(), ProcessorError> {
+    let topic = |suffix: &str| {
+        get_shadow_topic(
+            tenant_id,
+            device_id,
+            shadow_name,
+            suffix,
+            &state.config.shadow_topic_prefix,
+        )
+    };
+
+    let request = match parse_shadow_request(&payload) {
+        Ok(request) => request,
+        Err(e) => {
+            let response = ErrorResponse::new(400, "Invalid JSON", None);
+            publish_response(&state, topic("delete/rejected"), &response)?;
+            return Err(ProcessorError::InvalidJson(e.to_string()));
+        }
+    };
+
+    match state.db._delete_shadow(device_id, shadow_name, tenant_id) {
+        Ok(shadow) => {
+            let response = shadow.delete_accepted_response(request.client_token);
+            publish_response(&state, topic("delete/accepted"), &response)?;
+        }
+        Err(DatabaseError::NotFoundError(_)) => {
+            let message = format!("No shadow exists with name: '{}'", shadow_name);
+            let response = ErrorResponse::new(404, &message, request.client_token);
+            publish_response(&state, topic("delete/rejected"), &response)?;
+        }
+        Err(e) => {
+            let response =
+                ErrorResponse::new(500, "Internal service failure", request.client_token);
+            publish_response(&state, topic("delete/rejected"), &response)?;
+            return Err(ProcessorError::DatabaseError(e));
+        }
+    }
+    info!(%tenant_id, device_id, %shadow_name, "Processed shadow delete");
     Ok(())
 }
 
@@ -222,27 +435,20 @@ async fn handle_metric_extraction(
     // get data config from db
     let maybe_config = state.db.get_data_config(tenant_id, Some(device_id))?;
     let metrics = match maybe_config {
-        Some(data_config) => data_config.extract_metrics_from_json(json),
+        Some(data_config) => {
+            let received_at = now_micros();
+            data_config.extract_metrics_from_json(json, received_at)
+        }
         None => return Ok(()),
     };
 
-    let mut counter = 0;
-    // store metrics
-    // TODO: batch insert for metrics
-    for (metric_name, metric_value) in metrics {
-        let res = state
-            .db
-            .put_metric(tenant_id, device_id, &metric_name, metric_value);
-        match res {
-            Ok(_) => {
-                counter += 1;
-                debug!(metric_name, "Stored metric");
-            }
-            Err(e) => {
-                return Err(ProcessorError::DatabaseError(e));
-            }
-        }
-    }
+    // the metric writer stores the metrics together with those of other messages
+    let counter = metrics.len();
+    state
+        .metric_sender
+        .send_async((tenant_id.clone(), device_id.to_string(), metrics))
+        .await
+        .map_err(|_| ProcessorError::MetricWriterStopped)?;
 
     info!(%tenant_id, device_id, counter, "Processed metrics");
 
@@ -250,7 +456,7 @@ async fn handle_metric_extraction(
 }
 
 async fn handle_message(msg: MqttMessage, state: ProcessorState) {
-    let topic_type = get_topic_type(&msg, &state);
+    let topic_type = get_topic_type(&msg.topic, &state.config.shadow_topic_prefix);
 
     if matches!(topic_type, TopicType::Other) {
         return;
@@ -276,6 +482,20 @@ async fn handle_message(msg: MqttMessage, state: ProcessorState) {
                 async move { handle_metric_extraction(&tid, &did, payload, state).await }
             });
         }
+        TopicType::ShadowGet(tid, did, sn) => {
+            task_set.spawn({
+                let state = state.clone();
+                let payload = payload.clone();
+                async move { handle_shadow_get(&tid, &did, &sn, payload, state).await }
+            });
+        }
+        TopicType::ShadowDelete(tid, did, sn) => {
+            task_set.spawn({
+                let state = state.clone();
+                let payload = payload.clone();
+                async move { handle_shadow_delete(&tid, &did, &sn, payload, state).await }
+            });
+        }
         TopicType::DataUpdate(tid, did) => {
             task_set.spawn({
                 let state = state.clone();
@@ -302,6 +522,26 @@ async fn handle_message(msg: MqttMessage, state: ProcessorState) {
     }
 }
 
+// Write the metrics of all messages queued since the last write in one transaction
+async fn run_metric_writer(db: Arc<DB>, receiver: flume::Receiver<MetricWrite>) {
+    while let Ok(write) = receiver.recv_async().await {
+        let mut batch = vec![write];
+        while batch.len() < METRIC_BATCH_SIZE {
+            match receiver.try_recv() {
+                Ok(write) => batch.push(write),
+                Err(_) => break,
+            }
+        }
+        let messages = batch.len();
+        let db = db.clone();
+        match tokio::task::spawn_blocking(move || db.put_metrics_batch(&batch)).await {
+            Ok(Ok(())) => debug!(messages, "Stored metrics"),
+            Ok(Err(e)) => warn!(error = ?e, messages, "Failed to store metrics"),
+            Err(e) => warn!(error = ?e, messages, "Metric write task failed"),
+        }
+    }
+}
+
 async fn run_stream_worker(mqtt_receiver: flume::Receiver<MqttMessage>, state: ProcessorState) {
     let strm = mqtt_receiver.into_stream();
     strm.for_each_concurrent(50, |msg| {
@@ -345,12 +585,20 @@ pub async fn start_processor(
 
     let config = Arc::new(config);
 
+    // run metric writer
+    let (metric_sender, metric_receiver) = flume::bounded::<MetricWrite>(10 * METRIC_BATCH_SIZE);
+    tokio::spawn(
+        run_metric_writer(processor.db.clone(), metric_receiver)
+            .instrument(debug_span!("MetricWriter")),
+    );
+
     //  run stream worker
     tokio::spawn({
         let receiver = processor.mqtt_receiver.clone();
         let state = ProcessorState {
             db: processor.db.clone(),
             mqtt_sender: processor.mqtt_sender.clone(),
+            metric_sender,
             config: config.clone(),
         };
         async move {
@@ -369,11 +617,7 @@ pub async fn start_processor(
         }
     });
 
-    let topic_patterns = vec![
-        format!("{}+/shadow/update", config.shadow_topic_prefix),
-        format!("{}+/shadow/+/update", config.shadow_topic_prefix),
-    ];
-    processor.subscribe_shadow_updates(topic_patterns).await?;
+    processor.subscribe_topics(config.ingest_topic_patterns()).await?;
     Ok(processor)
 }
 
@@ -11,7 +11,7 @@ fn setup_db() -> Arc<DB> {
 }
 
 async fn setup_mqtt() -> MqttServer {
-    start_broker(None).await
+    start_broker(None, None).await
 }
 
 #[tokio::test]
@@ -36,3 +36,107 @@ async fn test_start_processor() {
     let processor = result.unwrap();
     assert!(processor.db.db.is_some(), "DB should be open");
 }
+
+#[test]
+fn test_get_topic_type() {
+    let prefix = "things/";
+
+    assert!(matches!(
+        get_topic_type("things/device1/shadow/update", prefix),
+        TopicType::ShadowUpdate(TenantId::Default, ref d, ShadowName::Default) if d == "device1"
+    ));
+    assert!(matches!(
+        get_topic_type("things/acme.device1/shadow/main/update", prefix),
+        TopicType::ShadowUpdate(TenantId::Custom(ref t), ref d, ShadowName::Custom(ref n))
+            if t == "acme" && d == "device1" && n == "main"
+    ));
+    assert!(matches!(
+        get_topic_type("things/acme.device1/data", prefix),
+        TopicType::DataUpdate(TenantId::Custom(ref t), ref d) if t == "acme" && d == "device1"
+    ));
+
+    // invalid tenants and other topics are ignored
+    assert!(matches!(
+        get_topic_type("things/a#b.device1/data", prefix),
+        TopicType::Other
+    ));
+    assert!(matches!(get_topic_type("things/acme./data", prefix), TopicType::Other));
+    assert!(matches!(get_topic_type("other/device1/data", prefix), TopicType::Other));
+}
+
+#[test]
+fn test_delta_return_topic() {
+    assert_eq!(
+        get_delta_return_topic(&TenantId::Default, "device1", &ShadowName::Default, "things/"),
+        "things/device1/shadow/update/delta"
+    );
+    assert_eq!(
+        get_delta_return_topic(
+            &TenantId::new("acme"),
+            "device1",
+            &ShadowName::new("main"),
+            "things/"
+        ),
+        "things/acme.device1/shadow/main/update/delta"
+    );
+}
+
+#[test]
+fn test_ingest_topic_patterns() {
+    let config = ProcessorConfig::default();
+    let patterns = config.ingest_topic_patterns();
+    assert!(patterns.contains(&"things/+/shadow/update".to_string()));
+    assert!(patterns.contains(&"things/+/shadow/+/update".to_string()));
+    assert!(patterns.contains(&"things/+/data".to_string()));
+
+    // configs without ingest topics fall back to the defaults
+    let config: ProcessorConfig =
+        serde_json::from_str(r#"{"shadow_topic_prefix": "devices/"}"#).unwrap();
+    assert!(config
+        .ingest_topic_patterns()
+        .contains(&"devices/+/data".to_string()));
+}
+
+#[test]
+fn test_shadow_request_topics() {
+    let prefix = "things/";
+
+    assert!(matches!(
+        get_topic_type("things/device1/shadow/get", prefix),
+        TopicType::ShadowGet(TenantId::Default, ref d, ShadowName::Default) if d == "device1"
+    ));
+    assert!(matches!(
+        get_topic_type("things/acme.device1/shadow/main/delete", prefix),
+        TopicType::ShadowDelete(TenantId::Custom(ref t), ref d, ShadowName::Custom(ref n))
+            if t == "acme" && d == "device1" && n == "main"
+    ));
+    // responses are never processed as requests
+    assert!(matches!(
+        get_topic_type("things/device1/shadow/get/accepted", prefix),
+        TopicType::Other
+    ));
+    assert!(matches!(
+        get_topic_type("things/device1/shadow/update/documents", prefix),
+        TopicType::Other
+    ));
+
+    assert_eq!(
+        get_shadow_topic(
+            &TenantId::new("acme"),
+            "device1",
+            &ShadowName::new("main"),
+            "get/accepted",
+            prefix
+        ),
+        "things/acme.device1/shadow/main/get/accepted"
+    );
+}
+
+#[test]
+fn test_parse_shadow_request() {
+    assert!(parse_shadow_request(b"").unwrap().client_token.is_none());
+    assert!(parse_shadow_request(b"{}").unwrap().client_token.is_none());
+    let request = parse_shadow_request(br#"{"clientToken": "abc"}"#).unwrap();
+    assert_eq!(request.client_token, Some("abc".to_string()));
+    assert!(parse_shadow_request(b"not json").is_err());
+}
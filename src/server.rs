@@ -2,20 +2,22 @@ use tokio_util::sync::CancellationToken;
 use tracing::warn;
 
 use crate::api::start_api_server;
+use crate::certs::renewal::start_expiry_worker;
+use crate::certs::CertificateManager;
 use crate::config::ForestConfig;
+use crate::db::retention::start_retention_worker;
+use crate::db::rollup::start_rollup_worker;
 use crate::db::DB;
+use crate::mqtt::auth::DeviceRegistry;
 use crate::mqtt::start_broker;
 use crate::processor::start_processor;
 
-use std::path::PathBuf;
 use std::sync::Arc;
 
 pub type ConnectionSet = dashmap::DashSet<String>;
 
 pub async fn start_server(config: &ForestConfig) -> CancellationToken {
-    let db_path = PathBuf::from(&config.database.path);
-
-    let maybe_db = DB::open_default(db_path.to_str().unwrap());
+    let maybe_db = DB::open(&config.database);
     let db = {
         match maybe_db {
             Ok(db) => Arc::new(db),
@@ -25,9 +27,24 @@ pub async fn start_server(config: &ForestConfig) -> CancellationToken {
         }
     };
 
+    let _rollup_worker = start_rollup_worker(db.clone());
+    let _retention_worker = start_retention_worker(db.clone());
+
     let connected_clients = Arc::new(ConnectionSet::new());
 
-    let mut mqtt_broker = start_broker(Some(config.mqtt.clone())).await;
+    // all tenants share the CA of the broker, the tenant is encoded in the client certificates
+    let cert_manager = match CertificateManager::new(&config.cert_dir, None) {
+        Ok(cert_manager) => Arc::new(cert_manager.with_config(&config.certificates)),
+        Err(e) => {
+            panic!("Failed to open certificate directory: {:?}", e);
+        }
+    };
+    let _expiry_worker = start_expiry_worker(db.clone(), cert_manager.clone());
+    let registry = DeviceRegistry {
+        db: db.clone(),
+        certs: cert_manager.clone(),
+    };
+    let mut mqtt_broker = start_broker(Some(config.mqtt.clone()), Some(registry)).await;
     let _broker_cancel_token = mqtt_broker.cancel_token.clone();
     let mqtt_sender = mqtt_broker.mqtt.clone();
     let mqtt_receiver = mqtt_broker.message_receiver();
@@ -60,6 +77,7 @@ pub async fn start_server(config: &ForestConfig) -> CancellationToken {
         Some(mqtt_sender),
         mqtt_metrics,
         connected_clients,
+        cert_manager,
         &config,
     )
     .await;
@@ -13,6 +13,8 @@ pub enum ShadowError {
     ShadowNameMismatch,
     #[error("TenantId mismatch")]
     TenantIdMismatch,
+    #[error("Version conflict: expected {expected}, current {current}")]
+    VersionConflict { expected: u64, current: u64 },
 }
 
 #[derive(Error, Debug)]
@@ -42,6 +44,9 @@ pub struct StateUpdateDocument {
     pub shadow_name: ShadowName,
     pub tenant_id: TenantId,
     pub state: StateDocument,
+    /// Expected current version of the shadow, the update is rejected if it differs
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub version: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -60,11 +65,135 @@ pub struct StateDocument {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeltaResponse {
     pub state: Value,
+    #[serde(default)]
+    pub version: u64,
+    #[serde(default)]
+    pub timestamp: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NestedStateDocument {
     pub state: StateDocument,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub version: Option<u64>,
+    #[serde(rename = "clientToken", skip_serializing_if = "Option::is_none", default)]
+    pub client_token: Option<String>,
+}
+
+/// Request document for `shadow/get` and `shadow/delete`, the payload may be empty
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ShadowRequest {
+    #[serde(rename = "clientToken", skip_serializing_if = "Option::is_none", default)]
+    pub client_token: Option<String>,
+}
+
+/// Response published on `get/accepted` and `update/accepted`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AcceptedResponse {
+    pub state: StateDocument,
+    pub metadata: MetadataDocument,
+    pub version: u64,
+    pub timestamp: u64,
+    #[serde(rename = "clientToken", skip_serializing_if = "Option::is_none", default)]
+    pub client_token: Option<String>,
+}
+
+/// Response published on `delete/accepted`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeleteResponse {
+    pub version: u64,
+    pub timestamp: u64,
+    #[serde(rename = "clientToken", skip_serializing_if = "Option::is_none", default)]
+    pub client_token: Option<String>,
+}
+
+/// Response published on the `rejected` topics
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub code: u16,
+    pub message: String,
+    pub timestamp: u64,
+    #[serde(rename = "clientToken", skip_serializing_if = "Option::is_none", default)]
+    pub client_token: Option<String>,
+}
+
+/// Shadow state as part of the `update/documents` response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShadowDocument {
+    pub state: StateDocument,
+    pub metadata: MetadataDocument,
+    pub version: u64,
+}
+
+/// Response published on `update/documents`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DocumentsResponse {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub previous: Option<ShadowDocument>,
+    pub current: ShadowDocument,
+    pub timestamp: u64,
+    #[serde(rename = "clientToken", skip_serializing_if = "Option::is_none", default)]
+    pub client_token: Option<String>,
+}
+
+/// Origin of an accepted shadow update
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdateSource {
+    Mqtt,
+    Api,
+}
+
+/// Accepted update as recorded in the shadow history
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShadowHistoryEntry {
+    pub version: u64,
+    pub timestamp: u64,
+    pub source: UpdateSource,
+    #[serde(skip_serializing_if = "Value::is_null")]
+    #[serde(default)]
+    pub reported: Value,
+    #[serde(skip_serializing_if = "Value::is_null")]
+    #[serde(default)]
+    pub desired: Value,
+}
+
+/// History entry stored together with the full shadow after the update
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShadowHistoryRecord {
+    #[serde(flatten)]
+    pub entry: ShadowHistoryEntry,
+    pub shadow: Shadow,
+}
+
+impl ShadowHistoryRecord {
+    pub fn new(update: &StateUpdateDocument, shadow: &Shadow, source: UpdateSource) -> Self {
+        ShadowHistoryRecord {
+            entry: ShadowHistoryEntry {
+                version: shadow.version,
+                timestamp: shadow.last_updated,
+                source,
+                reported: update.state.reported.clone(),
+                desired: update.state.desired.clone(),
+            },
+            shadow: shadow.clone(),
+        }
+    }
+}
+
+impl ErrorResponse {
+    pub fn new(code: u16, message: &str, client_token: Option<String>) -> Self {
+        ErrorResponse {
+            code,
+            message: message.to_string(),
+            timestamp: current_timestamp(),
+            client_token,
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, ShadowSerializationError> {
+        Ok(serde_json::to_string(self)?)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -90,6 +219,7 @@ impl StateUpdateDocument {
                 desired: Value::Null,
                 delta: Value::Null,
             },
+            version: None,
         }
     }
 
@@ -123,6 +253,7 @@ impl StateUpdateDocument {
             shadow_name: shadow_name.to_owned(),
             tenant_id: tenant_id.to_owned(),
             state: nested.state,
+            version: nested.version,
         }
     }
 
@@ -209,9 +340,75 @@ impl Shadow {
         }
         Ok(Some(serde_json::to_string(&DeltaResponse {
             state: self.state.delta.clone(),
+            version: self.version,
+            timestamp: self.last_updated,
         })?))
     }
 
+    /// Full shadow state as returned on `get/accepted`
+    pub fn get_accepted_response(&self, client_token: Option<String>) -> AcceptedResponse {
+        AcceptedResponse {
+            state: self.state.clone(),
+            metadata: self.metadata.clone(),
+            version: self.version,
+            timestamp: current_timestamp(),
+            client_token,
+        }
+    }
+
+    /// Acknowledge an update, echoing the requested state
+    pub fn update_accepted_response(
+        &self,
+        update: &StateUpdateDocument,
+        client_token: Option<String>,
+    ) -> AcceptedResponse {
+        AcceptedResponse {
+            state: StateDocument {
+                reported: update.state.reported.clone(),
+                desired: update.state.desired.clone(),
+                delta: Value::Null,
+            },
+            metadata: self.metadata.clone(),
+            version: self.version,
+            timestamp: self.last_updated,
+            client_token,
+        }
+    }
+
+    pub fn delete_accepted_response(&self, client_token: Option<String>) -> DeleteResponse {
+        DeleteResponse {
+            version: self.version,
+            timestamp: current_timestamp(),
+            client_token,
+        }
+    }
+
+    /// Previous and current state of the shadow after an update
+    pub fn documents_response(
+        &self,
+        previous: Option<&Shadow>,
+        client_token: Option<String>,
+    ) -> DocumentsResponse {
+        DocumentsResponse {
+            previous: previous.map(|p| p.to_shadow_document()),
+            current: self.to_shadow_document(),
+            timestamp: self.last_updated,
+            client_token,
+        }
+    }
+
+    fn to_shadow_document(&self) -> ShadowDocument {
+        ShadowDocument {
+            state: StateDocument {
+                reported: self.state.reported.clone(),
+                desired: self.state.desired.clone(),
+                delta: Value::Null,
+            },
+            metadata: self.metadata.clone(),
+            version: self.version,
+        }
+    }
+
     pub fn get_reported_metadata(&self) -> &Value {
         &self.metadata.reported
     }
@@ -224,6 +421,12 @@ impl Shadow {
         self.version
     }
 
+    /// Continue after the given version, used when a deleted shadow is created again
+    pub fn with_version(mut self, version: u64) -> Self {
+        self.version = version;
+        self
+    }
+
     fn calculate_delta(&mut self) {
         fn diff_recursive(reported: &Value, desired: &Value) -> Option<Value> {
             match (reported, desired) {
@@ -271,6 +474,14 @@ impl Shadow {
         if self.tenant_id != update.tenant_id {
             return Err(ShadowError::TenantIdMismatch);
         }
+        if let Some(expected) = update.version {
+            if expected != self.version {
+                return Err(ShadowError::VersionConflict {
+                    expected,
+                    current: self.version,
+                });
+            }
+        }
 
         // Update state
         if !update.state.reported.is_null() || !update.state.desired.is_null() {
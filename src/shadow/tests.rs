@@ -114,6 +114,7 @@ fn test_shadow_update() {
                 delta: json!(null),
             }
         },
+        version: None,
     };
 
     // Apply update
@@ -157,6 +158,7 @@ fn test_shadow_update() {
             desired: Value::Null,
             delta: Value::Null,
         },
+        version: None,
     };
     assert!(matches!(
         shadow.update(&invalid_update),
@@ -173,6 +175,7 @@ fn test_shadow_update() {
             desired: Value::Null,
             delta: Value::Null,
         },
+        version: None,
     };
     assert!(matches!(
         shadow.update(&invalid_update),
@@ -210,6 +213,7 @@ fn test_shadow_serialization() {
             }),
             delta: json!(null),
         },
+        version: None,
     };
 
     shadow.update(&update).unwrap();
@@ -348,3 +352,79 @@ fn test_shadow_name_in_struct() {
     let test: TestStruct = serde_json::from_str(r#"{"name":"custom-name"}"#).unwrap();
     assert_eq!(test.name, ShadowName::Custom("custom-name".to_string()));
 }
+
+#[test]
+fn test_shadow_responses() {
+    let mut shadow = Shadow::new("device1", &ShadowName::Default, &TenantId::Default);
+    let previous = shadow.clone();
+    let nested = NestedStateDocument::from_json(
+        r#"{"state": {"desired": {"color": "red"}}, "clientToken": "token-1"}"#,
+    )
+    .unwrap();
+    assert_eq!(nested.client_token, Some("token-1".to_string()));
+    let update = StateUpdateDocument::from_nested_state(
+        nested,
+        "device1",
+        &ShadowName::Default,
+        &TenantId::Default,
+    );
+    shadow.update(&update).unwrap();
+
+    // update/accepted echoes the request state and client token
+    let accepted = serde_json::to_value(
+        shadow.update_accepted_response(&update, Some("token-1".to_string())),
+    )
+    .unwrap();
+    assert_eq!(accepted["state"], json!({"desired": {"color": "red"}}));
+    assert_eq!(accepted["version"], 1);
+    assert_eq!(accepted["clientToken"], "token-1");
+
+    // update/documents contains previous and current state without delta
+    let documents =
+        serde_json::to_value(shadow.documents_response(Some(&previous), None)).unwrap();
+    assert_eq!(documents["previous"]["version"], 0);
+    assert_eq!(documents["current"]["version"], 1);
+    assert_eq!(documents["current"]["state"], json!({"desired": {"color": "red"}}));
+    assert!(documents.get("clientToken").is_none());
+
+    // get/accepted contains the delta
+    let get = serde_json::to_value(shadow.get_accepted_response(None)).unwrap();
+    assert_eq!(get["state"]["delta"], json!({"color": "red"}));
+
+    let rejected =
+        serde_json::to_value(ErrorResponse::new(404, "not found", Some("t".to_string()))).unwrap();
+    assert_eq!(rejected["code"], 404);
+    assert_eq!(rejected["clientToken"], "t");
+}
+
+#[test]
+fn test_shadow_update_version_conflict() {
+    let mut shadow = Shadow::new("device1", &ShadowName::Default, &TenantId::Default);
+    let mut update = StateUpdateDocument::new("device1", &ShadowName::Default, &TenantId::Default);
+    update.set_desired_value(json!({"color": "red"}));
+
+    // matching version is applied
+    update.version = Some(0);
+    shadow.update(&update).unwrap();
+    assert_eq!(shadow.get_version(), 1);
+
+    // stale version is rejected and leaves the shadow untouched
+    update.set_desired_value(json!({"color": "blue"}));
+    assert!(matches!(
+        shadow.update(&update),
+        Err(ShadowError::VersionConflict {
+            expected: 0,
+            current: 1
+        })
+    ));
+    assert_eq!(shadow.get_desired_value()["color"], "red");
+    assert_eq!(shadow.get_version(), 1);
+
+    // updates without version are always applied
+    update.version = None;
+    shadow.update(&update).unwrap();
+    assert_eq!(shadow.get_version(), 2);
+
+    let nested = NestedStateDocument::from_json(r#"{"state": {}, "version": 2}"#).unwrap();
+    assert_eq!(nested.version, Some(2));
+}
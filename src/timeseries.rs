@@ -2,13 +2,60 @@
 //!
 //! A `TimeSeries` object maintains two parallel vectors: one for timestamps and one for values.
 //! It provides methods to create a new time series and add data points while keeping the series ordered by timestamp.
+//! Timestamps are microseconds since the unix epoch.
 //!
 
+mod compression;
+
 use chrono::{DateTime, Datelike, TimeZone, Timelike, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use thiserror::Error;
 
+pub const MICROS_PER_SECOND: u64 = 1_000_000;
+const MICROS_PER_HOUR: u64 = 3600 * MICROS_PER_SECOND;
+
+/// Current time in microseconds since the unix epoch
+pub fn now_micros() -> u64 {
+    Utc::now().timestamp_micros() as u64
+}
+
+/// Unit of timestamps exchanged with clients
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Precision {
+    #[default]
+    #[serde(rename = "s")]
+    Seconds,
+    #[serde(rename = "ms")]
+    Milliseconds,
+    #[serde(rename = "us")]
+    Microseconds,
+}
+
+impl Precision {
+    fn micros(&self) -> u64 {
+        match self {
+            Precision::Seconds => MICROS_PER_SECOND,
+            Precision::Milliseconds => 1_000,
+            Precision::Microseconds => 1,
+        }
+    }
+
+    pub fn to_micros(&self, timestamp: u64) -> u64 {
+        timestamp.saturating_mul(self.micros())
+    }
+
+    pub fn from_micros(&self, timestamp: u64) -> u64 {
+        timestamp / self.micros()
+    }
+
+    /// Inclusive range in microseconds, the end covers its whole unit
+    pub fn range_to_micros(&self, start: u64, end: u64) -> (u64, u64) {
+        let end = end.saturating_add(1).saturating_mul(self.micros()) - 1;
+        (self.to_micros(start), end)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct LatLong {
     pub latitude: f64,
@@ -20,6 +67,9 @@ pub enum MetricValue {
     Float(f64),
     Int(i64),
     Location(LatLong),
+    Bool(bool),
+    /// Free text and categorical values
+    String(String),
 }
 
 impl std::fmt::Display for MetricValue {
@@ -28,6 +78,8 @@ impl std::fmt::Display for MetricValue {
             MetricValue::Float(val) => write!(f, "{}", val),
             MetricValue::Int(val) => write!(f, "{}", val),
             MetricValue::Location(loc) => write!(f, "({}, {})", loc.latitude, loc.longitude),
+            MetricValue::Bool(val) => write!(f, "{}", val),
+            MetricValue::String(val) => write!(f, "{}", val),
         }
     }
 }
@@ -36,6 +88,9 @@ impl std::fmt::Display for MetricValue {
 pub struct TimeSeriesModel {
     pub device_id: String,
     pub metric: String,
+    /// Unit of the timestamps in `data`
+    #[serde(default)]
+    pub precision: Precision,
     pub data: Vec<(u64, Value)>,
 }
 
@@ -50,7 +105,9 @@ impl MetricValue {
         match self {
             MetricValue::Float(f) => Some(f),
             MetricValue::Int(i) => Some(i as f64),
-            MetricValue::Location(_) => None,
+            // booleans count as 0 and 1, so averages yield the share of `true`
+            MetricValue::Bool(b) => Some(b as i64 as f64),
+            MetricValue::Location(_) | MetricValue::String(_) => None,
         }
     }
 
@@ -58,7 +115,8 @@ impl MetricValue {
         match self {
             MetricValue::Float(f) => Some(f as i64),
             MetricValue::Int(i) => Some(i),
-            MetricValue::Location(_) => None,
+            MetricValue::Bool(b) => Some(b as i64),
+            MetricValue::Location(_) | MetricValue::String(_) => None,
         }
     }
 
@@ -68,6 +126,20 @@ impl MetricValue {
             _ => None,
         }
     }
+
+    pub fn into_bool(self) -> Option<bool> {
+        match self {
+            MetricValue::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn into_string(self) -> Option<String> {
+        match self {
+            MetricValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
 }
 
 impl From<MetricValue> for serde_json::Value {
@@ -81,6 +153,8 @@ impl From<MetricValue> for serde_json::Value {
                 "lat": loc.latitude,
                 "long": loc.longitude
             }),
+            MetricValue::Bool(b) => serde_json::Value::Bool(b),
+            MetricValue::String(s) => serde_json::Value::String(s),
         }
     }
 }
@@ -88,7 +162,7 @@ impl From<MetricValue> for serde_json::Value {
 // Add trait for different timeseries types
 pub trait TimeSeriesConversions: Send + Sync {
     fn to_binary(&self) -> Result<Vec<u8>, TimeseriesSerializationError>;
-    fn to_model(&self, device_id: &str, metric: &str) -> TimeSeriesModel;
+    fn to_model(&self, device_id: &str, metric: &str, precision: Precision) -> TimeSeriesModel;
     fn from_binary(data: &[u8]) -> Result<Self, TimeseriesSerializationError>
     where
         Self: Sized;
@@ -133,7 +207,7 @@ pub struct TimeSeriesBucketIter<'a, T> {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TimeSeries<T> {
-    timestamps: Vec<u64>, // Vector of Unix timestamps in seconds
+    timestamps: Vec<u64>, // Vector of Unix timestamps in microseconds
     values: Vec<T>,       // Vector of values
 }
 
@@ -296,7 +370,15 @@ impl<T> TimeSeries<T> {
         }
     }
 
-    /// Converts a Unix timestamp into a reverse chronological database key.
+    // Scale timestamps of legacy series stored with second precision
+    fn seconds_to_micros(mut self) -> Self {
+        for timestamp in self.timestamps.iter_mut() {
+            *timestamp = timestamp.saturating_mul(MICROS_PER_SECOND);
+        }
+        self
+    }
+
+    /// Converts a Unix timestamp in microseconds into a reverse chronological database key.
     /// Keys are formatted to sort newer timestamps before older ones.
     ///
     /// # Key Schema
@@ -317,6 +399,7 @@ impl<T> TimeSeries<T> {
     /// // Result: "0976091609"
     /// ```
     pub fn ts_to_key(timestamp: u64) -> String {
+        let timestamp = timestamp / MICROS_PER_SECOND;
         // cap timestamp at 32472147600 (2999-01-01 00:00:00 UTC)
         if timestamp > 32472147600 {
             return "0000000000".to_string();
@@ -338,20 +421,20 @@ impl<T> TimeSeries<T> {
         )
     }
 
-    /// Converts a database key back into a Unix timestamp.
+    /// Converts a database key back into a Unix timestamp in microseconds.
     /// This is the inverse operation of `ts_to_key`.
     ///
     /// # Arguments
     /// * `key` - A 10-character string in format "yyyymmddhh" (reversed chronologically)
     ///
     /// # Returns
-    /// * `Result<u64, &'static str>` - Unix timestamp or error if key is invalid
+    /// * `Result<u64, &'static str>` - Unix timestamp in microseconds or error if key is invalid
     ///
     /// # Example
     /// ```
     /// let key = "0976091609"; // March 15, 2024 14:00 UTC
     /// let ts = TimeSeries::<f64>::key_to_ts(key).unwrap();
-    /// assert_eq!(ts, 1710511200);
+    /// assert_eq!(ts, 1710511200_000_000);
     /// ```
     pub fn key_to_ts(key: &str) -> Result<u64, &'static str> {
         if key.len() != 10 {
@@ -373,20 +456,20 @@ impl<T> TimeSeries<T> {
             .single()
             .ok_or("Invalid datetime components")?;
 
-        Ok(datetime.timestamp() as u64)
+        Ok(datetime.timestamp() as u64 * MICROS_PER_SECOND)
     }
 }
 
 impl<T: Clone> TimeSeries<T> {
     /// Returns an iterator that yields hourly buckets of the time series.
-    /// Each bucket is a new TimeSeries containing points for one hour (3600 seconds).
+    /// Each bucket is a new TimeSeries containing points for one hour.
     ///
     /// # Example
     /// ```
     /// let mut ts = TimeSeries::new();
-    /// ts.add_point(3600, 10.0);
-    /// ts.add_point(3601, 20.0);
-    /// ts.add_point(7200, 30.0); // Next hour
+    /// ts.add_point(3600_000_000, 10.0);
+    /// ts.add_point(3601_000_000, 20.0);
+    /// ts.add_point(7200_000_000, 30.0); // Next hour
     ///
     /// for bucket in ts.buckets() {
     ///     println!("Bucket with {} points", bucket.len());
@@ -436,13 +519,13 @@ impl<'a, T: Clone> Iterator for TimeSeriesBucketIter<'a, T> {
         }
 
         let mut bucket = TimeSeries::new();
-        let current_hour = self.series.timestamps[self.current_idx] / 3600;
+        let current_hour = self.series.timestamps[self.current_idx] / MICROS_PER_HOUR;
         let mut idx = self.current_idx;
 
         // Collect all points in the current hour
         while idx < self.series.timestamps.len() {
             let ts = self.series.timestamps[idx];
-            if ts / 3600 != current_hour {
+            if ts / MICROS_PER_HOUR != current_hour {
                 break;
             }
             bucket.add_point(ts, self.series.values[idx].clone());
@@ -469,12 +552,22 @@ pub enum SerializationFormat {
     Json,
 }
 
+/// Type byte of stored series. All formats before `BinaryMetricSeriesMicros` are
+/// legacy formats with timestamps in seconds, they are converted on read.
 #[derive(Debug, Clone, Copy)]
 pub enum TimeseriesStorageFormat {
     BinaryFloatSeries,
     BinaryIntSeries,
     BinaryLocationSeries,
     BinaryMetricSeries,
+    BinaryMetricSeriesMicros,
+    BinaryBoolSeries,
+    BinaryStringSeries,
+    /// Columnar encoding with compressed timestamps and values, see `compression`
+    CompressedMetricSeries,
+    BinaryFloatSeriesMicros,
+    BinaryIntSeriesMicros,
+    BinaryLocationSeriesMicros,
 }
 
 #[derive(Error, Debug)]
@@ -487,6 +580,8 @@ pub enum TimeseriesSerializationError {
     WrongTypeByte(String),
     #[error("JSON serialization error: {0}")]
     JsonError(#[from] serde_json::Error),
+    #[error("Compressed series error: {0}")]
+    CompressionError(String),
 }
 
 impl<T: Serialize> TimeSeries<T> {
@@ -519,7 +614,7 @@ pub type IntTimeSeries = TimeSeries<i64>;
 impl TimeSeriesConversions for IntTimeSeries {
     fn to_binary(&self) -> Result<Vec<u8>, TimeseriesSerializationError> {
         // convert the type to a single byte
-        let type_byte = TimeseriesStorageFormat::BinaryIntSeries as u8;
+        let type_byte = TimeseriesStorageFormat::BinaryIntSeriesMicros as u8;
         // serialize the type and the data
         // the result is a Vec<u8> with the type byte followed by the serialized data
         let mut data = bincode::serialize(&type_byte)?;
@@ -527,14 +622,20 @@ impl TimeSeriesConversions for IntTimeSeries {
         Ok(data)
     }
 
-    fn to_model(&self, device_id: &str, metric: &str) -> TimeSeriesModel {
+    fn to_model(&self, device_id: &str, metric: &str, precision: Precision) -> TimeSeriesModel {
         let data = self
             .iter()
-            .map(|(ts, val)| (ts, serde_json::Value::from(val.clone())))
+            .map(|(ts, val)| {
+                (
+                    precision.from_micros(ts),
+                    serde_json::Value::from(val.clone()),
+                )
+            })
             .collect();
         TimeSeriesModel {
             device_id: device_id.to_string(),
             metric: metric.to_string(),
+            precision,
             data,
         }
     }
@@ -544,8 +645,9 @@ impl TimeSeriesConversions for IntTimeSeries {
         Self: Sized,
     {
         let type_byte = data[0];
-        // check if the type is correct
-        if type_byte != TimeseriesStorageFormat::BinaryIntSeries as u8 {
+        // check if the type is correct, the legacy type stores seconds
+        let legacy = type_byte == TimeseriesStorageFormat::BinaryIntSeries as u8;
+        if !legacy && type_byte != TimeseriesStorageFormat::BinaryIntSeriesMicros as u8 {
             return Err(TimeseriesSerializationError::WrongTypeByte(String::from(
                 "Cannot deserialize binary data into IntTimeSeries. Wrong type byte.",
             )));
@@ -557,7 +659,11 @@ impl TimeSeriesConversions for IntTimeSeries {
             )));
         }
         // deserialize the data
-        Ok(bincode::deserialize(&data[1..])?)
+        let series: IntTimeSeries = bincode::deserialize(&data[1..])?;
+        if legacy {
+            return Ok(series.seconds_to_micros());
+        }
+        Ok(series)
     }
 }
 
@@ -566,7 +672,7 @@ pub type FloatTimeSeries = TimeSeries<f64>;
 impl TimeSeriesConversions for FloatTimeSeries {
     fn to_binary(&self) -> Result<Vec<u8>, TimeseriesSerializationError> {
         // convert the type to a single byte
-        let type_byte = TimeseriesStorageFormat::BinaryFloatSeries as u8;
+        let type_byte = TimeseriesStorageFormat::BinaryFloatSeriesMicros as u8;
         // serialize the type and the data
         // the result is a Vec<u8> with the type byte followed by the serialized data
         let mut data = bincode::serialize(&type_byte)?;
@@ -574,14 +680,20 @@ impl TimeSeriesConversions for FloatTimeSeries {
         Ok(data)
     }
 
-    fn to_model(&self, device_id: &str, metric: &str) -> TimeSeriesModel {
+    fn to_model(&self, device_id: &str, metric: &str, precision: Precision) -> TimeSeriesModel {
         let data = self
             .iter()
-            .map(|(ts, val)| (ts, serde_json::Value::from(val.clone())))
+            .map(|(ts, val)| {
+                (
+                    precision.from_micros(ts),
+                    serde_json::Value::from(val.clone()),
+                )
+            })
             .collect();
         TimeSeriesModel {
             device_id: device_id.to_string(),
             metric: metric.to_string(),
+            precision,
             data,
         }
     }
@@ -591,8 +703,9 @@ impl TimeSeriesConversions for FloatTimeSeries {
         Self: Sized,
     {
         let type_byte = data[0];
-        // check if the type is correct
-        if type_byte != TimeseriesStorageFormat::BinaryFloatSeries as u8 {
+        // check if the type is correct, the legacy type stores seconds
+        let legacy = type_byte == TimeseriesStorageFormat::BinaryFloatSeries as u8;
+        if !legacy && type_byte != TimeseriesStorageFormat::BinaryFloatSeriesMicros as u8 {
             return Err(TimeseriesSerializationError::WrongTypeByte(String::from(
                 "Cannot deserialize binary data into FloatTimeSeries. Wrong type byte.",
             )));
@@ -604,7 +717,11 @@ impl TimeSeriesConversions for FloatTimeSeries {
             )));
         }
         // deserialize the data
-        Ok(bincode::deserialize(&data[1..])?)
+        let series: FloatTimeSeries = bincode::deserialize(&data[1..])?;
+        if legacy {
+            return Ok(series.seconds_to_micros());
+        }
+        Ok(series)
     }
 }
 
@@ -613,7 +730,7 @@ pub type LocationTimeSeries = TimeSeries<LatLong>;
 impl TimeSeriesConversions for LocationTimeSeries {
     fn to_binary(&self) -> Result<Vec<u8>, TimeseriesSerializationError> {
         // convert the type to a single byte
-        let type_byte = TimeseriesStorageFormat::BinaryLocationSeries as u8;
+        let type_byte = TimeseriesStorageFormat::BinaryLocationSeriesMicros as u8;
         // serialize the type and the data
         // the result is a Vec<u8> with the type byte followed by the serialized data
         let mut data = bincode::serialize(&type_byte)?;
@@ -621,12 +738,12 @@ impl TimeSeriesConversions for LocationTimeSeries {
         Ok(data)
     }
 
-    fn to_model(&self, device_id: &str, metric: &str) -> TimeSeriesModel {
+    fn to_model(&self, device_id: &str, metric: &str, precision: Precision) -> TimeSeriesModel {
         let data = self
             .iter()
             .map(|(ts, val)| {
                 (
-                    ts,
+                    precision.from_micros(ts),
                     serde_json::Value::from(MetricValue::Location(val.clone())),
                 )
             })
@@ -634,6 +751,7 @@ impl TimeSeriesConversions for LocationTimeSeries {
         TimeSeriesModel {
             device_id: device_id.to_string(),
             metric: metric.to_string(),
+            precision,
             data,
         }
     }
@@ -643,8 +761,9 @@ impl TimeSeriesConversions for LocationTimeSeries {
         Self: Sized,
     {
         let type_byte = data[0];
-        // check if the type is correct
-        if type_byte != TimeseriesStorageFormat::BinaryLocationSeries as u8 {
+        // check if the type is correct, the legacy type stores seconds
+        let legacy = type_byte == TimeseriesStorageFormat::BinaryLocationSeries as u8;
+        if !legacy && type_byte != TimeseriesStorageFormat::BinaryLocationSeriesMicros as u8 {
             return Err(TimeseriesSerializationError::WrongTypeByte(String::from(
                 "Cannot deserialize binary data into LocationTimeSeries. Wrong type byte.",
             )));
@@ -656,6 +775,111 @@ impl TimeSeriesConversions for LocationTimeSeries {
             )));
         }
         // deserialize the data
+        let series: LocationTimeSeries = bincode::deserialize(&data[1..])?;
+        if legacy {
+            return Ok(series.seconds_to_micros());
+        }
+        Ok(series)
+    }
+}
+
+pub type BoolTimeSeries = TimeSeries<bool>;
+
+impl TimeSeriesConversions for BoolTimeSeries {
+    fn to_binary(&self) -> Result<Vec<u8>, TimeseriesSerializationError> {
+        // convert the type to a single byte
+        let type_byte = TimeseriesStorageFormat::BinaryBoolSeries as u8;
+        // serialize the type and the data
+        // the result is a Vec<u8> with the type byte followed by the serialized data
+        let mut data = bincode::serialize(&type_byte)?;
+        data.extend(bincode::serialize(self)?);
+        Ok(data)
+    }
+
+    fn to_model(&self, device_id: &str, metric: &str, precision: Precision) -> TimeSeriesModel {
+        let data = self
+            .iter()
+            .map(|(ts, val)| (precision.from_micros(ts), serde_json::Value::Bool(*val)))
+            .collect();
+        TimeSeriesModel {
+            device_id: device_id.to_string(),
+            metric: metric.to_string(),
+            precision,
+            data,
+        }
+    }
+
+    fn from_binary(data: &[u8]) -> Result<Self, TimeseriesSerializationError>
+    where
+        Self: Sized,
+    {
+        let type_byte = data[0];
+        // check if the type is correct
+        if type_byte != TimeseriesStorageFormat::BinaryBoolSeries as u8 {
+            return Err(TimeseriesSerializationError::WrongTypeByte(String::from(
+                "Cannot deserialize binary data into BoolTimeSeries. Wrong type byte.",
+            )));
+        }
+        // check length
+        if data.len() < 2 {
+            return Err(TimeseriesSerializationError::WrongTypeByte(String::from(
+                "Cannot deserialize binary data into BoolTimeSeries. Data too short.",
+            )));
+        }
+        // deserialize the data
+        Ok(bincode::deserialize(&data[1..])?)
+    }
+}
+
+pub type StringTimeSeries = TimeSeries<String>;
+
+impl TimeSeriesConversions for StringTimeSeries {
+    fn to_binary(&self) -> Result<Vec<u8>, TimeseriesSerializationError> {
+        // convert the type to a single byte
+        let type_byte = TimeseriesStorageFormat::BinaryStringSeries as u8;
+        // serialize the type and the data
+        // the result is a Vec<u8> with the type byte followed by the serialized data
+        let mut data = bincode::serialize(&type_byte)?;
+        data.extend(bincode::serialize(self)?);
+        Ok(data)
+    }
+
+    fn to_model(&self, device_id: &str, metric: &str, precision: Precision) -> TimeSeriesModel {
+        let data = self
+            .iter()
+            .map(|(ts, val)| {
+                (
+                    precision.from_micros(ts),
+                    serde_json::Value::String(val.clone()),
+                )
+            })
+            .collect();
+        TimeSeriesModel {
+            device_id: device_id.to_string(),
+            metric: metric.to_string(),
+            precision,
+            data,
+        }
+    }
+
+    fn from_binary(data: &[u8]) -> Result<Self, TimeseriesSerializationError>
+    where
+        Self: Sized,
+    {
+        let type_byte = data[0];
+        // check if the type is correct
+        if type_byte != TimeseriesStorageFormat::BinaryStringSeries as u8 {
+            return Err(TimeseriesSerializationError::WrongTypeByte(String::from(
+                "Cannot deserialize binary data into StringTimeSeries. Wrong type byte.",
+            )));
+        }
+        // check length
+        if data.len() < 2 {
+            return Err(TimeseriesSerializationError::WrongTypeByte(String::from(
+                "Cannot deserialize binary data into StringTimeSeries. Data too short.",
+            )));
+        }
+        // deserialize the data
         Ok(bincode::deserialize(&data[1..])?)
     }
 }
@@ -663,6 +887,12 @@ impl TimeSeriesConversions for LocationTimeSeries {
 pub type MetricTimeSeries = TimeSeries<MetricValue>;
 
 impl MetricTimeSeries {
+    /// True if the binary data is stored in a legacy format with second precision
+    pub fn is_legacy_binary(data: &[u8]) -> bool {
+        matches!(data.first(), Some(&type_byte)
+            if type_byte < TimeseriesStorageFormat::BinaryMetricSeriesMicros as u8)
+    }
+
     pub fn to_float_series(&self) -> Option<FloatTimeSeries> {
         let mut float_ts = FloatTimeSeries::new();
         for (ts, val) in self.iter() {
@@ -698,6 +928,193 @@ impl MetricTimeSeries {
         }
         Some(loc_ts)
     }
+
+    pub fn to_bool_series(&self) -> Option<BoolTimeSeries> {
+        let mut bool_ts = BoolTimeSeries::new();
+        for (ts, val) in self.iter() {
+            if let Some(bool_val) = val.clone().into_bool() {
+                bool_ts.add_point(ts, bool_val);
+            } else {
+                return None;
+            }
+        }
+        Some(bool_ts)
+    }
+
+    pub fn to_string_series(&self) -> Option<StringTimeSeries> {
+        let mut string_ts = StringTimeSeries::new();
+        for (ts, val) in self.iter() {
+            if let Some(string_val) = val.clone().into_string() {
+                string_ts.add_point(ts, string_val);
+            } else {
+                return None;
+            }
+        }
+        Some(string_ts)
+    }
+}
+
+/// Aggregate functions for downsampling a time series into buckets
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregation {
+    Min,
+    Max,
+    Avg,
+    Sum,
+    Count,
+    First,
+    Last,
+}
+
+#[derive(Error, Debug)]
+pub enum AggregationError {
+    #[error("Invalid bucket width: {0}")]
+    InvalidBucketWidth(String),
+    #[error("Invalid aggregation: {0}")]
+    InvalidAggregation(String),
+    #[error("Aggregation {0} is not supported for non-numeric values")]
+    UnsupportedAggregation(Aggregation),
+}
+
+impl std::fmt::Display for Aggregation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Aggregation::Min => "min",
+            Aggregation::Max => "max",
+            Aggregation::Avg => "avg",
+            Aggregation::Sum => "sum",
+            Aggregation::Count => "count",
+            Aggregation::First => "first",
+            Aggregation::Last => "last",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::str::FromStr for Aggregation {
+    type Err = AggregationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "min" => Ok(Aggregation::Min),
+            "max" => Ok(Aggregation::Max),
+            "avg" | "mean" => Ok(Aggregation::Avg),
+            "sum" => Ok(Aggregation::Sum),
+            "count" => Ok(Aggregation::Count),
+            "first" => Ok(Aggregation::First),
+            "last" => Ok(Aggregation::Last),
+            _ => Err(AggregationError::InvalidAggregation(s.to_string())),
+        }
+    }
+}
+
+/// Parses a bucket width like `100ms`, `30s`, `5m`, `1h` or `1d` into microseconds.
+/// A plain number is interpreted as seconds.
+pub fn parse_bucket_width(width: &str) -> Result<u64, AggregationError> {
+    let width = width.trim();
+    let (number, unit) = match width.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => width.split_at(idx),
+        None => (width, "s"),
+    };
+    let multiplier = match unit {
+        "us" => 1,
+        "ms" => 1_000,
+        "s" => MICROS_PER_SECOND,
+        "m" => 60 * MICROS_PER_SECOND,
+        "h" => 3600 * MICROS_PER_SECOND,
+        "d" => 86400 * MICROS_PER_SECOND,
+        _ => return Err(AggregationError::InvalidBucketWidth(width.to_string())),
+    };
+    match number.parse::<u64>() {
+        Ok(n) if n > 0 => n
+            .checked_mul(multiplier)
+            .ok_or_else(|| AggregationError::InvalidBucketWidth(width.to_string())),
+        _ => Err(AggregationError::InvalidBucketWidth(width.to_string())),
+    }
+}
+
+// Aggregate the values of a single bucket
+fn aggregate_values(
+    values: &[&MetricValue],
+    aggregation: Aggregation,
+) -> Result<MetricValue, AggregationError> {
+    match aggregation {
+        Aggregation::First => return Ok(values[0].clone()),
+        Aggregation::Last => return Ok(values[values.len() - 1].clone()),
+        Aggregation::Count => return Ok(MetricValue::Int(values.len() as i64)),
+        _ => {}
+    }
+
+    // integer buckets stay integers for min, max and sum
+    let ints: Option<Vec<i64>> = values
+        .iter()
+        .map(|v| match v {
+            MetricValue::Int(i) => Some(*i),
+            _ => None,
+        })
+        .collect();
+    if let Some(ints) = ints {
+        match aggregation {
+            Aggregation::Min => return Ok(MetricValue::Int(*ints.iter().min().unwrap())),
+            Aggregation::Max => return Ok(MetricValue::Int(*ints.iter().max().unwrap())),
+            // an overflowing sum falls back to a float sum
+            Aggregation::Sum => {
+                if let Some(sum) = ints.iter().try_fold(0i64, |sum, i| sum.checked_add(*i)) {
+                    return Ok(MetricValue::Int(sum));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let floats = values
+        .iter()
+        .map(|v| (*v).clone().into_float())
+        .collect::<Option<Vec<f64>>>()
+        .ok_or(AggregationError::UnsupportedAggregation(aggregation))?;
+    let value = match aggregation {
+        Aggregation::Min => floats.iter().copied().fold(f64::INFINITY, f64::min),
+        Aggregation::Max => floats.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        Aggregation::Sum => floats.iter().sum(),
+        _ => floats.iter().sum::<f64>() / floats.len() as f64,
+    };
+    Ok(MetricValue::Float(value))
+}
+
+impl MetricTimeSeries {
+    /// Downsamples the series into buckets of `bucket_width` microseconds.
+    /// Buckets are aligned to the unix epoch and each result point is
+    /// timestamped with the start of its bucket. Location and string values
+    /// only support `count`, `first` and `last`, booleans count as 0 and 1.
+    pub fn aggregate(
+        &self,
+        bucket_width: u64,
+        aggregation: Aggregation,
+    ) -> Result<MetricTimeSeries, AggregationError> {
+        if bucket_width == 0 {
+            return Err(AggregationError::InvalidBucketWidth("0".to_string()));
+        }
+        let mut result = MetricTimeSeries::new();
+        let mut bucket_start = None;
+        let mut bucket_values: Vec<&MetricValue> = Vec::new();
+
+        for (ts, value) in self.iter() {
+            let start = ts - ts % bucket_width;
+            if bucket_start != Some(start) {
+                if let Some(previous_start) = bucket_start {
+                    result.add_point(previous_start, aggregate_values(&bucket_values, aggregation)?);
+                }
+                bucket_start = Some(start);
+                bucket_values.clear();
+            }
+            bucket_values.push(value);
+        }
+        if let Some(start) = bucket_start {
+            result.add_point(start, aggregate_values(&bucket_values, aggregation)?);
+        }
+        Ok(result)
+    }
 }
 
 impl From<&FloatTimeSeries> for MetricTimeSeries {
@@ -730,25 +1147,48 @@ impl From<&LocationTimeSeries> for MetricTimeSeries {
     }
 }
 
+impl From<&BoolTimeSeries> for MetricTimeSeries {
+    fn from(bool_ts: &BoolTimeSeries) -> Self {
+        let mut metric_ts = MetricTimeSeries::new();
+        for (ts, val) in bool_ts.into_iter() {
+            metric_ts.add_point(ts, MetricValue::Bool(*val));
+        }
+        metric_ts
+    }
+}
+
+impl From<&StringTimeSeries> for MetricTimeSeries {
+    fn from(string_ts: &StringTimeSeries) -> Self {
+        let mut metric_ts = MetricTimeSeries::new();
+        for (ts, val) in string_ts.into_iter() {
+            metric_ts.add_point(ts, MetricValue::String(val.clone()));
+        }
+        metric_ts
+    }
+}
+
 impl TimeSeriesConversions for MetricTimeSeries {
     fn to_binary(&self) -> Result<Vec<u8>, TimeseriesSerializationError> {
-        // convert the type to a single byte
-        let type_byte = TimeseriesStorageFormat::BinaryMetricSeries as u8;
-        // serialize the type and the data
-        // the result is a Vec<u8> with the type byte followed by the serialized data
-        let mut data = bincode::serialize(&type_byte)?;
-        data.extend(bincode::serialize(self)?);
+        // the type byte is followed by the compressed columns
+        let mut data = vec![TimeseriesStorageFormat::CompressedMetricSeries as u8];
+        data.extend(compression::encode(self));
         Ok(data)
     }
 
-    fn to_model(&self, device_id: &str, metric: &str) -> TimeSeriesModel {
+    fn to_model(&self, device_id: &str, metric: &str, precision: Precision) -> TimeSeriesModel {
         let data = self
             .iter()
-            .map(|(ts, val)| (ts, serde_json::Value::from(val.clone())))
+            .map(|(ts, val)| {
+                (
+                    precision.from_micros(ts),
+                    serde_json::Value::from(val.clone()),
+                )
+            })
             .collect();
         TimeSeriesModel {
             device_id: device_id.to_string(),
             metric: metric.to_string(),
+            precision,
             data,
         }
     }
@@ -757,29 +1197,56 @@ impl TimeSeriesConversions for MetricTimeSeries {
     where
         Self: Sized,
     {
-        let type_byte = data[0];
+        let type_byte = *data.first().ok_or_else(|| {
+            TimeseriesSerializationError::WrongTypeByte(String::from(
+                "Cannot deserialize binary data into MetricTimeSeries. Data too short.",
+            ))
+        })?;
+
+        if type_byte == TimeseriesStorageFormat::CompressedMetricSeries as u8 {
+            return compression::decode(&data[1..]);
+        }
 
-        //  first check if the type is metric series
-        if type_byte == TimeseriesStorageFormat::BinaryMetricSeries as u8 {
+        //  bincode encoded metric series
+        if type_byte == TimeseriesStorageFormat::BinaryMetricSeriesMicros as u8
+            || type_byte == TimeseriesStorageFormat::BinaryMetricSeries as u8
+        {
             // this is a native metric series
             if data.len() < 2 {
                 return Err(TimeseriesSerializationError::WrongTypeByte(String::from(
                     "Cannot deserialize binary data into MetricTimeSeries. Data too short.",
                 )));
             }
-            return Ok(bincode::deserialize(&data[1..])?);
+            let metric_ts: MetricTimeSeries = bincode::deserialize(&data[1..])?;
+            if type_byte == TimeseriesStorageFormat::BinaryMetricSeries as u8 {
+                return Ok(metric_ts.seconds_to_micros());
+            }
+            return Ok(metric_ts);
         }
 
         // we can construct a metric time series from any of the other types
-        if type_byte == TimeseriesStorageFormat::BinaryFloatSeries as u8 {
+        // the typed series scale their legacy seconds themselves
+        if type_byte == TimeseriesStorageFormat::BinaryFloatSeries as u8
+            || type_byte == TimeseriesStorageFormat::BinaryFloatSeriesMicros as u8
+        {
             let float_ts = FloatTimeSeries::from_binary(data)?;
             return Ok(MetricTimeSeries::from(&float_ts));
-        } else if type_byte == TimeseriesStorageFormat::BinaryIntSeries as u8 {
+        } else if type_byte == TimeseriesStorageFormat::BinaryIntSeries as u8
+            || type_byte == TimeseriesStorageFormat::BinaryIntSeriesMicros as u8
+        {
             let int_ts = IntTimeSeries::from_binary(data)?;
             return Ok(MetricTimeSeries::from(&int_ts));
-        } else if type_byte == TimeseriesStorageFormat::BinaryLocationSeries as u8 {
+        } else if type_byte == TimeseriesStorageFormat::BinaryLocationSeries as u8
+            || type_byte == TimeseriesStorageFormat::BinaryLocationSeriesMicros as u8
+        {
             let loc_ts = LocationTimeSeries::from_binary(data)?;
             return Ok(MetricTimeSeries::from(&loc_ts));
+        } else if type_byte == TimeseriesStorageFormat::BinaryBoolSeries as u8 {
+            let bool_ts = BoolTimeSeries::from_binary(data)?;
+            return Ok(MetricTimeSeries::from(&bool_ts));
+        } else if type_byte == TimeseriesStorageFormat::BinaryStringSeries as u8 {
+            let string_ts = StringTimeSeries::from_binary(data)?;
+            return Ok(MetricTimeSeries::from(&string_ts));
         }
 
         Err(TimeseriesSerializationError::WrongTypeByte(String::from(
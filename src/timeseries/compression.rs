@@ -0,0 +1,404 @@
+//! Compact columnar encoding of `MetricTimeSeries` buckets.
+//!
+//! Layout after the type byte, all integers are LEB128 varints:
+//!
+//! - number of points
+//! - timestamps: the first timestamp, the first delta and then the zig-zag
+//!   encoded delta-of-deltas, so regular intervals cost a single byte per point
+//! - value kinds as runs of `(kind, length)`, a series of one type is one run
+//! - one column per kind in the order of `Kind`, holding the values of that kind:
+//!   - floats: Gorilla XOR stream, repeated values cost a single bit
+//!   - ints: zig-zag deltas to the previous int
+//!   - locations: one XOR stream for latitudes, one for longitudes
+//!   - bools: packed bits
+//!   - strings: index into a dictionary of the previous strings of the bucket,
+//!     new strings follow their index as length and UTF-8 bytes
+
+use super::{LatLong, MetricTimeSeries, MetricValue, TimeSeries, TimeseriesSerializationError};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Float,
+    Int,
+    Location,
+    Bool,
+    String,
+}
+
+const KINDS: [Kind; 5] = [
+    Kind::Float,
+    Kind::Int,
+    Kind::Location,
+    Kind::Bool,
+    Kind::String,
+];
+
+impl Kind {
+    fn of(value: &MetricValue) -> Kind {
+        match value {
+            MetricValue::Float(_) => Kind::Float,
+            MetricValue::Int(_) => Kind::Int,
+            MetricValue::Location(_) => Kind::Location,
+            MetricValue::Bool(_) => Kind::Bool,
+            MetricValue::String(_) => Kind::String,
+        }
+    }
+
+    fn from_u8(kind: u8) -> Option<Kind> {
+        KINDS.get(kind as usize).copied()
+    }
+}
+
+fn error(message: &str) -> TimeseriesSerializationError {
+    TimeseriesSerializationError::CompressionError(message.to_string())
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+struct ByteReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn varint(&mut self) -> Result<u64, TimeseriesSerializationError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .ok_or_else(|| error("truncated varint"))?;
+            self.pos += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(error("varint too long"))
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], TimeseriesSerializationError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| error("truncated data"))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    // Bit stream starting at the current position, the position is moved
+    // behind the stream once it is finished
+    fn bits(&self) -> BitReader<'a> {
+        BitReader {
+            data: &self.data[self.pos..],
+            bit: 0,
+        }
+    }
+
+    fn skip_bits(&mut self, reader: &BitReader) {
+        self.pos += reader.bit.div_ceil(8);
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bit: usize,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u64, count: u32) {
+        for i in (0..count).rev() {
+            if self.bit % 8 == 0 {
+                self.bytes.push(0);
+            }
+            if (value >> i) & 1 == 1 {
+                let last = self.bytes.len() - 1;
+                self.bytes[last] |= 0x80 >> (self.bit % 8);
+            }
+            self.bit += 1;
+        }
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    bit: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, count: u32) -> Result<u64, TimeseriesSerializationError> {
+        let mut value = 0u64;
+        for _ in 0..count {
+            let byte = self
+                .data
+                .get(self.bit / 8)
+                .ok_or_else(|| error("truncated bit stream"))?;
+            value = (value << 1) | u64::from((byte >> (7 - self.bit % 8)) & 1);
+            self.bit += 1;
+        }
+        Ok(value)
+    }
+}
+
+// Gorilla XOR compression of a float column
+fn encode_floats(out: &mut Vec<u8>, values: impl Iterator<Item = f64>) {
+    let mut writer = BitWriter::default();
+    let mut previous: Option<u64> = None;
+    // leading and trailing zeros of the last stored block
+    let mut block = (u32::MAX, 0);
+    for value in values {
+        let bits = value.to_bits();
+        let xor = match previous {
+            Some(previous) => bits ^ previous,
+            None => {
+                writer.write(bits, 64);
+                previous = Some(bits);
+                continue;
+            }
+        };
+        previous = Some(bits);
+        if xor == 0 {
+            writer.write(0, 1);
+            continue;
+        }
+        writer.write(1, 1);
+        let leading = xor.leading_zeros().min(31);
+        let trailing = xor.trailing_zeros();
+        if block.0 != u32::MAX && leading >= block.0 && trailing >= block.1 {
+            // the meaningful bits fit into the previous block
+            writer.write(0, 1);
+            writer.write(xor >> block.1, 64 - block.0 - block.1);
+        } else {
+            let meaningful = 64 - leading - trailing;
+            writer.write(1, 1);
+            writer.write(u64::from(leading), 5);
+            writer.write(u64::from(meaningful - 1), 6);
+            writer.write(xor >> trailing, meaningful);
+            block = (leading, trailing);
+        }
+    }
+    out.extend(writer.bytes);
+}
+
+fn decode_floats(
+    reader: &mut ByteReader,
+    count: usize,
+) -> Result<Vec<f64>, TimeseriesSerializationError> {
+    let mut values = Vec::new();
+    if count == 0 {
+        return Ok(values);
+    }
+    let mut bits = reader.bits();
+    let mut previous = bits.read(64)?;
+    values.push(f64::from_bits(previous));
+    let mut block = (0, 0);
+    for _ in 1..count {
+        if bits.read(1)? == 1 {
+            if bits.read(1)? == 1 {
+                let leading = bits.read(5)? as u32;
+                let meaningful = bits.read(6)? as u32 + 1;
+                if leading + meaningful > 64 {
+                    return Err(error("invalid float block"));
+                }
+                block = (leading, 64 - leading - meaningful);
+            }
+            let xor = bits.read(64 - block.0 - block.1)? << block.1;
+            previous ^= xor;
+        }
+        values.push(f64::from_bits(previous));
+    }
+    reader.skip_bits(&bits);
+    Ok(values)
+}
+
+pub(super) fn encode(series: &MetricTimeSeries) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_varint(&mut out, series.timestamps.len() as u64);
+
+    // wrapping arithmetic keeps the encoding reversible for any timestamps
+    let mut previous_ts = 0u64;
+    let mut previous_delta = 0i64;
+    for (i, &timestamp) in series.timestamps.iter().enumerate() {
+        if i == 0 {
+            write_varint(&mut out, timestamp);
+        } else {
+            let delta = timestamp.wrapping_sub(previous_ts) as i64;
+            write_varint(&mut out, zigzag(delta.wrapping_sub(previous_delta)));
+            previous_delta = delta;
+        }
+        previous_ts = timestamp;
+    }
+
+    let mut runs: Vec<(Kind, u64)> = Vec::new();
+    for value in &series.values {
+        let kind = Kind::of(value);
+        match runs.last_mut() {
+            Some((run_kind, len)) if *run_kind == kind => *len += 1,
+            _ => runs.push((kind, 1)),
+        }
+    }
+    write_varint(&mut out, runs.len() as u64);
+    for (kind, len) in runs {
+        out.push(kind as u8);
+        write_varint(&mut out, len);
+    }
+
+    let values = &series.values;
+    encode_floats(
+        &mut out,
+        values.iter().filter_map(|value| match value {
+            MetricValue::Float(f) => Some(*f),
+            _ => None,
+        }),
+    );
+    let mut previous_int = 0i64;
+    for value in values {
+        if let MetricValue::Int(i) = value {
+            write_varint(&mut out, zigzag(i.wrapping_sub(previous_int)));
+            previous_int = *i;
+        }
+    }
+    let locations = || {
+        values.iter().filter_map(|value| match value {
+            MetricValue::Location(location) => Some(location),
+            _ => None,
+        })
+    };
+    encode_floats(&mut out, locations().map(|location| location.latitude));
+    encode_floats(&mut out, locations().map(|location| location.longitude));
+    let mut bools = BitWriter::default();
+    for value in values {
+        if let MetricValue::Bool(b) = value {
+            bools.write(u64::from(*b), 1);
+        }
+    }
+    out.extend(bools.bytes);
+    let mut dictionary: HashMap<&str, usize> = HashMap::new();
+    for value in values {
+        if let MetricValue::String(s) = value {
+            match dictionary.get(s.as_str()) {
+                Some(index) => write_varint(&mut out, *index as u64),
+                None => {
+                    write_varint(&mut out, dictionary.len() as u64);
+                    write_varint(&mut out, s.len() as u64);
+                    out.extend(s.as_bytes());
+                    dictionary.insert(s, dictionary.len());
+                }
+            }
+        }
+    }
+    out
+}
+
+pub(super) fn decode(data: &[u8]) -> Result<MetricTimeSeries, TimeseriesSerializationError> {
+    let mut reader = ByteReader { data, pos: 0 };
+    let count = reader.varint()? as usize;
+    // every timestamp takes at least one byte
+    if count > data.len() {
+        return Err(error("invalid number of points"));
+    }
+
+    let mut timestamps = Vec::with_capacity(count);
+    let mut previous_delta = 0i64;
+    for i in 0..count {
+        if i == 0 {
+            timestamps.push(reader.varint()?);
+            continue;
+        }
+        let delta = previous_delta.wrapping_add(unzigzag(reader.varint()?));
+        let previous_ts = timestamps[i - 1];
+        let timestamp = previous_ts.wrapping_add(delta as u64);
+        if timestamp <= previous_ts {
+            return Err(error("timestamps are not increasing"));
+        }
+        timestamps.push(timestamp);
+        previous_delta = delta;
+    }
+
+    let mut kinds = Vec::with_capacity(count);
+    for _ in 0..reader.varint()? {
+        let kind = Kind::from_u8(reader.bytes(1)?[0]).ok_or_else(|| error("unknown value kind"))?;
+        let len = reader.varint()? as usize;
+        if kinds.len().checked_add(len).filter(|n| *n <= count).is_none() {
+            return Err(error("too many values"));
+        }
+        kinds.extend(std::iter::repeat_n(kind, len));
+    }
+    if kinds.len() != count {
+        return Err(error("missing values"));
+    }
+    let kind_count = |kind: Kind| kinds.iter().filter(|k| **k == kind).count();
+
+    let floats = decode_floats(&mut reader, kind_count(Kind::Float))?;
+    let mut ints = Vec::new();
+    let mut previous_int = 0i64;
+    for _ in 0..kind_count(Kind::Int) {
+        previous_int = previous_int.wrapping_add(unzigzag(reader.varint()?));
+        ints.push(previous_int);
+    }
+    let latitudes = decode_floats(&mut reader, kind_count(Kind::Location))?;
+    let longitudes = decode_floats(&mut reader, kind_count(Kind::Location))?;
+    let mut bools = Vec::new();
+    let mut bits = reader.bits();
+    for _ in 0..kind_count(Kind::Bool) {
+        bools.push(bits.read(1)? == 1);
+    }
+    reader.skip_bits(&bits);
+    let mut strings: Vec<String> = Vec::new();
+    let mut dictionary: Vec<String> = Vec::new();
+    for _ in 0..kind_count(Kind::String) {
+        let index = reader.varint()? as usize;
+        if index == dictionary.len() {
+            let len = reader.varint()? as usize;
+            let s = std::str::from_utf8(reader.bytes(len)?)
+                .map_err(|_| error("invalid UTF-8 string"))?;
+            dictionary.push(s.to_string());
+        }
+        let s = dictionary
+            .get(index)
+            .ok_or_else(|| error("invalid string index"))?;
+        strings.push(s.clone());
+    }
+    if reader.pos != data.len() {
+        return Err(error("trailing data"));
+    }
+
+    let mut floats = floats.into_iter();
+    let mut ints = ints.into_iter();
+    let mut locations = latitudes.into_iter().zip(longitudes);
+    let mut bools = bools.into_iter();
+    let mut strings = strings.into_iter();
+    // the columns hold exactly as many values as there are points of their kind
+    let values = kinds
+        .into_iter()
+        .map(|kind| match kind {
+            Kind::Float => floats.next().map(MetricValue::Float),
+            Kind::Int => ints.next().map(MetricValue::Int),
+            Kind::Location => locations.next().map(|(latitude, longitude)| {
+                MetricValue::Location(LatLong::new(latitude, longitude))
+            }),
+            Kind::Bool => bools.next().map(MetricValue::Bool),
+            Kind::String => strings.next().map(MetricValue::String),
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| error("missing values"))?;
+    Ok(TimeSeries { timestamps, values })
+}
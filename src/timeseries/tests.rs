@@ -156,17 +156,19 @@ fn test_bucket_iterator() {
     let buckets: Vec<TimeSeries<i32>> = ts.buckets().collect();
     assert_eq!(buckets.len(), 0);
 
-    // Add points in first hour (0-3599)
+    let hour = 3600 * MICROS_PER_SECOND;
+
+    // Add points in first hour
     ts.add_point(0, 10);
-    ts.add_point(1800, 20);
-    ts.add_point(3599, 30);
+    ts.add_point(hour / 2, 20);
+    ts.add_point(hour - 1, 30);
 
-    // Add points in second hour (3600-7199)
-    ts.add_point(3600, 40);
-    ts.add_point(5400, 50);
+    // Add points in second hour
+    ts.add_point(hour, 40);
+    ts.add_point(3 * hour / 2, 50);
 
-    // Add point in third hour (7200-10799)
-    ts.add_point(7200, 60);
+    // Add point in third hour
+    ts.add_point(2 * hour, 60);
 
     // Collect all buckets
     let buckets: Vec<TimeSeries<i32>> = ts.buckets().collect();
@@ -176,26 +178,26 @@ fn test_bucket_iterator() {
 
     // First bucket should have 3 points
     assert_eq!(buckets[0].len(), 3);
-    assert_eq!(buckets[0].timestamps, vec![0, 1800, 3599]);
+    assert_eq!(buckets[0].timestamps, vec![0, hour / 2, hour - 1]);
     assert_eq!(buckets[0].values, vec![10, 20, 30]);
 
     // Second bucket should have 2 points
     assert_eq!(buckets[1].len(), 2);
-    assert_eq!(buckets[1].timestamps, vec![3600, 5400]);
+    assert_eq!(buckets[1].timestamps, vec![hour, 3 * hour / 2]);
     assert_eq!(buckets[1].values, vec![40, 50]);
 
     // Third bucket should have 1 point
     assert_eq!(buckets[2].len(), 1);
-    assert_eq!(buckets[2].timestamps, vec![7200]);
+    assert_eq!(buckets[2].timestamps, vec![2 * hour]);
     assert_eq!(buckets[2].values, vec![60]);
 }
 
 #[test]
 fn test_timestamp_key_conversion() {
     // March 15, 2024 14:00 UTC
-    let timestamp1: u64 = 1710511200;
-    // March 15, 2024 14:30 UTC
-    let timestamp2: u64 = 1710511200 + 1800;
+    let timestamp1: u64 = 1710511200 * MICROS_PER_SECOND;
+    // March 15, 2024 14:30:00.5 UTC
+    let timestamp2: u64 = (1710511200 + 1800) * MICROS_PER_SECOND + 500_000;
 
     // Convert to key
     let key = TimeSeries::<f64>::ts_to_key(timestamp1);
@@ -323,3 +325,313 @@ fn test_timeseries_conversions() {
         _ => panic!("Wrong value type"),
     }
 }
+
+#[test]
+fn test_parse_bucket_width() {
+    assert_eq!(parse_bucket_width("250us").unwrap(), 250);
+    assert_eq!(parse_bucket_width("100ms").unwrap(), 100_000);
+    assert_eq!(parse_bucket_width("30s").unwrap(), 30 * MICROS_PER_SECOND);
+    assert_eq!(parse_bucket_width("1m").unwrap(), 60 * MICROS_PER_SECOND);
+    assert_eq!(parse_bucket_width("2h").unwrap(), 7200 * MICROS_PER_SECOND);
+    assert_eq!(parse_bucket_width("1d").unwrap(), 86400 * MICROS_PER_SECOND);
+    assert_eq!(parse_bucket_width("300").unwrap(), 300 * MICROS_PER_SECOND);
+    assert!(parse_bucket_width("0m").is_err());
+    assert!(parse_bucket_width("1w").is_err());
+    assert!(parse_bucket_width("99999999999999d").is_err());
+    assert!(parse_bucket_width("h").is_err());
+}
+
+#[test]
+fn test_aggregate() {
+    let mut ts = MetricTimeSeries::new();
+    // first minute
+    ts.add_point(0, MetricValue::Int(4));
+    ts.add_point(20, MetricValue::Int(2));
+    ts.add_point(40, MetricValue::Int(6));
+    // third minute, second minute has no data
+    ts.add_point(130, MetricValue::Float(1.5));
+    ts.add_point(150, MetricValue::Int(2));
+
+    let result = ts.aggregate(60, Aggregation::Min).unwrap();
+    assert_eq!(result.timestamps, vec![0, 120]);
+    assert_eq!(result.values, vec![MetricValue::Int(2), MetricValue::Float(1.5)]);
+
+    let result = ts.aggregate(60, Aggregation::Max).unwrap();
+    assert_eq!(result.values, vec![MetricValue::Int(6), MetricValue::Float(2.0)]);
+
+    let result = ts.aggregate(60, Aggregation::Sum).unwrap();
+    assert_eq!(result.values, vec![MetricValue::Int(12), MetricValue::Float(3.5)]);
+
+    let result = ts.aggregate(60, Aggregation::Avg).unwrap();
+    assert_eq!(result.values, vec![MetricValue::Float(4.0), MetricValue::Float(1.75)]);
+
+    let result = ts.aggregate(60, Aggregation::Count).unwrap();
+    assert_eq!(result.values, vec![MetricValue::Int(3), MetricValue::Int(2)]);
+
+    let result = ts.aggregate(60, Aggregation::First).unwrap();
+    assert_eq!(result.values, vec![MetricValue::Int(4), MetricValue::Float(1.5)]);
+
+    let result = ts.aggregate(3600, Aggregation::Last).unwrap();
+    assert_eq!(result.timestamps, vec![0]);
+    assert_eq!(result.values, vec![MetricValue::Int(2)]);
+
+    let mut ts = MetricTimeSeries::new();
+    ts.add_point(0, MetricValue::Int(i64::MAX));
+    ts.add_point(20, MetricValue::Int(i64::MAX));
+    let result = ts.aggregate(60, Aggregation::Sum).unwrap();
+    assert_eq!(result.values, vec![MetricValue::Float(2.0 * i64::MAX as f64)]);
+}
+
+#[test]
+fn test_aggregate_location() {
+    let mut ts = MetricTimeSeries::new();
+    ts.add_point(10, MetricValue::Location(LatLong::new(45.5, -122.6)));
+    ts.add_point(20, MetricValue::Location(LatLong::new(40.7, -74.0)));
+
+    let result = ts.aggregate(60, Aggregation::Last).unwrap();
+    assert_eq!(
+        result.values,
+        vec![MetricValue::Location(LatLong::new(40.7, -74.0))]
+    );
+    assert!(matches!(
+        ts.aggregate(60, Aggregation::Avg),
+        Err(AggregationError::UnsupportedAggregation(Aggregation::Avg))
+    ));
+    assert_eq!("MAX".parse::<Aggregation>().unwrap(), Aggregation::Max);
+    assert!("median".parse::<Aggregation>().is_err());
+}
+
+#[test]
+fn test_sub_second_points() {
+    let mut ts = MetricTimeSeries::new();
+    // 100 Hz samples within the same second do not collide
+    for i in 0..100 {
+        ts.add_point(1710511200 * MICROS_PER_SECOND + i * 10_000, MetricValue::Int(i as i64));
+    }
+    assert_eq!(ts.len(), 100);
+    assert_eq!(ts.buckets().count(), 1);
+
+    let model = ts.to_model("device1", "vibration", Precision::Milliseconds);
+    assert_eq!(model.data[1].0, 1710511200010);
+    let model = ts.to_model("device1", "vibration", Precision::Seconds);
+    assert_eq!(model.data[99].0, 1710511200);
+}
+
+#[test]
+fn test_precision() {
+    assert_eq!(Precision::Seconds.to_micros(2), 2 * MICROS_PER_SECOND);
+    assert_eq!(Precision::Milliseconds.to_micros(2), 2_000);
+    assert_eq!(Precision::Microseconds.from_micros(1_500_000), 1_500_000);
+    assert_eq!(Precision::Seconds.from_micros(1_500_000), 1);
+    // the end of a range covers its whole unit
+    assert_eq!(
+        Precision::Seconds.range_to_micros(1, 2),
+        (MICROS_PER_SECOND, 3 * MICROS_PER_SECOND - 1)
+    );
+    assert_eq!(Precision::Microseconds.range_to_micros(1, 2), (1, 2));
+    assert_eq!(
+        serde_json::from_str::<Precision>(r#""ms""#).unwrap(),
+        Precision::Milliseconds
+    );
+}
+
+#[test]
+fn test_legacy_binary_conversion() {
+    // series written before microsecond precision stored seconds
+    let mut legacy = MetricTimeSeries::new();
+    legacy.add_point(1710511200, MetricValue::Float(1.0));
+    let mut data = bincode::serialize(&(TimeseriesStorageFormat::BinaryMetricSeries as u8)).unwrap();
+    data.extend(bincode::serialize(&legacy).unwrap());
+    assert!(MetricTimeSeries::is_legacy_binary(&data));
+
+    let converted = MetricTimeSeries::from_binary(&data).unwrap();
+    assert_eq!(
+        converted.latest(),
+        Some((1710511200 * MICROS_PER_SECOND, &MetricValue::Float(1.0)))
+    );
+
+    let float_legacy = legacy.to_float_series().unwrap();
+    let mut data = bincode::serialize(&(TimeseriesStorageFormat::BinaryFloatSeries as u8)).unwrap();
+    data.extend(bincode::serialize(&float_legacy).unwrap());
+    assert!(MetricTimeSeries::is_legacy_binary(&data));
+    let converted = MetricTimeSeries::from_binary(&data).unwrap();
+    assert_eq!(converted.first_timestamp(), Some(1710511200 * MICROS_PER_SECOND));
+    let float_series = FloatTimeSeries::from_binary(&data).unwrap();
+    assert_eq!(float_series.first_timestamp(), Some(1710511200 * MICROS_PER_SECOND));
+
+    // typed series hold microseconds and are not scaled again
+    let data = float_series.to_binary().unwrap();
+    assert!(!MetricTimeSeries::is_legacy_binary(&data));
+    let restored = MetricTimeSeries::from_binary(&data).unwrap();
+    assert_eq!(restored.first_timestamp(), Some(1710511200 * MICROS_PER_SECOND));
+    let mut ints = IntTimeSeries::new();
+    ints.add_point(1710511200 * MICROS_PER_SECOND, 1);
+    let restored = MetricTimeSeries::from_binary(&ints.to_binary().unwrap()).unwrap();
+    assert_eq!(restored.first_timestamp(), Some(1710511200 * MICROS_PER_SECOND));
+
+    // current series are stored as they are
+    let data = converted.to_binary().unwrap();
+    assert!(!MetricTimeSeries::is_legacy_binary(&data));
+    assert_eq!(
+        MetricTimeSeries::from_binary(&data).unwrap().first_timestamp(),
+        Some(1710511200 * MICROS_PER_SECOND)
+    );
+}
+
+#[test]
+fn test_bool_and_string_series() {
+    let mut ts = MetricTimeSeries::new();
+    ts.add_point(1000, MetricValue::Bool(true));
+    ts.add_point(2000, MetricValue::Bool(false));
+    ts.add_point(3000, MetricValue::Bool(true));
+
+    let bool_ts = ts.to_bool_series().unwrap();
+    let data = bool_ts.to_binary().unwrap();
+    assert_eq!(data[0], TimeseriesStorageFormat::BinaryBoolSeries as u8);
+    let restored = MetricTimeSeries::from_binary(&data).unwrap();
+    assert_eq!(restored.get_value_for_timestamp(2000), Some(&MetricValue::Bool(false)));
+
+    // booleans aggregate as 0 and 1
+    let result = ts.aggregate(10_000, Aggregation::Avg).unwrap();
+    assert_eq!(result.latest(), Some((0, &MetricValue::Float(2.0 / 3.0))));
+
+    let model = ts.to_model("device1", "door_open", Precision::Microseconds);
+    assert_eq!(model.data[0], (1000, serde_json::Value::Bool(true)));
+
+    let mut ts = MetricTimeSeries::new();
+    ts.add_point(1000, MetricValue::String("1.4.2".to_string()));
+    ts.add_point(2000, MetricValue::String("1.5.0".to_string()));
+
+    let string_ts = ts.to_string_series().unwrap();
+    let restored = MetricTimeSeries::from_binary(&string_ts.to_binary().unwrap()).unwrap();
+    assert_eq!(restored.len(), 2);
+    // the metric series encoding keeps the new variants as well
+    let restored = MetricTimeSeries::from_binary(&ts.to_binary().unwrap()).unwrap();
+    assert_eq!(
+        restored.latest(),
+        Some((2000, &MetricValue::String("1.5.0".to_string())))
+    );
+
+    let model = ts.to_model("device1", "firmware", Precision::Microseconds);
+    assert_eq!(model.data[1].1, serde_json::json!("1.5.0"));
+    assert!(ts.to_bool_series().is_none());
+
+    let result = ts.aggregate(10_000, Aggregation::Count).unwrap();
+    assert_eq!(result.latest(), Some((0, &MetricValue::Int(2))));
+    assert!(matches!(
+        ts.aggregate(10_000, Aggregation::Max),
+        Err(AggregationError::UnsupportedAggregation(Aggregation::Max))
+    ));
+}
+
+fn assert_roundtrip(ts: &MetricTimeSeries) -> Vec<u8> {
+    let data = ts.to_binary().unwrap();
+    assert_eq!(data[0], TimeseriesStorageFormat::CompressedMetricSeries as u8);
+    let restored = MetricTimeSeries::from_binary(&data).unwrap();
+    let expected: Vec<(u64, &MetricValue)> = ts.iter().collect();
+    let actual: Vec<(u64, &MetricValue)> = restored.iter().collect();
+    assert_eq!(actual.len(), expected.len());
+    for ((ts, value), (expected_ts, expected_value)) in actual.into_iter().zip(expected) {
+        assert_eq!(ts, expected_ts);
+        match (value, expected_value) {
+            // NaN never equals itself, compare the bits
+            (MetricValue::Float(f), MetricValue::Float(e)) => assert_eq!(f.to_bits(), e.to_bits()),
+            _ => assert_eq!(value, expected_value),
+        }
+    }
+    data
+}
+
+#[test]
+fn test_compressed_roundtrip() {
+    assert_roundtrip(&MetricTimeSeries::new());
+
+    let start = 1710511200 * MICROS_PER_SECOND;
+    let mut ts = MetricTimeSeries::new();
+    for (i, value) in [21.5, 21.5, 21.625, -0.0, f64::NAN, f64::INFINITY, f64::MIN_POSITIVE, 1e300]
+        .into_iter()
+        .enumerate()
+    {
+        ts.add_point(start + i as u64 * 1_000_003, MetricValue::Float(value));
+    }
+    assert_roundtrip(&ts);
+
+    let mut ts = MetricTimeSeries::new();
+    for (i, value) in [0, -1, i64::MAX, i64::MIN, 42, 42].into_iter().enumerate() {
+        ts.add_point(i as u64, MetricValue::Int(value));
+    }
+    // irregular and huge gaps between timestamps
+    ts.add_point(u64::MAX - 1, MetricValue::Int(7));
+    assert_roundtrip(&ts);
+
+    // mixed types, locations, bools and repeated strings
+    let mut ts = MetricTimeSeries::new();
+    ts.add_point(start, MetricValue::Location(LatLong::new(52.52, 13.405)));
+    ts.add_point(start + 1, MetricValue::Location(LatLong::new(52.5201, 13.4049)));
+    ts.add_point(start + 2, MetricValue::Bool(true));
+    ts.add_point(start + 3, MetricValue::String("running".to_string()));
+    ts.add_point(start + 4, MetricValue::Bool(false));
+    ts.add_point(start + 5, MetricValue::String("idle".to_string()));
+    ts.add_point(start + 6, MetricValue::String("running".to_string()));
+    ts.add_point(start + 7, MetricValue::String("ümlaut".to_string()));
+    ts.add_point(start + 8, MetricValue::Float(1.5));
+    ts.add_point(start + 9, MetricValue::Int(-3));
+    assert_roundtrip(&ts);
+}
+
+#[test]
+fn test_compressed_size() {
+    // an hour of readings every second with sensor noise
+    let start = 1710511200 * MICROS_PER_SECOND;
+    let mut floats = MetricTimeSeries::new();
+    let mut counters = MetricTimeSeries::new();
+    for i in 0..3600u64 {
+        let value = 21.0 + ((i / 60) % 10) as f64 * 0.1;
+        floats.add_point(start + i * MICROS_PER_SECOND, MetricValue::Float(value));
+        counters.add_point(start + i * MICROS_PER_SECOND, MetricValue::Int(i as i64 * 3));
+    }
+    for ts in [&floats, &counters] {
+        let compressed = assert_roundtrip(ts);
+        let bincode = bincode::serialize(ts).unwrap();
+        assert!(
+            compressed.len() * 4 < bincode.len(),
+            "compressed {} bytes, bincode {} bytes",
+            compressed.len(),
+            bincode.len()
+        );
+    }
+}
+
+#[test]
+fn test_compressed_reads_bincode_and_rejects_corrupt_data() {
+    let mut ts = MetricTimeSeries::new();
+    ts.add_point(1000, MetricValue::Float(1.0));
+    ts.add_point(2000, MetricValue::String("on".to_string()));
+
+    // buckets written before the compressed format are read transparently
+    let mut data =
+        bincode::serialize(&(TimeseriesStorageFormat::BinaryMetricSeriesMicros as u8)).unwrap();
+    data.extend(bincode::serialize(&ts).unwrap());
+    let restored = MetricTimeSeries::from_binary(&data).unwrap();
+    assert_eq!(restored.len(), 2);
+    assert_eq!(restored.latest(), Some((2000, &MetricValue::String("on".to_string()))));
+
+    let data = assert_roundtrip(&ts);
+    for len in 1..data.len() {
+        assert!(matches!(
+            MetricTimeSeries::from_binary(&data[..len]),
+            Err(TimeseriesSerializationError::CompressionError(_))
+        ));
+    }
+    assert!(MetricTimeSeries::from_binary(&[]).is_err());
+
+    // run lengths that overflow the number of values are rejected
+    let mut data = data[..1].to_vec();
+    data.extend([1, 0x10, 2, 0, 1, 0]);
+    data.extend([0xff; 9]);
+    data.push(0x01);
+    assert!(matches!(
+        MetricTimeSeries::from_binary(&data),
+        Err(TimeseriesSerializationError::CompressionError(_))
+    ));
+}